@@ -1,11 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
+use parking_lot::RwLock;
 use rand::{Rng, SeedableRng, StdRng};
 
-use chain::{BlockChain, SignedBlock};
-use primitives::hash::CryptoHash;
-use primitives::signature::PublicKey;
-use primitives::types::{AccountId, BlockId};
+use chain::{BlockChain, BlockWeigher, HeaderVerifier, SignedBlock};
+use primitives::hash::{hash_struct, CryptoHash};
+use primitives::signature::{verify_signature, PublicKey};
+use primitives::traits::{Decode, Encode};
+use primitives::types::{AccountId, AuthorityMask, BlockId, PartialSignature};
+use primitives::utils::index_to_bytes;
+use randomness::epoch_seed;
+use storage::{DBTransaction, Storage};
 use types::{SignedBeaconBlock, SignedBeaconBlockHeader};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -28,16 +34,80 @@ pub struct AuthorityConfig {
     pub num_seats_per_slot: u64,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct SelectedAuthority {
     pub account_id: AccountId,
     pub public_key: PublicKey,
 }
 
-#[derive(Clone)]
+/// Authorities of all the seats of an epoch, by block number. The last block of the previous
+/// epoch commits to its hash, so that light clients can follow the authority changes.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AuthoritySet {
+    pub epoch: u64,
+    pub slots: Vec<Vec<SelectedAuthority>>,
+}
+
+impl AuthoritySet {
+    pub fn hash(&self) -> CryptoHash {
+        hash_struct(self)
+    }
+
+    /// Returns authorities for given block number, if it belongs to the epoch.
+    pub fn get_authorities(&self, index: u64) -> Option<&Vec<SelectedAuthority>> {
+        let first_index = self.epoch * self.slots.len() as u64 + 1;
+        if index < first_index {
+            return None;
+        }
+        self.slots.get((index - first_index) as usize)
+    }
+}
+
+/// Epoch in which the block with given number takes its seats. The genesis block has no seats,
+/// so the last block of an epoch is a multiple of the epoch length.
+pub fn epoch_of_block(index: u64, epoch_length: u64) -> u64 {
+    index.saturating_sub(1) / epoch_length
+}
+
+/// Marks the seats whose authority signed the given hash. The authority mask of a block is not
+/// covered by its hash, so only the signatures show which seats signed it.
+pub fn verified_mask(
+    authorities: &[SelectedAuthority],
+    hash: &CryptoHash,
+    signatures: &[PartialSignature],
+) -> AuthorityMask {
+    authorities
+        .iter()
+        .map(|authority| {
+            signatures
+                .iter()
+                .any(|signature| verify_signature(signature, hash, &authority.public_key))
+        })
+        .collect()
+}
+
+/// Seats of the blocks in `[first_index, last_index]` taken from a slashed authority.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Revocation {
+    first_index: u64,
+    last_index: u64,
+}
+
+fn is_revoked(
+    revoked: &BTreeMap<AccountId, Revocation>,
+    account_id: &AccountId,
+    index: u64,
+) -> bool {
+    revoked.get(account_id).map_or(false, |revocation| {
+        revocation.first_index <= index && index <= revocation.last_index
+    })
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 struct RecordedProposal {
     pub public_key: PublicKey,
-    /// Stake is either positive for proposal or negative for kicked out accounts.
+    /// Stake is either positive for proposal, zero for accounts that unstaked everything
+    /// or negative for kicked out accounts.
     pub stake: i64,
 }
 
@@ -50,10 +120,145 @@ pub struct Authority {
     current: HashMap<u64, Vec<SelectedAuthority>>,
     /// Cache of current threshold.
     current_threshold: HashMap<u64, u64>,
+    /// Stake behind every seat, by the epoch in which the seats are taken.
+    seat_stake: HashMap<u64, u64>,
     /// Proposals in the given epoch.
     proposals: HashMap<AccountId, RecordedProposal>,
     /// Proposals per epoch.
     accepted_proposals: HashMap<u64, Vec<AuthorityProposal>>,
+    /// Index of the last processed block header.
+    last_index: u64,
+    /// Seed of the randomness beacon after the previous epoch.
+    randomness: CryptoHash,
+    /// Values revealed for the randomness beacon in the current epoch.
+    reveals: BTreeMap<AccountId, CryptoHash>,
+    /// Slashed authorities. Their signatures don't count for the seats they were already
+    /// selected for.
+    revoked: BTreeMap<AccountId, Revocation>,
+    /// Storage of the schedule, so that it doesn't need to be rebuilt from the whole chain.
+    storage: Arc<Storage>,
+    /// Genesis hash of the beacon chain, prefix of the keys in the storage.
+    genesis_hash: CryptoHash,
+}
+
+/// Authorities that take the seats of an epoch. Stored in `COL_AUTHORITIES` once selected.
+#[derive(Serialize, Deserialize)]
+struct EpochAuthorities {
+    /// Selected authorities by block index.
+    slots: HashMap<u64, Vec<SelectedAuthority>>,
+    seat_stake: u64,
+}
+
+/// Threshold and proposals accepted on the switch to an epoch.
+#[derive(Serialize, Deserialize)]
+struct EpochProposals {
+    threshold: u64,
+    accepted_proposals: Vec<AuthorityProposal>,
+}
+
+/// Progress of the schedule, stored after every processed header.
+#[derive(Serialize, Deserialize)]
+struct ScheduleHead {
+    last_index: u64,
+    current_epoch: u64,
+    proposals: HashMap<AccountId, RecordedProposal>,
+    randomness: CryptoHash,
+    reveals: BTreeMap<AccountId, CryptoHash>,
+    revoked: BTreeMap<AccountId, Revocation>,
+}
+
+const AUTHORITIES_KEY: u8 = 0;
+const PROPOSALS_KEY: u8 = 1;
+const HEAD_KEY: u8 = 2;
+const SIGNATURES_KEY: u8 = 3;
+
+/// Stored schedule around the current epoch. Enough to continue tracking the authorities
+/// from the last processed block without the beacon chain before it.
+#[derive(Serialize, Deserialize)]
+pub struct AuthorityCheckpoint {
+    head: ScheduleHead,
+    epochs: Vec<(u64, EpochAuthorities)>,
+    proposals: EpochProposals,
+}
+
+impl AuthorityCheckpoint {
+    /// Checks that the header is the last one processed by the schedule, that it's signed only
+    /// by the authorities of its slot and commits to the authorities of the next epoch.
+    pub fn verify_header(
+        &self,
+        header: &SignedBeaconBlockHeader,
+        epoch_length: u64,
+    ) -> Result<(), String> {
+        let index = header.body.index;
+        if self.head.last_index != index {
+            return Err(format!(
+                "Authorities are processed up to block #{} instead of block #{}",
+                self.head.last_index, index
+            ));
+        }
+        let get_authorities = |index| {
+            self.epochs.iter().filter_map(|(_, epoch)| epoch.slots.get(&index)).next()
+        };
+        if index == 0 {
+            // Genesis is not signed and commits to nothing.
+            if hash_struct(&header.body) != header.hash
+                || header.body.authority_set_hash != CryptoHash::default()
+            {
+                return Err(format!("Genesis header {:?} is not valid", header.hash));
+            }
+            return Ok(());
+        }
+        let authorities: Vec<_> = get_authorities(index)
+            .ok_or_else(|| format!("Authorities of #{} are missing", index))?
+            .iter()
+            .filter(|authority| !is_revoked(&self.head.revoked, &authority.account_id, index))
+            .cloned()
+            .collect();
+        verify_signed_by(header, &authorities)?;
+        let authority_set_hash = if index % epoch_length == 0 {
+            let epoch = index / epoch_length;
+            let slots = (1..=epoch_length)
+                .map(|i| get_authorities(epoch * epoch_length + i).cloned())
+                .collect::<Option<_>>()
+                .ok_or_else(|| format!("Authorities of epoch {} are missing", epoch))?;
+            AuthoritySet { epoch, slots }.hash()
+        } else {
+            CryptoHash::default()
+        };
+        if header.body.authority_set_hash != authority_set_hash {
+            return Err(format!("Header {:?} commits to other authorities", header.hash));
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the header matches its hash and is signed only by the given authorities.
+fn verify_signed_by(
+    header: &SignedBeaconBlockHeader,
+    authorities: &[SelectedAuthority],
+) -> Result<(), String> {
+    if hash_struct(&header.body) != header.hash {
+        return Err(format!("Header {:?} does not match its hash", header.hash));
+    }
+    if header.signature.is_empty() {
+        return Err(format!("Header {:?} is not signed", header.hash));
+    }
+    for signature in header.signature.iter() {
+        if !authorities
+            .iter()
+            .any(|authority| verify_signature(signature, &header.hash, &authority.public_key))
+        {
+            return Err(format!("Header {:?} is signed by a non-authority", header.hash));
+        }
+    }
+    Ok(())
+}
+
+fn schedule_key(genesis_hash: &CryptoHash, kind: u8, epoch: u64) -> Vec<u8> {
+    let mut key = genesis_hash.as_ref().to_vec();
+    key.push(kind);
+    key.extend(index_to_bytes(epoch));
+    key
 }
 
 /// Finds threshold for given proposals and number of seats.
@@ -92,45 +297,34 @@ fn find_threshold(proposed: &[u64], num_seats: u64) -> Result<u64, String> {
 impl Authority {
     // TODO: figure out a way to generalize Authority selection process, by providing AuthoritySelector.
 
-    /// Builds authority for given valid blockchain.
-    /// Starting from best block, figure out current authorities.
+    /// Builds authority for given valid blockchain. The schedule stored in the storage is
+    /// loaded and only the blocks processed since it was stored are replayed.
     pub fn new(
         authority_config: AuthorityConfig,
         blockchain: &BlockChain<SignedBeaconBlock>,
+        storage: Arc<Storage>,
     ) -> Self {
         let mut authority = Authority {
             authority_config,
             current: HashMap::default(),
             current_threshold: HashMap::default(),
+            seat_stake: HashMap::default(),
             proposals: HashMap::default(),
             current_epoch: 0,
             accepted_proposals: HashMap::default(),
+            last_index: 0,
+            randomness: CryptoHash::default(),
+            reveals: BTreeMap::new(),
+            revoked: BTreeMap::new(),
+            storage,
+            genesis_hash: blockchain.genesis_hash,
         };
-
-        // TODO: cache authorities in the Storage, to not need to process the whole chain.
-        let (initial_authority, threshold) = authority.proposals_to_authority(
-            &CryptoHash::default(),
-            &authority.authority_config.initial_authorities,
-            0,
-        );
-        // Initial authorities operate for first two epochs.
-        for (index, value) in initial_authority.iter() {
-            authority.current.insert(*index, value.clone());
-            authority
-                .current
-                .insert(*index + authority.authority_config.epoch_length, value.clone());
+        if !authority.load() {
+            authority.init();
         }
-        authority.current_threshold.insert(0, threshold);
-        authority.current_threshold.insert(1, threshold);
-        authority
-            .accepted_proposals
-            .insert(0, authority.authority_config.initial_authorities.clone());
-        authority
-            .accepted_proposals
-            .insert(1, authority.authority_config.initial_authorities.clone());
 
-        let last_index = blockchain.best_block().header().body.index;
-        for index in 1..last_index {
+        let best_index = blockchain.best_block().header().body.index;
+        for index in authority.last_index + 1..=best_index {
             // TODO: handle if block is not found.
             if let Some(header) = blockchain.get_header(&BlockId::Number(index)) {
                 authority.process_block_header(&header);
@@ -140,11 +334,176 @@ impl Authority {
         authority
     }
 
+    /// Selects the initial authorities, that operate for the first two epochs.
+    fn init(&mut self) {
+        let (initial_authority, threshold) = self.proposals_to_authority(
+            &CryptoHash::default(),
+            &self.authority_config.initial_authorities,
+            0,
+        );
+        let epoch_length = self.authority_config.epoch_length;
+        let second_epoch: HashMap<u64, Vec<SelectedAuthority>> = initial_authority
+            .iter()
+            .map(|(index, value)| (*index + epoch_length, value.clone()))
+            .collect();
+        let initial_proposals = self.authority_config.initial_authorities.clone();
+        let mut db_transaction = self.storage.transaction();
+        for (epoch, slots) in vec![(0, initial_authority), (1, second_epoch)] {
+            self.write_authorities(&mut db_transaction, epoch, slots, threshold);
+            self.write_proposals(&mut db_transaction, epoch, threshold, initial_proposals.clone());
+        }
+        self.write_head(&mut db_transaction);
+        self.storage.write(db_transaction).expect("Database write failed");
+    }
+
+    fn key(&self, kind: u8, epoch: u64) -> Vec<u8> {
+        schedule_key(&self.genesis_hash, kind, epoch)
+    }
+
+    fn signatures_key(&self, block_hash: &CryptoHash) -> Vec<u8> {
+        let mut key = self.genesis_hash.as_ref().to_vec();
+        key.push(SIGNATURES_KEY);
+        key.extend(block_hash.as_ref());
+        key
+    }
+
+    fn read<T: Decode>(&self, key: &[u8]) -> Option<T> {
+        match self.storage.get(storage::COL_AUTHORITIES, key) {
+            Ok(Some(data)) => {
+                Some(Decode::decode(data.as_ref()).expect("Failed to decode authority schedule"))
+            }
+            _ => None,
+        }
+    }
+
+    fn put<T: Encode>(&self, db_transaction: &mut DBTransaction, key: &[u8], value: &T) {
+        let data = Encode::encode(value).expect("Error serializing data");
+        db_transaction.put(storage::COL_AUTHORITIES, key, &data);
+    }
+
+    /// Loads the head of the schedule and the epochs around the current one.
+    /// Returns false if nothing is stored yet.
+    fn load(&mut self) -> bool {
+        let head: ScheduleHead = match self.read(&self.key(HEAD_KEY, 0)) {
+            Some(head) => head,
+            None => return false,
+        };
+        self.last_index = head.last_index;
+        self.current_epoch = head.current_epoch;
+        self.proposals = head.proposals;
+        self.randomness = head.randomness;
+        self.reveals = head.reveals;
+        self.revoked = head.revoked;
+        let epoch_proposals: EpochProposals = self
+            .read(&self.key(PROPOSALS_KEY, self.current_epoch))
+            .expect("Missing proposals for current epoch");
+        self.current_threshold.insert(self.current_epoch, epoch_proposals.threshold);
+        self.accepted_proposals.insert(self.current_epoch, epoch_proposals.accepted_proposals);
+        for epoch in self.current_epoch.saturating_sub(1)..=self.current_epoch + 1 {
+            if let Some(epoch_authorities) = self.read_epoch(epoch) {
+                self.current.extend(epoch_authorities.slots);
+                self.seat_stake.insert(epoch, epoch_authorities.seat_stake);
+            }
+        }
+        true
+    }
+
+    /// Index of the last processed block header.
+    pub fn last_index(&self) -> u64 {
+        self.last_index
+    }
+
+    /// Returns the stored schedule of the epochs that are needed after the last processed
+    /// block: the previous and the current one and the two selected ahead.
+    pub fn checkpoint(&self) -> Result<AuthorityCheckpoint, String> {
+        let head = self.read(&self.key(HEAD_KEY, 0)).ok_or("Authority schedule is not stored")?;
+        let epochs = (self.current_epoch.saturating_sub(1)..=self.current_epoch + 2)
+            .filter_map(|epoch| self.read_epoch(epoch).map(|authorities| (epoch, authorities)))
+            .collect();
+        let proposals = self
+            .read(&self.key(PROPOSALS_KEY, self.current_epoch))
+            .ok_or("Proposals of the current epoch are not stored")?;
+        Ok(AuthorityCheckpoint { head, epochs, proposals })
+    }
+
+    /// Adds the schedule of the checkpoint to `db_transaction` for the beacon chain that starts
+    /// from the block with given hash, so that `Authority::new` continues from it.
+    pub fn import_checkpoint(
+        db_transaction: &mut DBTransaction,
+        genesis_hash: &CryptoHash,
+        checkpoint: &AuthorityCheckpoint,
+    ) -> Result<(), String> {
+        let current_epoch = checkpoint.head.current_epoch;
+        let mut entries: Vec<_> = checkpoint
+            .epochs
+            .iter()
+            .map(|(epoch, authorities)| {
+                (schedule_key(genesis_hash, AUTHORITIES_KEY, *epoch), authorities.encode())
+            })
+            .collect();
+        entries.push((
+            schedule_key(genesis_hash, PROPOSALS_KEY, current_epoch),
+            checkpoint.proposals.encode(),
+        ));
+        entries.push((schedule_key(genesis_hash, HEAD_KEY, 0), checkpoint.head.encode()));
+        for (key, data) in entries {
+            let data = data.ok_or("Error serializing authority schedule")?;
+            db_transaction.put(storage::COL_AUTHORITIES, &key, &data);
+        }
+        Ok(())
+    }
+
+    fn read_epoch(&self, epoch: u64) -> Option<EpochAuthorities> {
+        self.read(&self.key(AUTHORITIES_KEY, epoch))
+    }
+
+    fn write_authorities(
+        &mut self,
+        db_transaction: &mut DBTransaction,
+        epoch: u64,
+        slots: HashMap<u64, Vec<SelectedAuthority>>,
+        seat_stake: u64,
+    ) {
+        let epoch_authorities = EpochAuthorities { slots, seat_stake };
+        self.put(db_transaction, &self.key(AUTHORITIES_KEY, epoch), &epoch_authorities);
+        self.current.extend(epoch_authorities.slots);
+        self.seat_stake.insert(epoch, seat_stake);
+    }
+
+    fn write_proposals(
+        &mut self,
+        db_transaction: &mut DBTransaction,
+        epoch: u64,
+        threshold: u64,
+        accepted_proposals: Vec<AuthorityProposal>,
+    ) {
+        let epoch_proposals = EpochProposals { threshold, accepted_proposals };
+        self.put(db_transaction, &self.key(PROPOSALS_KEY, epoch), &epoch_proposals);
+        self.current_threshold.insert(epoch, threshold);
+        self.accepted_proposals.insert(epoch, epoch_proposals.accepted_proposals);
+    }
+
+    fn write_head(&self, db_transaction: &mut DBTransaction) {
+        let head = ScheduleHead {
+            last_index: self.last_index,
+            current_epoch: self.current_epoch,
+            proposals: self.proposals.clone(),
+            randomness: self.randomness,
+            reveals: self.reveals.clone(),
+            revoked: self.revoked.clone(),
+        };
+        self.put(db_transaction, &self.key(HEAD_KEY, 0), &head);
+    }
+
     pub fn process_block_header(&mut self, header: &SignedBeaconBlockHeader) {
         // Always skip genesis block.
         if header.body.index == 0 {
             return;
         }
+        let mut db_transaction = self.storage.transaction();
+        for (account_id, value) in header.body.random_reveals.iter() {
+            self.reveals.insert(account_id.clone(), *value);
+        }
         for authority_proposal in header.body.authority_proposal.iter() {
             self.proposals.insert(
                 authority_proposal.account_id.clone(),
@@ -154,8 +513,20 @@ impl Authority {
                 },
             );
         }
+        // The slashing proposal keeps the authority out of the epochs selected from now on,
+        // the seats of the epochs selected before are revoked from the next block.
+        let epoch_length = self.authority_config.epoch_length;
+        for account_id in header.body.slashed_authorities.iter() {
+            let revocation = Revocation {
+                first_index: header.body.index + 1,
+                last_index: (self.current_epoch + 3) * epoch_length,
+            };
+            self.revoked.insert(account_id.clone(), revocation);
+        }
         let header_authorities =
             self.get_authorities(header.body.index).expect("Processing block has unexpected index");
+        // The mask is not signed, but blocks only carry the signature of their producer, so
+        // going by the signatures would kick out all the other authorities of the slot.
         for (i, participated) in header.authority_mask.iter().enumerate() {
             if !participated {
                 let threshold = *self
@@ -195,24 +566,52 @@ impl Authority {
                 .expect("Missing proposals for current epoch")
                 .iter()
             {
-                let amount = self
-                    .proposals
-                    .get(&proposal.account_id)
-                    .unwrap_or(&RecordedProposal { public_key: proposal.public_key, stake: 0 })
-                    .stake;
-                if (amount < 0 && proposal.amount > (-amount) as u64) || amount == 0 {
+                // Authorities without a new record keep their seats. A new proposal replaces
+                // the accepted one, so unstaked authorities are not selected again.
+                // Slashed authorities are not selected again, even if they were kicked out
+                // after the slashing.
+                let keep = !self.revoked.contains_key(&proposal.account_id)
+                    && match self.proposals.get(&proposal.account_id) {
+                        Some(recorded_proposal) => {
+                            recorded_proposal.stake < 0
+                                && proposal.amount > (-recorded_proposal.stake) as u64
+                        }
+                        None => true,
+                    };
+                if keep {
                     new_proposals.push(proposal.clone());
                 }
             }
+            // Seats are assigned in the order of the proposals, which must not depend on the
+            // order of the hash map.
+            new_proposals.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+            self.randomness = epoch_seed(&self.randomness, &self.reveals);
+            self.reveals.clear();
             let (authorities, threshold) =
-                self.proposals_to_authority(&CryptoHash::default(), &new_proposals, 2);
-            self.current.extend(authorities);
-            self.current_threshold.insert(next_epoch, threshold);
+                self.proposals_to_authority(&self.randomness, &new_proposals, 2);
+            let selected_epoch = self.current_epoch + 2;
+            self.write_authorities(&mut db_transaction, selected_epoch, authorities, threshold);
+            self.write_proposals(&mut db_transaction, next_epoch, threshold, new_proposals);
             self.current_epoch = next_epoch;
             self.proposals = HashMap::default();
-            self.accepted_proposals.insert(next_epoch, new_proposals);
-            // TODO: clean up current for old epochs.
+            let first_index = self.current_epoch * epoch_length + 1;
+            self.revoked.retain(|_, revocation| revocation.last_index >= first_index);
+            self.prune();
         }
+        self.last_index = self.last_index.max(header.body.index);
+        self.write_head(&mut db_transaction);
+        self.storage.write(db_transaction).expect("Database write failed");
+    }
+
+    /// Drops old epochs from memory, they are read from the storage when needed.
+    fn prune(&mut self) {
+        let current_epoch = self.current_epoch;
+        let first_epoch = current_epoch.saturating_sub(1);
+        let first_index = first_epoch * self.authority_config.epoch_length + 1;
+        self.current.retain(|index, _| *index >= first_index);
+        self.seat_stake.retain(|epoch, _| *epoch >= first_epoch);
+        self.current_threshold.retain(|epoch, _| *epoch >= current_epoch);
+        self.accepted_proposals.retain(|epoch, _| *epoch >= current_epoch);
     }
 
     fn proposals_to_authority(
@@ -263,6 +662,16 @@ impl Authority {
         (result, threshold)
     }
 
+    #[inline]
+    pub fn epoch_length(&self) -> u64 {
+        self.authority_config.epoch_length
+    }
+
+    /// Epoch in which the block with given number takes its seats.
+    fn epoch(&self, index: u64) -> u64 {
+        epoch_of_block(index, self.authority_config.epoch_length)
+    }
+
     /// Returns authorities for given block number.
     pub fn get_authorities(&self, index: u64) -> Result<Vec<SelectedAuthority>, String> {
         if index == 0 {
@@ -270,6 +679,10 @@ impl Authority {
             Ok(vec![])
         } else if self.current.contains_key(&index) {
             Ok(self.current[&index].clone())
+        } else if let Some(authorities) =
+            self.read_epoch(self.epoch(index)).and_then(|mut epoch| epoch.slots.remove(&index))
+        {
+            Ok(authorities)
         } else {
             Err(format!(
                 "Authority for index {} is not found, current epoch {} has indices [{}, {}]",
@@ -280,6 +693,141 @@ impl Authority {
             ))
         }
     }
+
+    /// Whether the seats of given block number were revoked from the authority.
+    pub fn is_revoked(&self, account_id: &AccountId, index: u64) -> bool {
+        is_revoked(&self.revoked, account_id, index)
+    }
+
+    /// Returns the authorities of given block number that still hold their seats.
+    pub fn get_seat_holders(&self, index: u64) -> Result<Vec<SelectedAuthority>, String> {
+        Ok(self
+            .get_authorities(index)?
+            .into_iter()
+            .filter(|authority| !self.is_revoked(&authority.account_id, index))
+            .collect())
+    }
+
+    /// Marks the seats of given block number whose authority signed the hash and still holds
+    /// the seat.
+    pub fn get_signed_mask(
+        &self,
+        index: u64,
+        hash: &CryptoHash,
+        signatures: &[PartialSignature],
+    ) -> Result<AuthorityMask, String> {
+        let authorities = self.get_authorities(index)?;
+        let authority_mask = verified_mask(&authorities, hash, signatures);
+        Ok(authorities
+            .iter()
+            .zip(authority_mask.into_iter())
+            .map(|(authority, signed)| signed && !self.is_revoked(&authority.account_id, index))
+            .collect())
+    }
+
+    /// Returns authorities of all the seats of given epoch.
+    pub fn get_authority_set(&self, epoch: u64) -> Result<AuthoritySet, String> {
+        let epoch_length = self.authority_config.epoch_length;
+        let slots = (1..=epoch_length)
+            .map(|i| self.get_authorities(epoch * epoch_length + i))
+            .collect::<Result<_, _>>()?;
+        Ok(AuthoritySet { epoch, slots })
+    }
+
+    /// Returns the hash of the authority set the header of given block number commits to.
+    /// The last block of an epoch commits to the next epoch, the other blocks to nothing.
+    pub fn get_authority_set_hash(&self, index: u64) -> Result<CryptoHash, String> {
+        let epoch_length = self.authority_config.epoch_length;
+        if index == 0 || index % epoch_length != 0 {
+            return Ok(CryptoHash::default());
+        }
+        Ok(self.get_authority_set(index / epoch_length)?.hash())
+    }
+
+    /// Stores the signatures that make a supermajority for the last block of an epoch, they
+    /// prove the authorities of the next epoch to light clients.
+    pub fn write_transition_signatures(
+        &self,
+        block_hash: &CryptoHash,
+        signatures: &[PartialSignature],
+    ) {
+        let mut db_transaction = self.storage.transaction();
+        self.put(&mut db_transaction, &self.signatures_key(block_hash), &signatures.to_vec());
+        self.storage.write(db_transaction).expect("Database write failed");
+    }
+
+    /// Returns the signatures stored for the last block of an epoch.
+    pub fn get_transition_signatures(
+        &self,
+        block_hash: &CryptoHash,
+    ) -> Option<Vec<PartialSignature>> {
+        self.read(&self.signatures_key(block_hash))
+    }
+
+    /// Returns the authorities of the seats of given block number and whether they signed the
+    /// block hash. Empty if the authorities are not known.
+    pub fn get_participation(
+        &self,
+        index: u64,
+        hash: &CryptoHash,
+        signatures: &[PartialSignature],
+    ) -> Vec<(AccountId, bool)> {
+        let authorities = self.get_authorities(index).unwrap_or_default();
+        let authority_mask = self.get_signed_mask(index, hash, signatures).unwrap_or_default();
+        authorities
+            .into_iter()
+            .zip(authority_mask.into_iter())
+            .map(|(authority, signed)| (authority.account_id, signed))
+            .collect()
+    }
+
+    /// Returns the total stake of the seats of given block number whose authority signed the
+    /// block hash.
+    pub fn get_signed_stake(
+        &self,
+        index: u64,
+        hash: &CryptoHash,
+        signatures: &[PartialSignature],
+    ) -> Result<u64, String> {
+        let authorities = self.get_authorities(index)?;
+        if authorities.is_empty() {
+            return Ok(0);
+        }
+        let epoch = self.epoch(index);
+        let seat_stake = match self.seat_stake.get(&epoch) {
+            Some(seat_stake) => *seat_stake,
+            None => self
+                .read_epoch(epoch)
+                .ok_or_else(|| format!("Stake for epoch {} is not found", epoch))?
+                .seat_stake,
+        };
+        let authority_mask = self.get_signed_mask(index, hash, signatures)?;
+        let signed = authority_mask.into_iter().filter(|signed| *signed).count();
+        Ok(signed as u64 * seat_stake)
+    }
+}
+
+/// Weighs beacon blocks by the stake of the authorities whose signatures verify, the authority
+/// mask is not signed. Blocks whose authorities are not known yet weigh nothing.
+impl BlockWeigher<SignedBeaconBlock> for RwLock<Authority> {
+    fn weight(&self, block: &SignedBeaconBlock) -> u128 {
+        self.read()
+            .get_signed_stake(block.body.header.index, &block.hash, &block.signature)
+            .unwrap_or(0) as u128
+    }
+}
+
+/// Accepts headers signed only by the authorities of their block number that still hold their
+/// seats, and that commit to the right authority set.
+impl HeaderVerifier<SignedBeaconBlockHeader> for RwLock<Authority> {
+    fn verify(&self, header: &SignedBeaconBlockHeader) -> Result<(), String> {
+        let authority = self.read();
+        verify_signed_by(header, &authority.get_seat_holders(header.body.index)?)?;
+        if header.body.authority_set_hash != authority.get_authority_set_hash(header.body.index)? {
+            return Err(format!("Header {:?} commits to a wrong authority set", header.hash));
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -287,9 +835,11 @@ mod test {
     use std::sync::Arc;
 
     use chain::{SignedBlock, SignedHeader};
-    use primitives::hash::CryptoHash;
+    use primitives::hash::{hash, CryptoHash};
     use primitives::signature::get_keypair;
-    use storage::test_utils::MemoryStorage;
+    use primitives::signer::InMemorySigner;
+    use primitives::traits::Signer;
+    use storage::test_utils::{create_memory_db, MemoryStorage};
 
     use super::*;
 
@@ -306,14 +856,55 @@ mod test {
         AuthorityConfig { initial_authorities, epoch_length, num_seats_per_slot }
     }
 
-    fn test_blockchain(num_blocks: u64) -> BlockChain<SignedBeaconBlock> {
-        let storage = Arc::new(MemoryStorage::default());
+    /// Config whose initial authorities sign with the returned signers.
+    fn get_signed_test_config(
+        num_authorities: usize,
+        epoch_length: u64,
+        num_seats_per_slot: u64,
+    ) -> (AuthorityConfig, Vec<InMemorySigner>) {
+        let signers: Vec<_> = (0..num_authorities).map(|_| InMemorySigner::default()).collect();
+        let initial_authorities = signers
+            .iter()
+            .enumerate()
+            .map(|(i, signer)| AuthorityProposal {
+                account_id: i.to_string(),
+                public_key: signer.public_key(),
+                amount: 100,
+            })
+            .collect();
+        (AuthorityConfig { initial_authorities, epoch_length, num_seats_per_slot }, signers)
+    }
+
+    /// Signs the block by the authorities of the given seats and marks them in the mask.
+    fn sign_seats(
+        authority: &Authority,
+        signers: &[InMemorySigner],
+        block: &mut SignedBeaconBlock,
+        seats: &[bool],
+    ) {
+        let authorities = authority.get_authorities(block.body.header.index).unwrap();
+        for (selected, signed) in authorities.iter().zip(seats.iter()) {
+            if *signed {
+                let sig = block.sign(&signers[selected.account_id.parse::<usize>().unwrap()]);
+                block.add_signature(sig);
+            }
+        }
+        block.authority_mask = seats.to_vec();
+    }
+
+    fn test_blockchain(
+        num_blocks: u64,
+        storage: Arc<MemoryStorage>,
+    ) -> BlockChain<SignedBeaconBlock> {
+        let signer = InMemorySigner::default();
         let mut last_block =
             SignedBeaconBlock::new(0, CryptoHash::default(), vec![], CryptoHash::default());
         let bc = BlockChain::new(last_block.clone(), storage);
         for i in 1..num_blocks {
-            let block =
+            let mut block =
                 SignedBeaconBlock::new(i, last_block.block_hash(), vec![], CryptoHash::default());
+            let sig = block.sign(&signer);
+            block.add_signature(sig);
             bc.insert_block(block.clone());
             last_block = block;
         }
@@ -328,8 +919,9 @@ mod test {
             .iter()
             .map(|a| SelectedAuthority { account_id: a.account_id.clone(), public_key: a.public_key })
             .collect();
-        let bc = test_blockchain(0);
-        let mut authority = Authority::new(authority_config, &bc);
+        let storage = Arc::new(create_memory_db());
+        let bc = test_blockchain(0, storage.clone());
+        let mut authority = Authority::new(authority_config, &bc, storage);
         assert_eq!(authority.get_authorities(0).unwrap(), vec![]);
         assert_eq!(
             authority.get_authorities(1).unwrap(),
@@ -367,6 +959,242 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_unstaked_authority_is_removed() {
+        let authority_config = get_test_config(4, 2, 2);
+        let unstaked = authority_config.initial_authorities[1].clone();
+        let storage = Arc::new(create_memory_db());
+        let bc = test_blockchain(0, storage.clone());
+        let mut authority = Authority::new(authority_config, &bc, storage);
+        let proposal = AuthorityProposal { amount: 0, ..unstaked.clone() };
+        let block1 =
+            SignedBeaconBlock::new(1, bc.genesis_hash, vec![proposal], CryptoHash::default());
+        let mut header1 = block1.header();
+        header1.authority_mask = vec![true, true];
+        let block2 = SignedBeaconBlock::new(2, header1.block_hash(), vec![], CryptoHash::default());
+        let mut header2 = block2.header();
+        header2.authority_mask = vec![true, true];
+        authority.process_block_header(&header1);
+        authority.process_block_header(&header2);
+        // Seats that were already assigned are kept.
+        assert!((1..5).any(|index| {
+            authority
+                .get_authorities(index)
+                .unwrap()
+                .iter()
+                .any(|a| a.account_id == unstaked.account_id)
+        }));
+        for index in 5..7 {
+            let authorities = authority.get_authorities(index).unwrap();
+            assert_eq!(authorities.len(), 2);
+            assert!(authorities.iter().all(|a| a.account_id != unstaked.account_id));
+        }
+    }
+
+    #[test]
+    fn test_slashed_authority_loses_seats() {
+        let authority_config = get_test_config(4, 2, 2);
+        let slashed = authority_config.initial_authorities[0].clone();
+        let storage = Arc::new(create_memory_db());
+        let bc = test_blockchain(0, storage.clone());
+        let mut authority = Authority::new(authority_config, &bc, storage);
+        let proposal = AuthorityProposal { amount: 0, ..slashed.clone() };
+        let block1 =
+            SignedBeaconBlock::new(1, bc.genesis_hash, vec![proposal], CryptoHash::default());
+        let mut header1 = block1.header();
+        header1.body.slashed_authorities = vec![slashed.account_id.clone()];
+        header1.authority_mask = vec![true, true];
+        let block2 = SignedBeaconBlock::new(2, header1.block_hash(), vec![], CryptoHash::default());
+        let mut header2 = block2.header();
+        header2.authority_mask = vec![true, true];
+        authority.process_block_header(&header1);
+        authority.process_block_header(&header2);
+        let holds_seat = |index| {
+            authority
+                .get_seat_holders(index)
+                .unwrap()
+                .iter()
+                .any(|a| a.account_id == slashed.account_id)
+        };
+        // The block with the slash was produced with the seat.
+        assert!(holds_seat(1));
+        // The seats stay in the schedule, so the authority sets don't change.
+        assert!(authority
+            .get_authorities(3)
+            .unwrap()
+            .iter()
+            .any(|a| a.account_id == slashed.account_id));
+        for index in 2..7 {
+            assert!(!holds_seat(index));
+        }
+        assert_eq!(authority.get_seat_holders(3).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_stake_weighted_fork_choice() {
+        let (authority_config, signers) = get_signed_test_config(4, 2, 2);
+        let storage = Arc::new(create_memory_db());
+        let bc = test_blockchain(0, storage.clone());
+        let authority = Arc::new(RwLock::new(Authority::new(authority_config, &bc, storage)));
+        bc.set_weigher(authority.clone());
+        let signed_stake = |block: &SignedBeaconBlock| {
+            let index = block.body.header.index;
+            authority.read().get_signed_stake(index, &block.hash, &block.signature)
+        };
+
+        let mut block1 = SignedBeaconBlock::new(1, bc.genesis_hash, vec![], hash(&[1]));
+        sign_seats(&authority.read(), &signers, &mut block1, &[true, false]);
+        let mut block2 = SignedBeaconBlock::new(1, bc.genesis_hash, vec![], hash(&[2]));
+        sign_seats(&authority.read(), &signers, &mut block2, &[true, true]);
+        assert_eq!(signed_stake(&block1), Ok(100));
+        assert_eq!(signed_stake(&block2), Ok(200));
+        // Seats marked in the mask without a signature don't count.
+        let mut forged = block1.clone();
+        forged.authority_mask = vec![true, true];
+        assert_eq!(signed_stake(&forged), Ok(100));
+        let block5 = SignedBeaconBlock::new(5, bc.genesis_hash, vec![], hash(&[5]));
+        assert!(signed_stake(&block5).is_err());
+
+        bc.insert_block(block1.clone());
+        bc.insert_block(block2.clone());
+        assert_eq!(bc.best_block(), block2);
+        assert_eq!(bc.best_block_index().cumulative_weight, 201);
+
+        // Branch with the same stake doesn't replace the best block.
+        let mut block3 = SignedBeaconBlock::new(2, block1.block_hash(), vec![], hash(&[3]));
+        sign_seats(&authority.read(), &signers, &mut block3, &[false, true]);
+        bc.insert_block(block3.clone());
+        assert_eq!(bc.best_block(), block2);
+        let mut block4 = SignedBeaconBlock::new(2, block1.block_hash(), vec![], hash(&[4]));
+        sign_seats(&authority.read(), &signers, &mut block4, &[true, true]);
+        bc.insert_block(block4.clone());
+        assert_eq!(bc.best_block(), block4);
+    }
+
+    #[test]
+    fn test_verify_header() {
+        let signers: Vec<_> = (0..2).map(|_| InMemorySigner::default()).collect();
+        let initial_authorities = signers
+            .iter()
+            .enumerate()
+            .map(|(i, signer)| AuthorityProposal {
+                account_id: i.to_string(),
+                public_key: signer.public_key(),
+                amount: 100,
+            })
+            .collect();
+        let authority_config =
+            AuthorityConfig { initial_authorities, epoch_length: 2, num_seats_per_slot: 2 };
+        let storage = Arc::new(create_memory_db());
+        let bc = test_blockchain(0, storage.clone());
+        let authority = Arc::new(RwLock::new(Authority::new(authority_config, &bc, storage)));
+        bc.set_header_verifier(authority.clone());
+
+        let mut block1 = SignedBeaconBlock::new(1, bc.genesis_hash, vec![], hash(&[1]));
+        assert!(bc.insert_header(block1.header()).is_err());
+        let sig = block1.sign(&InMemorySigner::default());
+        block1.add_signature(sig);
+        assert!(bc.insert_header(block1.header()).is_err());
+
+        let authority_id = authority.read().get_authorities(1).unwrap()[0].account_id.clone();
+        let signer = &signers[authority_id.parse::<usize>().unwrap()];
+        let mut block1 = SignedBeaconBlock::new(1, bc.genesis_hash, vec![], hash(&[1]));
+        let sig = block1.sign(signer);
+        block1.add_signature(sig);
+        let mut forged_header = block1.header();
+        forged_header.body.shard_block_hash = hash(&[2]);
+        assert!(bc.insert_header(forged_header).is_err());
+        assert_eq!(bc.insert_header(block1.header()), Ok(true));
+        assert!(bc.is_known(&block1.hash));
+
+        // The last block of the epoch commits to the authorities of the next one.
+        let authority_id = authority.read().get_authorities(2).unwrap()[0].account_id.clone();
+        let signer = &signers[authority_id.parse::<usize>().unwrap()];
+        let signed_block = |authority_set_hash| {
+            let mut block = SignedBeaconBlock::with_commitments(
+                2,
+                block1.hash,
+                vec![],
+                hash(&[2]),
+                vec![],
+                vec![],
+                authority_set_hash,
+            );
+            let sig = block.sign(signer);
+            block.add_signature(sig);
+            block
+        };
+        assert!(bc.insert_header(signed_block(CryptoHash::default()).header()).is_err());
+        let authority_set_hash = authority.read().get_authority_set(1).unwrap().hash();
+        assert_eq!(authority.read().get_authority_set_hash(2), Ok(authority_set_hash));
+        assert_eq!(bc.insert_header(signed_block(authority_set_hash).header()), Ok(true));
+    }
+
+    #[test]
+    fn test_persisted_schedule() {
+        let (authority_config, signers) = get_signed_test_config(4, 2, 2);
+        let initial_authorities = authority_config.initial_authorities.clone();
+        let storage = Arc::new(create_memory_db());
+        let bc = test_blockchain(6, storage.clone());
+        let authority = Authority::new(authority_config, &bc, storage.clone());
+        assert_eq!(authority.last_index, 5);
+        assert_eq!(authority.current_epoch, 2);
+        assert!(!authority.current.contains_key(&1));
+
+        // Schedule is loaded without replaying the chain.
+        let config = || AuthorityConfig {
+            initial_authorities: initial_authorities.clone(),
+            epoch_length: 2,
+            num_seats_per_slot: 2,
+        };
+        let empty_bc = test_blockchain(0, Arc::new(create_memory_db()));
+        let loaded = Authority::new(config(), &empty_bc, storage);
+        // Schedule is rebuilt from the chain when nothing is stored.
+        let replayed = Authority::new(config(), &bc, Arc::new(create_memory_db()));
+        for index in 1..9 {
+            assert_eq!(loaded.get_authorities(index), authority.get_authorities(index));
+            assert_eq!(replayed.get_authorities(index), authority.get_authorities(index));
+            let block_hash = hash(&[index as u8]);
+            let signatures: Vec<_> =
+                signers.iter().map(|signer| signer.sign(&block_hash)).collect();
+            assert_eq!(
+                loaded.get_signed_stake(index, &block_hash, &signatures),
+                authority.get_signed_stake(index, &block_hash, &signatures)
+            );
+        }
+        assert!(loaded.get_authorities(9).is_err());
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let authority_config = get_test_config(4, 2, 2);
+        let initial_authorities = authority_config.initial_authorities.clone();
+        let storage = Arc::new(create_memory_db());
+        let bc = test_blockchain(6, storage.clone());
+        let authority = Authority::new(authority_config, &bc, storage);
+        let checkpoint = authority.checkpoint().unwrap();
+
+        // Chain that starts from the last block continues with the same schedule.
+        let start = bc.best_block();
+        let new_storage = Arc::new(create_memory_db());
+        let mut db_transaction = new_storage.transaction();
+        Authority::import_checkpoint(&mut db_transaction, &start.block_hash(), &checkpoint)
+            .unwrap();
+        new_storage.write(db_transaction).unwrap();
+        let new_bc = BlockChain::new(start.clone(), new_storage.clone());
+        let config =
+            AuthorityConfig { initial_authorities, epoch_length: 2, num_seats_per_slot: 2 };
+        let mut loaded = Authority::new(config, &new_bc, new_storage);
+        assert_eq!(loaded.last_index(), 5);
+        for index in 3..10 {
+            assert_eq!(loaded.get_authorities(index), authority.get_authorities(index));
+        }
+        let mut block6 = SignedBeaconBlock::new(6, start.block_hash(), vec![], hash(&[6]));
+        block6.authority_mask = vec![true, true];
+        loaded.process_block_header(&block6.header());
+        assert!(loaded.get_authorities(9).is_ok());
+    }
+
     #[test]
     fn test_find_threshold() {
         assert_eq!(find_threshold(&[1000000, 1000000, 10], 10).unwrap(), 200000);
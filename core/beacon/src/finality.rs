@@ -0,0 +1,237 @@
+//! Finality of the beacon chain blocks.
+//!
+//! A block signed by authorities holding more than 2/3 of the stake of its slot is final:
+//! the chain never reorganizes past it. Every seat of a slot stands for the same amount of
+//! stake (the threshold of the epoch), so the share of stake equals the share of seats.
+//!
+//! Only the producer signs a block. The other authorities of the slot co-sign it with votes
+//! that they broadcast once they import the block, and the votes are counted with the
+//! signature of the producer. The signatures of the last block of an epoch are stored, light
+//! clients follow the authority changes by them.
+use std::collections::HashMap;
+
+use authority::{Authority, SelectedAuthority};
+use chain::SignedHeader;
+use primitives::hash::CryptoHash;
+use primitives::signature::verify_signature;
+use primitives::traits::Signer;
+use primitives::types::{AuthorityMask, BlockId, PartialSignature};
+use types::{BeaconBlockChain, SignedBeaconBlockHeader};
+
+/// Signature of a beacon block by an authority of its slot.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct BlockVote {
+    /// Index of the block, selects the authorities that may vote for it.
+    pub index: u64,
+    pub block_hash: CryptoHash,
+    pub signature: PartialSignature,
+}
+
+impl BlockVote {
+    pub fn new(index: u64, block_hash: CryptoHash, signer: &Signer) -> Self {
+        BlockVote { index, block_hash, signature: signer.sign(&block_hash) }
+    }
+}
+
+/// Votes for the blocks that are not final yet. A vote can arrive before its block, so the
+/// votes are kept by the block hash until a later block is finalized.
+#[derive(Default)]
+pub struct VotePool {
+    /// Signatures of the votes by block hash, with the index of the block.
+    votes: HashMap<CryptoHash, (u64, Vec<PartialSignature>)>,
+}
+
+impl VotePool {
+    pub fn new() -> Self {
+        VotePool::default()
+    }
+
+    /// Adds the vote if it's signed by an authority of its slot that still holds its seat.
+    /// Returns whether the vote wasn't known yet.
+    pub fn add_vote(&mut self, authority: &Authority, vote: BlockVote) -> Result<bool, String> {
+        let authorities = authority.get_seat_holders(vote.index)?;
+        if !authorities.iter().any(|selected| {
+            verify_signature(&vote.signature, &vote.block_hash, &selected.public_key)
+        }) {
+            return Err(format!("Vote for block #{} is not signed by its authorities", vote.index));
+        }
+        let (_, signatures) =
+            self.votes.entry(vote.block_hash).or_insert_with(|| (vote.index, vec![]));
+        if signatures.contains(&vote.signature) {
+            return Ok(false);
+        }
+        signatures.push(vote.signature);
+        Ok(true)
+    }
+
+    /// Signatures of the votes for the block.
+    pub fn signatures(&self, block_hash: &CryptoHash) -> Vec<PartialSignature> {
+        self.votes.get(block_hash).map(|(_, signatures)| signatures.clone()).unwrap_or_default()
+    }
+
+    /// Drops the votes for the blocks up to the index.
+    pub fn prune(&mut self, index: u64) {
+        self.votes.retain(|_, (vote_index, _)| *vote_index > index);
+    }
+}
+
+/// Checks whether the authorities marked in the mask hold more than 2/3 of the stake.
+pub fn has_supermajority(
+    authorities: &[SelectedAuthority],
+    authority_mask: &AuthorityMask,
+) -> bool {
+    if authorities.is_empty() {
+        return false;
+    }
+    let signed =
+        authorities.iter().zip(authority_mask.iter()).filter(|(_, signed)| **signed).count();
+    3 * signed > 2 * authorities.len()
+}
+
+/// Finalizes the block of the given header if the signature of its producer and the votes
+/// for it make a supermajority. Only the seats whose signature verifies are counted, the
+/// authority mask is not signed. The signatures of the last block of an epoch are stored once
+/// they make a supermajority. Returns true if the finalized block of the chain has changed.
+pub fn process_block_header(
+    beacon_chain: &BeaconBlockChain,
+    authority: &Authority,
+    votes: &mut VotePool,
+    header: &SignedBeaconBlockHeader,
+) -> Result<bool, String> {
+    let authorities = authority.get_authorities(header.index())?;
+    let mut signatures = header.signature.clone();
+    for signature in votes.signatures(&header.hash) {
+        // The producer votes for its block too.
+        if !signatures.contains(&signature) {
+            signatures.push(signature);
+        }
+    }
+    let authority_mask = authority.get_signed_mask(header.index(), &header.hash, &signatures)?;
+    if !has_supermajority(&authorities, &authority_mask) {
+        return Ok(false);
+    }
+    if header.index() % authority.epoch_length() == 0 {
+        authority.write_transition_signatures(&header.hash, &signatures);
+    }
+    let old_finalized_hash = beacon_chain.finalized_block().hash;
+    beacon_chain.finalize_block(&header.block_hash())?;
+    let finalized_block = beacon_chain.finalized_block();
+    votes.prune(finalized_block.body.header.index);
+    Ok(finalized_block.hash != old_finalized_hash)
+}
+
+/// Adds the vote and finalizes its block if the block is stored and has a supermajority.
+/// Returns true if the finalized block of the chain has changed.
+pub fn process_vote(
+    beacon_chain: &BeaconBlockChain,
+    authority: &Authority,
+    votes: &mut VotePool,
+    vote: BlockVote,
+) -> Result<bool, String> {
+    let block_hash = vote.block_hash;
+    if !votes.add_vote(authority, vote)? {
+        return Ok(false);
+    }
+    match beacon_chain.get_header(&BlockId::Hash(block_hash)) {
+        Some(header) => process_block_header(beacon_chain, authority, votes, &header),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use authority::{AuthorityConfig, AuthorityProposal};
+    use chain::SignedBlock;
+    use primitives::hash::CryptoHash;
+    use primitives::signature::get_keypair;
+    use primitives::signer::InMemorySigner;
+    use primitives::traits::Signer;
+    use storage::test_utils::{create_memory_db, MemoryStorage};
+    use types::SignedBeaconBlock;
+
+    use super::*;
+
+    fn test_authority(
+        beacon_chain: &BeaconBlockChain,
+        storage: Arc<MemoryStorage>,
+        signers: &[InMemorySigner],
+    ) -> Authority {
+        let initial_authorities = signers
+            .iter()
+            .enumerate()
+            .map(|(i, signer)| AuthorityProposal {
+                account_id: i.to_string(),
+                public_key: signer.public_key(),
+                amount: 100,
+            })
+            .collect();
+        let authority_config =
+            AuthorityConfig { initial_authorities, epoch_length: 2, num_seats_per_slot: 3 };
+        Authority::new(authority_config, beacon_chain, storage)
+    }
+
+    #[test]
+    fn test_has_supermajority() {
+        let authorities: Vec<_> = (0..3)
+            .map(|i| SelectedAuthority { account_id: i.to_string(), public_key: get_keypair().0 })
+            .collect();
+        assert!(has_supermajority(&authorities, &vec![true, true, true]));
+        assert!(!has_supermajority(&authorities, &vec![true, false, true]));
+        assert!(!has_supermajority(&authorities, &vec![true, true]));
+        assert!(!has_supermajority(&[], &vec![]));
+    }
+
+    #[test]
+    fn test_process_block_header() {
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let storage = Arc::new(create_memory_db());
+        let beacon_chain = BeaconBlockChain::new(genesis.clone(), storage.clone());
+        let signers: Vec<_> = (0..3).map(|_| InMemorySigner::default()).collect();
+        let authority = test_authority(&beacon_chain, storage, &signers);
+        let mut votes = VotePool::new();
+        let process = |votes: &mut VotePool, header: &SignedBeaconBlockHeader| {
+            process_block_header(&beacon_chain, &authority, votes, header)
+        };
+        // Every authority holds one seat of each slot, the first one produces the blocks.
+        let produce = |index: u64, parent_hash: CryptoHash| {
+            let mut block =
+                SignedBeaconBlock::new(index, parent_hash, vec![], CryptoHash::default());
+            let sig = block.sign(&signers[0]);
+            block.add_signature(sig);
+            block
+        };
+
+        let mut block1 = produce(1, genesis.hash);
+        // The mask is not signed, claiming more seats doesn't count.
+        block1.authority_mask = vec![true, true, true];
+        beacon_chain.insert_block(block1.clone());
+        assert_eq!(process(&mut votes, &block1.header()), Ok(false));
+        let vote = BlockVote::new(1, block1.hash, &signers[1]);
+        assert_eq!(process_vote(&beacon_chain, &authority, &mut votes, vote.clone()), Ok(false));
+        assert_eq!(process_vote(&beacon_chain, &authority, &mut votes, vote), Ok(false));
+        assert_eq!(beacon_chain.finalized_block(), genesis);
+        let outsider = InMemorySigner::default();
+        let vote = BlockVote::new(1, block1.hash, &outsider);
+        assert!(process_vote(&beacon_chain, &authority, &mut votes, vote).is_err());
+        let vote = BlockVote::new(1, block1.hash, &signers[2]);
+        assert_eq!(process_vote(&beacon_chain, &authority, &mut votes, vote), Ok(true));
+        assert_eq!(beacon_chain.finalized_block(), block1);
+
+        // Votes that arrive before the block are counted once it's imported.
+        let block2 = produce(2, block1.hash);
+        for signer in signers[1..].iter() {
+            let vote = BlockVote::new(2, block2.hash, signer);
+            assert_eq!(process_vote(&beacon_chain, &authority, &mut votes, vote), Ok(false));
+        }
+        beacon_chain.insert_block(block2.clone());
+        assert_eq!(process(&mut votes, &block2.header()), Ok(true));
+        assert_eq!(beacon_chain.finalized_block(), block2);
+        // Only the signatures of the last block of the epoch are stored.
+        assert_eq!(authority.get_transition_signatures(&block1.hash), None);
+        assert_eq!(authority.get_transition_signatures(&block2.hash).map(|s| s.len()), Some(3));
+        assert_eq!(process(&mut votes, &block2.header()), Ok(false));
+        assert!(votes.signatures(&block2.hash).is_empty());
+    }
+}
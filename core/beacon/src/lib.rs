@@ -7,5 +7,12 @@ extern crate chain;
 extern crate primitives;
 extern crate storage;
 
+#[cfg(test)]
+extern crate futures;
+
 pub mod authority;
+pub mod finality;
+pub mod light_client;
+pub mod randomness;
+pub mod slashing;
 pub mod types;
@@ -0,0 +1,176 @@
+//! Proofs of the authority changes for light clients.
+//!
+//! A light client knows only the authorities of the genesis epoch. The last block of every
+//! epoch commits to the hash of the authorities of the next one. Its producer and the votes
+//! of the other authorities of its slot sign it, and the nodes store these signatures once
+//! they make a supermajority. A chain of these headers with their signatures and the committed
+//! authority sets proves the authorities of any later epoch without the rest of the beacon
+//! chain.
+use authority::{verified_mask, Authority, AuthoritySet};
+use chain::SignedHeader;
+use finality::has_supermajority;
+use primitives::hash::hash_struct;
+use primitives::types::{BlockId, PartialSignature};
+use types::{BeaconBlockChain, SignedBeaconBlockHeader};
+
+/// Header of the last block of an epoch and the authorities of the next epoch it commits to.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AuthorityTransition {
+    pub header: SignedBeaconBlockHeader,
+    /// Signatures of the header by the authorities of its slot, the header itself carries
+    /// only the signature of its producer.
+    pub signatures: Vec<PartialSignature>,
+    pub next_authorities: AuthoritySet,
+}
+
+/// Builds the transitions from the genesis authorities to the authorities of given epoch
+/// along the best chain.
+pub fn get_authority_proof(
+    beacon_chain: &BeaconBlockChain,
+    authority: &Authority,
+    epoch: u64,
+) -> Result<Vec<AuthorityTransition>, String> {
+    (1..=epoch)
+        .map(|epoch| {
+            let index = epoch * authority.epoch_length();
+            let header = beacon_chain
+                .get_header(&BlockId::Number(index))
+                .ok_or_else(|| format!("Header {} is not found", index))?;
+            let signatures = authority
+                .get_transition_signatures(&header.hash)
+                .ok_or_else(|| format!("Signatures of header {} are not stored", index))?;
+            let next_authorities = authority.get_authority_set(epoch)?;
+            Ok(AuthorityTransition { header, signatures, next_authorities })
+        })
+        .collect()
+}
+
+/// Follows the transitions from the genesis authorities and returns the authorities of the
+/// last epoch. The signatures of every header must come from more than 2/3 of the seats of its
+/// slot, and the header must commit to the authorities of the next transition.
+pub fn verify_authority_proof(
+    genesis_authorities: &AuthoritySet,
+    proof: &[AuthorityTransition],
+) -> Result<AuthoritySet, String> {
+    let epoch_length = genesis_authorities.slots.len() as u64;
+    let mut authorities = genesis_authorities.clone();
+    for transition in proof.iter() {
+        let header = &transition.header;
+        let next_authorities = &transition.next_authorities;
+        if header.index() != (authorities.epoch + 1) * epoch_length
+            || next_authorities.epoch != authorities.epoch + 1
+            || next_authorities.slots.len() as u64 != epoch_length
+        {
+            return Err(format!(
+                "Header {:?} is not a transition from epoch {}",
+                header.hash, authorities.epoch
+            ));
+        }
+        if hash_struct(&header.body) != header.hash {
+            return Err(format!("Header {:?} does not match its hash", header.hash));
+        }
+        if header.body.authority_set_hash != next_authorities.hash() {
+            return Err(format!("Header {:?} commits to other authorities", header.hash));
+        }
+        // The authority mask is not signed, so the seats are counted by the signatures.
+        let slot = authorities
+            .get_authorities(header.index())
+            .ok_or_else(|| format!("Authorities of header {:?} are not known", header.hash))?;
+        let authority_mask = verified_mask(slot, &header.hash, &transition.signatures);
+        if !has_supermajority(slot, &authority_mask) {
+            return Err(format!("Header {:?} is not signed by a supermajority", header.hash));
+        }
+        authorities = next_authorities.clone();
+    }
+    Ok(authorities)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use authority::{AuthorityConfig, AuthorityProposal};
+    use chain::SignedBlock;
+    use finality::{process_block_header, BlockVote, VotePool};
+    use primitives::hash::{hash, CryptoHash};
+    use primitives::signer::InMemorySigner;
+    use primitives::traits::Signer;
+    use storage::test_utils::create_memory_db;
+    use types::SignedBeaconBlock;
+
+    use super::*;
+
+    #[test]
+    fn test_authority_proof() {
+        let signers: Vec<_> = (0..3).map(|_| InMemorySigner::default()).collect();
+        let initial_authorities = signers
+            .iter()
+            .enumerate()
+            .map(|(i, signer)| AuthorityProposal {
+                account_id: i.to_string(),
+                public_key: signer.public_key(),
+                amount: 100,
+            })
+            .collect();
+        let authority_config =
+            AuthorityConfig { initial_authorities, epoch_length: 2, num_seats_per_slot: 3 };
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let storage = Arc::new(create_memory_db());
+        let beacon_chain = BeaconBlockChain::new(genesis.clone(), storage.clone());
+        let mut authority = Authority::new(authority_config, &beacon_chain, storage);
+        let mut votes = VotePool::new();
+
+        let mut last_block = genesis;
+        for index in 1..5 {
+            let mut block = SignedBeaconBlock::with_commitments(
+                index,
+                last_block.hash,
+                vec![],
+                hash(&[index as u8]),
+                vec![],
+                vec![],
+                authority.get_authority_set_hash(index).unwrap(),
+            );
+            block.authority_mask = vec![true, true, true];
+            // The producer signs the block and the other authorities of the slot vote for it.
+            let slot: Vec<_> = authority
+                .get_authorities(index)
+                .unwrap()
+                .iter()
+                .map(|selected| &signers[selected.account_id.parse::<usize>().unwrap()])
+                .collect();
+            let sig = block.sign(slot[0]);
+            block.add_signature(sig);
+            beacon_chain.insert_block(block.clone());
+            authority.process_block_header(&block.header());
+            for signer in slot[1..].iter() {
+                let vote = BlockVote::new(index, block.hash, *signer);
+                votes.add_vote(&authority, vote).unwrap();
+            }
+            process_block_header(&beacon_chain, &authority, &mut votes, &block.header()).unwrap();
+            last_block = block;
+        }
+
+        let genesis_authorities = authority.get_authority_set(0).unwrap();
+        let proof = get_authority_proof(&beacon_chain, &authority, 2).unwrap();
+        assert_eq!(proof.len(), 2);
+        assert_eq!(
+            verify_authority_proof(&genesis_authorities, &proof),
+            Ok(authority.get_authority_set(2).unwrap())
+        );
+        assert_eq!(
+            verify_authority_proof(&genesis_authorities, &[]),
+            Ok(genesis_authorities.clone())
+        );
+        assert!(verify_authority_proof(&genesis_authorities, &proof[1..]).is_err());
+        assert!(get_authority_proof(&beacon_chain, &authority, 3).is_err());
+
+        let mut forged = proof.clone();
+        forged[1].next_authorities.slots[0].pop();
+        assert!(verify_authority_proof(&genesis_authorities, &forged).is_err());
+        // The header alone carries only the signature of its producer.
+        let mut unsigned = proof.clone();
+        unsigned[0].signatures = unsigned[0].header.signature.clone();
+        assert!(verify_authority_proof(&genesis_authorities, &unsigned).is_err());
+    }
+}
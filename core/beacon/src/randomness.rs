@@ -0,0 +1,126 @@
+//! Randomness beacon of the beacon chain.
+//!
+//! Every authority commits to the tip of a hash chain and then reveals the chain backwards,
+//! one preimage per `RevealRandomness` transaction, once per epoch in which it holds a seat.
+//! Blocks list the values revealed in them, and the values revealed during an epoch are mixed
+//! into the seed of the previous epoch to seed the seat assignment, see `epoch_seed`.
+//!
+//! A revealed value is fixed by the commitment, so nobody can pick it, and the seed doesn't
+//! depend on the blocks the reveals land in or on their order. The result can still be biased
+//! by leaving reveals out: an authority can withhold its reveal once it has seen the others,
+//! and producers can drop submitted reveals. An authority that holds a seat and has committed
+//! to a hash chain, but reveals nothing during the epoch, gets no reward for it.
+use std::collections::BTreeMap;
+
+use primitives::hash::{hash, hash_struct, CryptoHash};
+use primitives::traits::Signer;
+use primitives::types::AccountId;
+
+/// Number of values in the hash chain of an authority, one is revealed per epoch.
+pub const HASH_CHAIN_LENGTH: u64 = 1 << 14;
+/// Message whose signature seeds the hash chain of an authority.
+const HASH_CHAIN_SEED: &[u8] = b"randomness beacon hash chain";
+
+/// Checks that the value is the preimage of the last committed value.
+pub fn verify_reveal(commitment: &CryptoHash, value: &CryptoHash) -> bool {
+    hash(value.as_ref()) == *commitment
+}
+
+/// Seed after an epoch with the given values revealed during it, ordered by their accounts.
+pub fn epoch_seed(
+    previous_seed: &CryptoHash,
+    reveals: &BTreeMap<AccountId, CryptoHash>,
+) -> CryptoHash {
+    hash_struct(&(previous_seed, reveals))
+}
+
+/// Secret hash chain of an authority.
+pub struct HashChain {
+    seed: CryptoHash,
+    length: u64,
+}
+
+impl HashChain {
+    pub fn new(seed: CryptoHash, length: u64) -> Self {
+        HashChain { seed, length }
+    }
+
+    /// Value `steps` hashes away from the seed.
+    fn value(&self, steps: u64) -> CryptoHash {
+        (0..steps).fold(self.seed, |value, _| hash(value.as_ref()))
+    }
+
+    /// Tip of the chain, published first.
+    pub fn commitment(&self) -> CryptoHash {
+        self.value(self.length)
+    }
+
+    /// Hash chain of the authority with given signer. It's seeded by a signature of a constant
+    /// message, so that only the authority knows it and gets it back after a restart.
+    pub fn for_signer(signer: &Signer) -> Self {
+        let seed = hash_struct(&signer.sign(&hash(HASH_CHAIN_SEED)));
+        HashChain::new(seed, HASH_CHAIN_LENGTH)
+    }
+
+    /// Value to publish after the given one, `None` if it's not on the chain or the chain is
+    /// used up.
+    pub fn next_reveal(&self, last_value: &CryptoHash) -> Option<CryptoHash> {
+        let mut value = self.seed;
+        for _ in 0..self.length {
+            let next = hash(value.as_ref());
+            if next == *last_value {
+                return Some(value);
+            }
+            value = next;
+        }
+        None
+    }
+
+    /// Value to publish after `count` values were revealed, `None` once the chain is used up.
+    pub fn reveal(&self, count: u64) -> Option<CryptoHash> {
+        if count < self.length {
+            Some(self.value(self.length - count - 1))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_chain() {
+        let chain = HashChain::new(hash(b"seed"), 3);
+        let mut commitment = chain.commitment();
+        for count in 0..3 {
+            let value = chain.reveal(count).unwrap();
+            assert!(verify_reveal(&commitment, &value));
+            assert!(!verify_reveal(&value, &commitment));
+            commitment = value;
+        }
+        assert_eq!(commitment, hash(b"seed"));
+        assert_eq!(chain.reveal(3), None);
+        assert_eq!(chain.next_reveal(&chain.commitment()), chain.reveal(0));
+        assert_eq!(chain.next_reveal(&chain.reveal(1).unwrap()), chain.reveal(2));
+        assert_eq!(chain.next_reveal(&hash(b"seed")), None);
+        assert_eq!(chain.next_reveal(&hash(b"other")), None);
+    }
+
+    #[test]
+    fn test_epoch_seed() {
+        let previous = hash(b"previous");
+        let mut reveals = BTreeMap::new();
+        assert_ne!(epoch_seed(&previous, &reveals), previous);
+        reveals.insert("bob".to_string(), hash(b"bob"));
+        let bob_only = epoch_seed(&previous, &reveals);
+        reveals.insert("alice".to_string(), hash(b"alice"));
+        assert_ne!(epoch_seed(&previous, &reveals), bob_only);
+        // Accounts are part of the seed, not only the values.
+        let mut swapped = BTreeMap::new();
+        swapped.insert("alice".to_string(), hash(b"bob"));
+        swapped.insert("bob".to_string(), hash(b"alice"));
+        assert_ne!(epoch_seed(&previous, &swapped), epoch_seed(&previous, &reveals));
+    }
+}
@@ -0,0 +1,226 @@
+//! Evidence of authority misbehavior.
+//!
+//! An honest authority signs at most one beacon block on top of a given parent, and every
+//! TxFlow message it creates approves its previous messages and is numbered after them. Two
+//! signed pieces of data that break these rules prove the misbehavior on their own, so the
+//! runtime can check the evidence without knowing the rest of the chain or the TxFlow DAG.
+//! The offender's stake is burned, it is not selected as an authority again and, from the
+//! block after the slash on, it loses the seats of the epochs it was already selected for.
+use std::collections::HashSet;
+
+use authority::{epoch_of_block, SelectedAuthority};
+use primitives::hash::{hash_struct, CryptoHash};
+use primitives::signature::{verify_signature, PublicKey};
+use primitives::traits::{Decode, Encode};
+use primitives::types::{AccountId, ChainPayload, MessageDataBody, Misbehavior, SignedEvidence};
+use types::{BeaconBlockHeader, SignedBeaconBlockHeader};
+
+fn decode<T: Decode>(evidence: &SignedEvidence) -> Result<T, String> {
+    Decode::decode(&evidence.data).ok_or_else(|| "Failed to decode the evidence".to_string())
+}
+
+fn is_signed(evidence: &SignedEvidence, hash: &CryptoHash, public_key: &PublicKey) -> bool {
+    verify_signature(&evidence.signature, hash, public_key)
+}
+
+/// Checks that the evidence proves misbehavior of the owner of the public key.
+pub fn verify_misbehavior(misbehavior: &Misbehavior, public_key: &PublicKey) -> Result<(), String> {
+    let signed = match misbehavior {
+        Misbehavior::BeaconEquivocation(first, second) => {
+            let first_header: BeaconBlockHeader = decode(first)?;
+            let second_header: BeaconBlockHeader = decode(second)?;
+            let first_hash = hash_struct(&first_header);
+            let second_hash = hash_struct(&second_header);
+            if first_hash == second_hash || first_header.parent_hash != second_header.parent_hash
+            {
+                return Err("Headers don't conflict".to_string());
+            }
+            is_signed(first, &first_hash, public_key) && is_signed(second, &second_hash, public_key)
+        }
+        Misbehavior::TxFlowFork(first, second) => {
+            let first_body: MessageDataBody<ChainPayload> = decode(first)?;
+            let second_body: MessageDataBody<ChainPayload> = decode(second)?;
+            let first_hash = first_body.signed_hash();
+            let second_hash = second_body.signed_hash();
+            // With the same parents or the same number neither message can approve the other.
+            let is_fork = first_body.parents == second_body.parents
+                || first_body.seq == second_body.seq;
+            if first_body.owner_uid != second_body.owner_uid
+                || !is_fork
+                || first_hash == second_hash
+            {
+                return Err("Messages don't conflict".to_string());
+            }
+            is_signed(first, &first_hash, public_key) && is_signed(second, &second_hash, public_key)
+        }
+    };
+    if signed {
+        Ok(())
+    } else {
+        Err("Evidence is not signed by the offender".to_string())
+    }
+}
+
+/// Identifies the misbehavior by the hashes signed by the offender, in either order, so that
+/// the same evidence can't be used twice.
+pub fn evidence_hash(misbehavior: &Misbehavior) -> Result<CryptoHash, String> {
+    let (first, second) = match misbehavior {
+        Misbehavior::BeaconEquivocation(first, second) => {
+            let first_header: BeaconBlockHeader = decode(first)?;
+            let second_header: BeaconBlockHeader = decode(second)?;
+            (hash_struct(&first_header), hash_struct(&second_header))
+        }
+        Misbehavior::TxFlowFork(first, second) => {
+            let first_body: MessageDataBody<ChainPayload> = decode(first)?;
+            let second_body: MessageDataBody<ChainPayload> = decode(second)?;
+            (first_body.signed_hash(), second_body.signed_hash())
+        }
+    };
+    Ok(hash_struct(&(first.min(second), first.max(second))))
+}
+
+/// Epoch of the misbehavior if the evidence shows it. Beacon headers carry their block number,
+/// while TxFlow messages don't tell which block they were for.
+pub fn misbehavior_epoch(
+    misbehavior: &Misbehavior,
+    epoch_length: u64,
+) -> Result<Option<u64>, String> {
+    match misbehavior {
+        Misbehavior::BeaconEquivocation(first, _) => {
+            let header: BeaconBlockHeader = decode(first)?;
+            Ok(Some(epoch_of_block(header.index, epoch_length)))
+        }
+        Misbehavior::TxFlowFork(_, _) => Ok(None),
+    }
+}
+
+/// Packages two headers signed by the owner of the public key into evidence, if they conflict.
+pub fn equivocation_evidence(
+    first: &SignedBeaconBlockHeader,
+    second: &SignedBeaconBlockHeader,
+    public_key: &PublicKey,
+) -> Option<Misbehavior> {
+    let evidence = |header: &SignedBeaconBlockHeader| {
+        let signature = header
+            .signature
+            .iter()
+            .find(|signature| verify_signature(signature, &header.hash, public_key))?;
+        Some(SignedEvidence { data: header.body.encode()?, signature: signature.clone() })
+    };
+    let misbehavior = Misbehavior::BeaconEquivocation(evidence(first)?, evidence(second)?);
+    if verify_misbehavior(&misbehavior, public_key).is_ok() {
+        Some(misbehavior)
+    } else {
+        None
+    }
+}
+
+/// Finds the authorities of the slot that signed both headers, with the evidence against each.
+pub fn find_equivocations(
+    authorities: &[SelectedAuthority],
+    first: &SignedBeaconBlockHeader,
+    second: &SignedBeaconBlockHeader,
+) -> Vec<(AccountId, Misbehavior)> {
+    let mut checked = HashSet::new();
+    authorities
+        .iter()
+        .filter(|authority| checked.insert(authority.account_id.clone()))
+        .filter_map(|authority| {
+            equivocation_evidence(first, second, &authority.public_key)
+                .map(|misbehavior| (authority.account_id.clone(), misbehavior))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chain::SignedBlock;
+    use primitives::hash::hash;
+    use primitives::signer::InMemorySigner;
+    use primitives::traits::Signer;
+    use primitives::types::TxFlowHash;
+    use types::SignedBeaconBlock;
+
+    use super::*;
+
+    fn signed_block(shard_block_hash: CryptoHash, signer: &InMemorySigner) -> SignedBeaconBlock {
+        let mut block =
+            SignedBeaconBlock::new(1, CryptoHash::default(), vec![], shard_block_hash);
+        let signature = block.sign(signer);
+        block.add_signature(signature);
+        block
+    }
+
+    fn signed_message(
+        parents: &[TxFlowHash],
+        seq: u64,
+        epoch: u64,
+        signer: &InMemorySigner,
+    ) -> SignedEvidence {
+        let body = MessageDataBody {
+            owner_uid: 0,
+            parents: parents.iter().cloned().collect(),
+            epoch,
+            seq,
+            payload: ChainPayload { body: vec![] },
+            endorsements: vec![],
+        };
+        let signature = signer.sign(&body.signed_hash());
+        SignedEvidence { data: body.encode().unwrap(), signature }
+    }
+
+    #[test]
+    fn test_beacon_equivocation() {
+        let signer = InMemorySigner::default();
+        let first = signed_block(hash(&[1]), &signer);
+        let second = signed_block(hash(&[2]), &signer);
+        let public_key = signer.public_key();
+        let evidence = equivocation_evidence(&first.header(), &second.header(), &public_key)
+            .expect("Blocks with the same parent conflict");
+        assert_eq!(verify_misbehavior(&evidence, &public_key), Ok(()));
+        assert!(verify_misbehavior(&evidence, &InMemorySigner::default().public_key()).is_err());
+        assert_eq!(misbehavior_epoch(&evidence, 2), Ok(Some(0)));
+        let swapped = equivocation_evidence(&second.header(), &first.header(), &public_key);
+        assert_eq!(evidence_hash(&swapped.unwrap()), evidence_hash(&evidence));
+        assert_eq!(equivocation_evidence(&first.header(), &first.header(), &public_key), None);
+        let other_signer = InMemorySigner::default();
+        let other = signed_block(hash(&[2]), &other_signer);
+        assert_eq!(equivocation_evidence(&first.header(), &other.header(), &public_key), None);
+
+        let authority = |account_id: &str, signer: &InMemorySigner| SelectedAuthority {
+            account_id: account_id.to_string(),
+            public_key: signer.public_key(),
+        };
+        let authorities = vec![
+            authority("alice", &signer),
+            authority("bob", &other_signer),
+            authority("alice", &signer),
+        ];
+        let offenders = find_equivocations(&authorities, &first.header(), &second.header());
+        assert_eq!(offenders, vec![("alice".to_string(), evidence)]);
+        assert!(find_equivocations(&authorities, &first.header(), &other.header()).is_empty());
+    }
+
+    #[test]
+    fn test_txflow_fork() {
+        let signer = InMemorySigner::default();
+        let public_key = signer.public_key();
+        let message = |parents: &[TxFlowHash], seq, epoch| {
+            signed_message(parents, seq, epoch, &signer)
+        };
+        let fork = Misbehavior::TxFlowFork(message(&[], 0, 1), message(&[], 0, 2));
+        assert_eq!(verify_misbehavior(&fork, &public_key), Ok(()));
+        // Messages with different parents conflict if they have the same number.
+        let fork = Misbehavior::TxFlowFork(message(&[1], 1, 1), message(&[2], 1, 1));
+        assert_eq!(verify_misbehavior(&fork, &public_key), Ok(()));
+        let chain = Misbehavior::TxFlowFork(message(&[1], 1, 1), message(&[2], 2, 1));
+        assert!(verify_misbehavior(&chain, &public_key).is_err());
+        let same = Misbehavior::TxFlowFork(message(&[1, 2], 1, 1), message(&[2, 1], 1, 1));
+        assert!(verify_misbehavior(&same, &public_key).is_err());
+        let forged = Misbehavior::TxFlowFork(
+            message(&[], 0, 1),
+            signed_message(&[], 0, 2, &InMemorySigner::default()),
+        );
+        assert!(verify_misbehavior(&forged, &public_key).is_err());
+    }
+}
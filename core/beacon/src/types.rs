@@ -1,6 +1,6 @@
 use chain::{SignedBlock, SignedHeader};
 use primitives::hash::{hash_struct, CryptoHash};
-use primitives::types::{AuthorityMask, MultiSignature, PartialSignature};
+use primitives::types::{AccountId, AuthorityMask, MultiSignature, PartialSignature};
 use authority::AuthorityProposal;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -13,6 +13,15 @@ pub struct BeaconBlockHeader {
     pub authority_proposal: Vec<AuthorityProposal>,
     /// Hash of the shard block.
     pub shard_block_hash: CryptoHash,
+    /// Values revealed for the randomness beacon in the block by their accounts, in the order
+    /// of the transactions, see `randomness::epoch_seed`.
+    pub random_reveals: Vec<(AccountId, CryptoHash)>,
+    /// Authorities slashed by the transactions of the block. They lose the seats they were
+    /// already selected for, see `authority::Authority::is_revoked`.
+    pub slashed_authorities: Vec<AccountId>,
+    /// Hash of the authorities of the next epoch, committed by the last block of every epoch
+    /// and default in the other blocks, see `authority::AuthoritySet`.
+    pub authority_set_hash: CryptoHash,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -58,7 +67,35 @@ impl SignedBeaconBlock {
         authority_proposal: Vec<AuthorityProposal>,
         shard_block_hash: CryptoHash,
     ) -> SignedBeaconBlock {
-        let header = BeaconBlockHeader { index, parent_hash, authority_proposal, shard_block_hash };
+        SignedBeaconBlock::with_commitments(
+            index,
+            parent_hash,
+            authority_proposal,
+            shard_block_hash,
+            vec![],
+            vec![],
+            CryptoHash::default(),
+        )
+    }
+
+    pub fn with_commitments(
+        index: u64,
+        parent_hash: CryptoHash,
+        authority_proposal: Vec<AuthorityProposal>,
+        shard_block_hash: CryptoHash,
+        random_reveals: Vec<(AccountId, CryptoHash)>,
+        slashed_authorities: Vec<AccountId>,
+        authority_set_hash: CryptoHash,
+    ) -> SignedBeaconBlock {
+        let header = BeaconBlockHeader {
+            index,
+            parent_hash,
+            authority_proposal,
+            shard_block_hash,
+            random_reveals,
+            slashed_authorities,
+            authority_set_hash,
+        };
         let hash = hash_struct(&header);
         SignedBeaconBlock {
             body: BeaconBlock { header },
@@ -94,8 +131,9 @@ impl SignedBlock for SignedBeaconBlock {
         self.signature.push(signature);
     }
 
+    /// Number of signatures. Chains that know the authorities weigh blocks by stake
+    /// instead, see `BlockWeigher` for `Authority`.
     fn weight(&self) -> u128 {
-        // TODO(#279): sum stakes instead of counting them
         self.signature.len() as u128
     }
 }
@@ -107,11 +145,14 @@ mod tests {
     use std::sync::Arc;
 
     use chain::BlockChain;
+    use futures::Stream;
     use primitives::hash::hash;
     use primitives::signer::InMemorySigner;
     use primitives::types::BlockId;
+    use primitives::utils::index_to_bytes;
     use std::collections::HashMap;
     use storage::test_utils::create_memory_db;
+    use storage::{DBTransaction, KeyValueDB};
 
     use super::*;
 
@@ -148,6 +189,21 @@ mod tests {
         assert_eq!(other_bc.get_block(&BlockId::Hash(block1.block_hash())).unwrap(), block1);
     }
 
+    #[test]
+    fn test_chain_from_later_block() {
+        let storage = Arc::new(create_memory_db());
+        let start = SignedBeaconBlock::new(10, hash(&[1]), vec![], CryptoHash::default());
+        let bc = BlockChain::new(start.clone(), storage.clone());
+        assert_eq!(bc.get_block(&BlockId::Number(10)).unwrap(), start);
+        assert_eq!(bc.best_index(), 10);
+        let block = SignedBeaconBlock::new(11, start.block_hash(), vec![], CryptoHash::default());
+        assert_eq!(bc.insert_block(block.clone()), false);
+        assert_eq!(bc.get_block(&BlockId::Number(11)).unwrap(), block);
+        assert_eq!(bc.iter_headers(&block.block_hash()).count(), 2);
+        let restarted = BlockChain::new(start.clone(), storage);
+        assert_eq!(restarted.best_block(), block);
+    }
+
     #[test]
     fn test_two_chains() {
         let storage = Arc::new(create_memory_db());
@@ -185,6 +241,19 @@ mod tests {
         }
         let best_hash = bc.best_block().block_hash();
         assert_eq!(best_hash, blocks.get(&expect).unwrap().block_hash());
+
+        // The index must point to the best chain only, regardless of the insertion order.
+        let best_index = bc.best_index();
+        let mut block = bc.best_block();
+        loop {
+            let index = block.body.header.index;
+            assert_eq!(bc.get_block(&BlockId::Number(index)).unwrap(), block);
+            if index == 0 {
+                break;
+            }
+            block = bc.get_block(&BlockId::Hash(block.body.header.parent_hash)).unwrap();
+        }
+        assert!(bc.get_block(&BlockId::Number(best_index + 1)).is_none());
     }
 
     #[test]
@@ -228,4 +297,298 @@ mod tests {
         // subtree weight of the lower fork is higher.  As is, we prefer the top fork.
         test_fork_choice_rule_helper(vec![(1, 0, 65), (2, 1, 20), (3, 0, 30), (4, 3, 35), (5, 3, 40)], 2);
     }
+
+    fn create_signed_block(
+        parent: &SignedBeaconBlock,
+        id: u8,
+        signers: &[InMemorySigner],
+    ) -> SignedBeaconBlock {
+        let mut block = SignedBeaconBlock::new(
+            parent.body.header.index + 1,
+            parent.block_hash(),
+            vec![],
+            hash(&[id]),
+        );
+        for signer in signers.iter() {
+            let sig = block.sign(signer);
+            block.add_signature(sig);
+        }
+        block
+    }
+
+    #[test]
+    fn test_reorg_notifications() {
+        let storage = Arc::new(create_memory_db());
+        let signers = (0..3).map(|_| InMemorySigner::default()).collect::<Vec<_>>();
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let bc = BlockChain::new(genesis.clone(), storage);
+        let reorgs = bc.subscribe_reorgs();
+
+        let block1 = create_signed_block(&genesis, 1, &signers[..1]);
+        let block2 = create_signed_block(&block1, 2, &signers[..1]);
+        let block3 = create_signed_block(&genesis, 3, &signers);
+        bc.insert_block(block1.clone());
+        bc.insert_block(block2.clone());
+        bc.insert_block(block3.clone());
+        assert_eq!(bc.best_block(), block3);
+
+        // Dropping the chain closes the stream.
+        drop(bc);
+        let reorgs: Vec<_> = reorgs.wait().map(|reorg| reorg.unwrap()).collect();
+        assert_eq!(reorgs.len(), 1);
+        assert_eq!(reorgs[0].retracted, vec![block2, block1]);
+        assert_eq!(reorgs[0].enacted, vec![block3]);
+    }
+
+    #[test]
+    fn test_orphan_blocks() {
+        let storage = Arc::new(create_memory_db());
+        let signers = vec![InMemorySigner::default()];
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let bc = BlockChain::new(genesis.clone(), storage);
+        let missing_blocks = bc.subscribe_missing_blocks();
+
+        let block1 = create_signed_block(&genesis, 1, &signers);
+        let block2 = create_signed_block(&block1, 2, &signers);
+        let block3 = create_signed_block(&block2, 3, &signers);
+        assert!(bc.insert_block(block3.clone()));
+        assert!(bc.insert_block(block2.clone()));
+        assert_eq!(bc.orphan_count(), 2);
+        assert_eq!(bc.best_block(), genesis);
+
+        assert!(!bc.insert_block(block1.clone()));
+        assert_eq!(bc.orphan_count(), 0);
+        assert_eq!(bc.best_block(), block3);
+        assert_eq!(bc.best_block_index().cumulative_weight, 4);
+        bc.request_missing_block(hash(&[4]));
+
+        drop(bc);
+        let missing_blocks: Vec<_> = missing_blocks.wait().map(|hash| hash.unwrap()).collect();
+        assert_eq!(missing_blocks, vec![block2.block_hash(), block1.block_hash(), hash(&[4])]);
+    }
+
+    #[test]
+    fn test_bounded_caches() {
+        let storage = Arc::new(create_memory_db());
+        let signers = vec![InMemorySigner::default()];
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let bc = BlockChain::with_cache_size(genesis.clone(), storage, 2);
+        let mut blocks = vec![genesis];
+        for i in 1..5 {
+            let block = create_signed_block(&blocks[i - 1], i as u8, &signers);
+            bc.insert_block(block.clone());
+            blocks.push(block);
+        }
+        // Evicted entries are read back from the storage.
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(bc.get_block(&BlockId::Number(i as u64)).as_ref(), Some(block));
+        }
+        let stats = bc.cache_stats();
+        assert!(stats["blocks"].misses > 0);
+        assert!(stats["block_index"].hits > 0);
+    }
+
+    #[test]
+    fn test_recover_canonical_chain() {
+        let storage = Arc::new(create_memory_db());
+        let signers = vec![InMemorySigner::default()];
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let bc = BlockChain::new(genesis.clone(), storage.clone());
+        let block1 = create_signed_block(&genesis, 1, &signers);
+        bc.insert_block(block1.clone());
+        drop(bc);
+
+        // Simulate a crash in the middle of the index update of an older version.
+        let mut db_transaction = storage.transaction();
+        db_transaction.delete(storage::COL_BLOCK_INDEX, &index_to_bytes(1));
+        db_transaction.put(storage::COL_BLOCK_INDEX, &index_to_bytes(2), genesis.hash.as_ref());
+        storage.write(db_transaction).unwrap();
+
+        let bc = BlockChain::new(genesis.clone(), storage);
+        assert_eq!(bc.best_block(), block1);
+        assert_eq!(bc.get_block(&BlockId::Number(1)).unwrap(), block1);
+        assert!(bc.get_block(&BlockId::Number(2)).is_none());
+    }
+
+    #[test]
+    fn test_insert_block_pair() {
+        let storage = Arc::new(create_memory_db());
+        let signers = vec![InMemorySigner::default()];
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let other_genesis = SignedBeaconBlock::genesis(hash(&[1]));
+        let bc = BlockChain::new(genesis.clone(), storage.clone());
+        let other_bc = BlockChain::new(other_genesis.clone(), storage.clone());
+        let block1 = create_signed_block(&genesis, 1, &signers);
+        let other_block1 = create_signed_block(&other_genesis, 2, &signers);
+        let mut db_transaction = storage.transaction();
+        db_transaction.put(storage::COL_EXTRA, b"state", b"value");
+        bc.insert_block_pair(block1.clone(), &other_bc, other_block1.clone(), db_transaction)
+            .unwrap();
+        assert_eq!(bc.best_block(), block1);
+        assert_eq!(other_bc.best_block(), other_block1);
+        assert_eq!(&*storage.get(storage::COL_EXTRA, b"state").unwrap().unwrap(), b"value");
+        // Stored blocks and blocks with missing parents are rejected.
+        let block2 = create_signed_block(&block1, 3, &signers);
+        let stored = other_block1.clone();
+        let result = bc.insert_block_pair(block2.clone(), &other_bc, stored, DBTransaction::new());
+        assert!(result.is_err());
+        let missing = create_signed_block(&other_block1, 4, &signers);
+        let other_orphan = create_signed_block(&missing, 5, &signers);
+        assert!(bc
+            .insert_block_pair(block2, &other_bc, other_orphan, DBTransaction::new())
+            .is_err());
+        assert_eq!(bc.best_block(), block1);
+
+        let restarted = BlockChain::new(other_genesis, storage);
+        assert_eq!(restarted.best_block(), other_block1);
+    }
+
+    #[test]
+    fn test_finalized_block() {
+        let storage = Arc::new(create_memory_db());
+        let signers = (0..3).map(|_| InMemorySigner::default()).collect::<Vec<_>>();
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let bc = BlockChain::new(genesis.clone(), storage.clone());
+        assert_eq!(bc.finalized_block(), genesis);
+
+        let block1 = create_signed_block(&genesis, 1, &signers[..1]);
+        let block2 = create_signed_block(&block1, 2, &signers[..1]);
+        bc.insert_block(block1.clone());
+        bc.insert_block(block2.clone());
+        assert!(bc.finalize_block(&block1.block_hash()).is_ok());
+        assert_eq!(bc.finalized_block(), block1);
+
+        // Heavier branch that doesn't contain the finalized block is ignored.
+        let block3 = create_signed_block(&genesis, 3, &signers);
+        bc.insert_block(block3.clone());
+        assert_eq!(bc.best_block(), block2);
+        assert!(bc.finalize_block(&block3.block_hash()).is_err());
+
+        // Heavier branch on top of the finalized block is accepted.
+        let block4 = create_signed_block(&block1, 4, &signers);
+        bc.insert_block(block4.clone());
+        assert_eq!(bc.best_block(), block4);
+
+        // Finalizing an ancestor keeps the later finalized block.
+        assert!(bc.finalize_block(&block4.block_hash()).is_ok());
+        assert!(bc.finalize_block(&block1.block_hash()).is_ok());
+        assert_eq!(bc.finalized_block(), block4);
+
+        drop(bc);
+        let bc = BlockChain::new(genesis.clone(), storage);
+        assert_eq!(bc.finalized_block(), block4);
+    }
+
+    #[test]
+    fn test_ancestor_queries() {
+        let storage = Arc::new(create_memory_db());
+        let signers = (0..2).map(|_| InMemorySigner::default()).collect::<Vec<_>>();
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let bc = BlockChain::new(genesis.clone(), storage);
+
+        // genesis - 1 - 2 - 3 - 4
+        //               \
+        //                 5 - 6
+        let mut best = vec![genesis.clone()];
+        for i in 1..5 {
+            let block = create_signed_block(&best[i - 1], i as u8, &signers);
+            bc.insert_block(block.clone());
+            best.push(block);
+        }
+        let block5 = create_signed_block(&best[2], 5, &signers[..1]);
+        let block6 = create_signed_block(&block5, 6, &signers[..1]);
+        bc.insert_block(block5.clone());
+        bc.insert_block(block6.clone());
+        assert_eq!(bc.best_block(), best[4]);
+
+        assert!(bc.is_ancestor(&best[1].hash, &best[4].hash));
+        assert!(bc.is_ancestor(&best[2].hash, &block6.hash));
+        assert!(bc.is_ancestor(&block6.hash, &block6.hash));
+        assert!(!bc.is_ancestor(&best[3].hash, &block6.hash));
+        assert!(!bc.is_ancestor(&block5.hash, &best[4].hash));
+        assert!(!bc.is_ancestor(&best[4].hash, &best[1].hash));
+
+        assert_eq!(bc.get_ancestor_header(&block6.hash, 3), Some(block5.header()));
+        assert_eq!(bc.get_ancestor_header(&block6.hash, 1), Some(best[1].header()));
+        assert_eq!(bc.get_ancestor_header(&block6.hash, 5), None);
+
+        assert_eq!(bc.get_common_ancestor(&best[4].hash, &block6.hash), Some(best[2].header()));
+        assert_eq!(bc.get_common_ancestor(&block5.hash, &block6.hash), Some(block5.header()));
+        assert_eq!(bc.get_common_ancestor(&best[1].hash, &best[3].hash), Some(best[1].header()));
+
+        let hashes: Vec<_> = bc.iter_headers(&block6.hash).map(|header| header.hash).collect();
+        assert_eq!(
+            hashes,
+            vec![block6.hash, block5.hash, best[2].hash, best[1].hash, genesis.hash]
+        );
+    }
+
+    #[test]
+    fn test_skip_pointers() {
+        let storage = Arc::new(create_memory_db());
+        let signers = (0..3).map(|_| InMemorySigner::default()).collect::<Vec<_>>();
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let bc = BlockChain::new(genesis.clone(), storage.clone());
+        let mut best = genesis.clone();
+        for i in 1..40 {
+            best = create_signed_block(&best, i as u8, &signers);
+            bc.insert_block(best.clone());
+        }
+        // A long side branch that is lighter than the best chain.
+        let mut blocks = vec![genesis.clone()];
+        for i in 1..100 {
+            let block = create_signed_block(&blocks[i - 1], 100 + i as u8, &signers[..1]);
+            bc.insert_block(block.clone());
+            blocks.push(block);
+        }
+        assert_eq!(bc.best_block(), best);
+        let tip = blocks[99].hash;
+        let skip_pointer = |hash: &CryptoHash| {
+            storage.get(storage::COL_SKIP_POINTERS, hash.as_ref()).unwrap()
+        };
+        assert_eq!(&*skip_pointer(&tip).unwrap(), blocks[65].hash.as_ref());
+        assert!(skip_pointer(&blocks[1].hash).is_none());
+        for (index, block) in blocks.iter().enumerate() {
+            assert_eq!(bc.get_ancestor_header(&tip, index as u64), Some(block.header()));
+            assert!(bc.is_ancestor(&block.hash, &tip));
+        }
+    }
+
+    #[test]
+    fn test_header_first_sync() {
+        let storage = Arc::new(create_memory_db());
+        let signers = vec![InMemorySigner::default()];
+        let genesis = SignedBeaconBlock::genesis(CryptoHash::default());
+        let bc = BlockChain::new(genesis.clone(), storage);
+        let mut blocks = vec![genesis.clone()];
+        for i in 1..4 {
+            let block = create_signed_block(&blocks[i - 1], i as u8, &signers);
+            blocks.push(block);
+        }
+
+        assert!(bc.insert_header(blocks[2].header()).is_err());
+        let mut wrong_index = create_signed_block(&genesis, 4, &signers).header();
+        wrong_index.body.index = 2;
+        assert!(bc.insert_header(wrong_index).is_err());
+
+        for block in blocks[1..].iter() {
+            assert_eq!(bc.insert_header(block.header()), Ok(true));
+        }
+        assert_eq!(bc.insert_header(blocks[1].header()), Ok(false));
+        assert!(bc.is_known(&blocks[3].hash));
+        assert!(!bc.has_block(&blocks[3].hash));
+        assert_eq!(bc.best_block(), genesis);
+        assert_eq!(
+            bc.get_missing_bodies(&blocks[3].hash, 10),
+            vec![blocks[1].hash, blocks[2].hash, blocks[3].hash]
+        );
+        assert_eq!(bc.get_missing_bodies(&blocks[3].hash, 1), vec![blocks[1].hash]);
+
+        // Bodies arrive in any order and are connected once their parents are.
+        assert!(bc.insert_block(blocks[3].clone()));
+        assert!(bc.insert_block(blocks[2].clone()));
+        assert!(!bc.insert_block(blocks[1].clone()));
+        assert_eq!(bc.best_block(), blocks[3]);
+        assert!(bc.get_missing_bodies(&blocks[3].hash, 10).is_empty());
+    }
 }
@@ -0,0 +1,136 @@
+//! Size-bounded cache that evicts the least recently used entries.
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use storage::metrics::CacheStats;
+
+/// Default number of entries in each of the `BlockChain` caches.
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+pub struct LruCache<K, V> {
+    capacity: usize,
+    /// Values with the tick under which they are in `order` and the tick of their last access.
+    entries: HashMap<K, (V, usize, AtomicUsize)>,
+    /// Keys ordered by the tick of their insertion. Lookups only update the last access of the
+    /// entry, so that they don't need exclusive access; the order catches up on eviction.
+    order: BTreeMap<usize, K>,
+    next_tick: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_tick: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed) as u64,
+            misses: self.misses.load(Ordering::Relaxed) as u64,
+        }
+    }
+
+    fn tick(&self) -> usize {
+        self.next_tick.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Checks presence of the key without counting it as an access.
+    pub fn contains_key<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.entries.contains_key(key)
+    }
+
+    /// Returns the value and marks it as the most recently used one.
+    pub fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        match self.entries.get(key) {
+            Some((value, _, last_access)) => {
+                last_access.store(self.tick(), Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(value)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Inserts the value, evicting the least recently used entries above the capacity.
+    pub fn insert(&mut self, key: K, value: V) {
+        let tick = self.tick();
+        let entry = (value, tick, AtomicUsize::new(tick));
+        if let Some((_, order_tick, _)) = self.entries.insert(key.clone(), entry) {
+            self.order.remove(&order_tick);
+        }
+        self.order.insert(tick, key);
+        while self.entries.len() > self.capacity {
+            let oldest = *self.order.keys().next().expect("Cache is not empty");
+            let key = self.order.remove(&oldest).expect("Cache order is broken");
+            let last_access = {
+                let (_, order_tick, last_access) =
+                    self.entries.get_mut(&key).expect("Cache order is broken");
+                *order_tick = last_access.load(Ordering::Relaxed);
+                *order_tick
+            };
+            if last_access > oldest {
+                // Accessed since it was ordered, so it moves to its last access.
+                self.order.insert(last_access, key);
+            } else {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        let (value, order_tick, _) = self.entries.remove(key)?;
+        self.order.remove(&order_tick);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eviction() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, "one");
+        cache.insert(2, "two");
+        assert_eq!(cache.get(&1), Some(&"one"));
+        cache.insert(3, "three");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some(&"one"));
+        assert_eq!(cache.get(&3), Some(&"three"));
+        assert_eq!(cache.remove(&1), Some("one"));
+        assert!(!cache.contains_key(&1));
+        assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 1 });
+    }
+}
@@ -1,3 +1,6 @@
+extern crate futures;
+#[macro_use]
+extern crate log;
 extern crate parking_lot;
 extern crate primitives;
 extern crate serde;
@@ -5,20 +8,29 @@ extern crate serde;
 extern crate serde_derive;
 extern crate storage;
 
-use std::collections::HashMap;
+pub mod cache;
+pub mod orphan_pool;
+
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::mem;
 use std::sync::Arc;
 
-use parking_lot::RwLock;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use parking_lot::{Mutex, RwLock};
 use serde::{de::DeserializeOwned, Serialize};
 
 use primitives::hash::CryptoHash;
 use primitives::traits::{Decode, Encode, Signer};
 use primitives::types::{BlockId, PartialSignature};
 use primitives::utils::index_to_bytes;
-use storage::Storage;
+use cache::{LruCache, DEFAULT_CACHE_SIZE};
+use orphan_pool::{OrphanPool, DEFAULT_MAX_ORPHANS};
+use storage::metrics::CacheStats;
+use storage::{DBTransaction, Storage};
 
 const BLOCKCHAIN_BEST_BLOCK: &[u8] = b"best";
+const BLOCKCHAIN_FINALIZED_BLOCK: &[u8] = b"finalized";
 
 /// Trait that abstracts ``Header"
 pub trait SignedHeader: Debug + Clone + Send + Sync + Serialize + DeserializeOwned + Eq + 'static
@@ -55,6 +67,17 @@ pub trait SignedBlock: Debug + Clone + Send + Sync + Serialize + DeserializeOwne
     fn weight(&self) -> u128;
 }
 
+/// Computes the weight of a block in the fork choice rule when it depends on more than the
+/// block itself, e.g. on the stakes of the authorities that signed it.
+pub trait BlockWeigher<B>: Send + Sync {
+    fn weight(&self, block: &B) -> u128;
+}
+
+/// Checks the signatures of headers that are inserted without their block.
+pub trait HeaderVerifier<H>: Send + Sync {
+    fn verify(&self, header: &H) -> Result<(), String>;
+}
+
 /// A block plus its "virtual" fields.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockIndex<B> {
@@ -63,52 +86,132 @@ pub struct BlockIndex<B> {
     pub cumulative_weight: u128,
 }
 
+/// Switch of the best chain to another branch.
+#[derive(Debug, Clone)]
+pub struct ChainReorg<B> {
+    /// Blocks that left the best chain, from the old best block down to the common ancestor.
+    pub retracted: Vec<B>,
+    /// Blocks that joined the best chain, from the common ancestor up to the new best block.
+    pub enacted: Vec<B>,
+}
+
 /// General BlockChain container.
 pub struct BlockChain<B: SignedBlock> {
     /// Storage backend.
     storage: Arc<Storage>,
     /// Genesis hash
     pub genesis_hash: CryptoHash,
+    /// Index of the genesis, the earliest ancestor that skip pointers can lead to.
+    genesis_index: u64,
     /// Best block key of current blockchain. Key length is CryptoHash length + "best" bytes.
     best_block_key: [u8; 36],
     /// Tip of the known chain.
     best_block_index: RwLock<BlockIndex<B>>,
+    /// Finalized block key of current blockchain. Key is CryptoHash plus "finalized" bytes.
+    finalized_block_key: [u8; 41],
+    /// Last finalized block. The best chain never switches to a branch that doesn't contain it.
+    finalized_header: RwLock<B::SignedHeader>,
     /// Headers indexed by hash
-    headers: RwLock<HashMap<Vec<u8>, B::SignedHeader>>,
+    headers: RwLock<LruCache<Vec<u8>, B::SignedHeader>>,
     /// Blocks indexed by hash
-    blocks: RwLock<HashMap<Vec<u8>, BlockIndex<B>>>,
-    /// Maps block index to hash.
-    // TODO: This doesn't handle forks at all and needs to be rewritten
-    index_to_hash: RwLock<HashMap<Vec<u8>, CryptoHash>>,
+    blocks: RwLock<LruCache<Vec<u8>, BlockIndex<B>>>,
+    /// Maps block index to hash of the block on the best chain. Blocks of other branches are
+    /// only reachable by hash.
+    index_to_hash: RwLock<LruCache<Vec<u8>, CryptoHash>>,
+    /// Receivers of the best chain reorganizations.
+    reorg_subscribers: Mutex<Vec<UnboundedSender<ChainReorg<B>>>>,
+    /// Blocks waiting for their parent.
+    orphans: Mutex<OrphanPool<B>>,
+    /// Receivers of hashes of missing blocks that should be fetched from peers.
+    missing_block_subscribers: Mutex<Vec<UnboundedSender<CryptoHash>>>,
+    /// Weight of the blocks for the fork choice rule, `SignedBlock::weight` if not set.
+    weigher: RwLock<Option<Arc<BlockWeigher<B>>>>,
+    /// Verifier of headers inserted without their block. Signatures are not checked if not set.
+    header_verifier: RwLock<Option<Arc<HeaderVerifier<B::SignedHeader>>>>,
     // TODO: state?
 }
 
-fn write_with_cache<T: Clone + Encode>(
-    storage: &Arc<Storage>,
-    col: Option<u32>,
-    cache: &RwLock<HashMap<Vec<u8>, T>>,
-    key: &[u8],
-    value: &T,
-) {
-    let data = Encode::encode(value).expect("Error serializing data");
-    cache.write().insert(key.to_vec(), value.clone());
-
-    let mut db_transaction = storage.transaction();
-    db_transaction.put(col, key, &data);
-    storage.write(db_transaction).expect("Database write failed");
+/// Writes of the chain together with the cache entries they change. The caches are updated
+/// only after the database write succeeds, so that they never serve what was not stored.
+struct ChainTransaction<B: SignedBlock> {
+    db_transaction: DBTransaction,
+    headers: Vec<(Vec<u8>, B::SignedHeader)>,
+    blocks: Vec<(Vec<u8>, BlockIndex<B>)>,
+    /// Canonical hashes by index, `None` if the index is dropped from the best chain.
+    index_to_hash: Vec<(Vec<u8>, Option<CryptoHash>)>,
+}
+
+impl<B: SignedBlock> ChainTransaction<B> {
+    fn new(db_transaction: DBTransaction) -> Self {
+        ChainTransaction { db_transaction, headers: vec![], blocks: vec![], index_to_hash: vec![] }
+    }
+
+    fn put<T: Encode>(&mut self, col: Option<u32>, key: &[u8], value: &T) {
+        let data = Encode::encode(value).expect("Error serializing data");
+        self.db_transaction.put(col, key, &data);
+    }
+
+    fn put_header(&mut self, block_hash: &CryptoHash, header: B::SignedHeader) {
+        self.put(storage::COL_HEADERS, block_hash.as_ref(), &header);
+        self.headers.push((block_hash.as_ref().to_vec(), header));
+    }
+
+    fn put_block(&mut self, block_hash: &CryptoHash, block_index: BlockIndex<B>) {
+        self.put(storage::COL_BLOCKS, block_hash.as_ref(), &block_index);
+        self.blocks.push((block_hash.as_ref().to_vec(), block_index));
+    }
+
+    fn put_canonical_hash(&mut self, index: u64, block_hash: &CryptoHash) {
+        let key = index_to_bytes(index);
+        self.put(storage::COL_BLOCK_INDEX, &key, block_hash);
+        self.index_to_hash.push((key, Some(*block_hash)));
+    }
+
+    fn put_skip_pointer(&mut self, block_hash: &CryptoHash, skip_hash: &CryptoHash) {
+        let col = storage::COL_SKIP_POINTERS;
+        self.db_transaction.put(col, block_hash.as_ref(), skip_hash.as_ref());
+    }
+
+    fn delete_canonical_hash(&mut self, index: u64) {
+        let key = index_to_bytes(index);
+        self.db_transaction.delete(storage::COL_BLOCK_INDEX, &key);
+        self.index_to_hash.push((key, None));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.db_transaction.ops.is_empty()
+    }
+
+    /// Takes the database writes out, leaving the cache entries.
+    fn take_db_transaction(&mut self) -> DBTransaction {
+        mem::replace(&mut self.db_transaction, DBTransaction::new())
+    }
+}
+
+/// Index of the ancestor that the skip pointer of the block with the given index leads to.
+/// Same as in Bitcoin: the distances depend on the lowest bits of the index, so that any
+/// ancestor is reached in O(log n) steps over skip pointers and parents.
+fn skip_index(index: u64) -> u64 {
+    fn clear_lowest_one(n: u64) -> u64 {
+        n & n.saturating_sub(1)
+    }
+    if index < 2 {
+        0
+    } else if index & 1 == 1 {
+        clear_lowest_one(clear_lowest_one(index - 1)) + 1
+    } else {
+        clear_lowest_one(index)
+    }
 }
 
 fn read_with_cache<T: Clone + Decode>(
     storage: &Arc<Storage>,
     col: Option<u32>,
-    cache: &RwLock<HashMap<Vec<u8>, T>>,
+    cache: &RwLock<LruCache<Vec<u8>, T>>,
     key: &[u8],
 ) -> Option<T> {
-    {
-        let read = cache.read();
-        if let Some(v) = read.get(key) {
-            return Some(v.clone());
-        }
+    if let Some(v) = cache.read().get(key) {
+        return Some(v.clone());
     }
 
     match storage.get(col, key) {
@@ -122,11 +225,23 @@ fn read_with_cache<T: Clone + Decode>(
 }
 
 impl<B: SignedBlock> BlockChain<B> {
+    /// Creates the chain that starts from `genesis`. It doesn't have to be the first block,
+    /// e.g. it's the block of the imported state snapshot.
     pub fn new(genesis: B, storage: Arc<Storage>) -> Self {
+        BlockChain::with_cache_size(genesis, storage, DEFAULT_CACHE_SIZE)
+    }
+
+    /// Creates the chain that keeps at most `cache_size` entries in each of its caches.
+    pub fn with_cache_size(genesis: B, storage: Arc<Storage>, cache_size: usize) -> Self {
         let genesis_hash = genesis.block_hash();
         let mut best_block_key = [0; 36];
         best_block_key[..32].copy_from_slice(genesis_hash.as_ref());
         best_block_key[32..].copy_from_slice(BLOCKCHAIN_BEST_BLOCK);
+        let mut finalized_block_key = [0; 41];
+        finalized_block_key[..32].copy_from_slice(genesis_hash.as_ref());
+        finalized_block_key[32..].copy_from_slice(BLOCKCHAIN_FINALIZED_BLOCK);
+        let genesis_header = genesis.header();
+        let genesis_number = genesis_header.index();
         let genesis_index = BlockIndex {
             block: genesis,
             cumulative_weight: 1,
@@ -134,11 +249,19 @@ impl<B: SignedBlock> BlockChain<B> {
         let bc = BlockChain {
             storage,
             genesis_hash,
+            genesis_index: genesis_number,
             best_block_key,
             best_block_index: RwLock::new(genesis_index.clone()),
-            headers: RwLock::new(HashMap::new()),
-            blocks: RwLock::new(HashMap::new()),
-            index_to_hash: RwLock::new(HashMap::new()),
+            finalized_block_key,
+            finalized_header: RwLock::new(genesis_header),
+            headers: RwLock::new(LruCache::new(cache_size)),
+            blocks: RwLock::new(LruCache::new(cache_size)),
+            index_to_hash: RwLock::new(LruCache::new(cache_size)),
+            reorg_subscribers: Mutex::new(vec![]),
+            orphans: Mutex::new(OrphanPool::new(DEFAULT_MAX_ORPHANS)),
+            missing_block_subscribers: Mutex::new(vec![]),
+            weigher: RwLock::new(None),
+            header_verifier: RwLock::new(None),
         };
 
         // Load best block hash from storage.
@@ -146,7 +269,15 @@ impl<B: SignedBlock> BlockChain<B> {
             Ok(Some(best_hash)) => CryptoHash::new(best_hash.as_ref()),
             _ => {
                 // Insert genesis block into cache.
-                bc.insert_block_index(&genesis_index);
+                let mut transaction = ChainTransaction::new(bc.storage.transaction());
+                bc.insert_block_index(&genesis_index, &mut transaction);
+                transaction.put_canonical_hash(genesis_number, &genesis_hash);
+                transaction.db_transaction.put(
+                    storage::COL_EXTRA,
+                    &bc.best_block_key,
+                    genesis_hash.as_ref(),
+                );
+                bc.write(transaction).expect("Database write failed");
                 genesis_hash
             }
         };
@@ -157,11 +288,31 @@ impl<B: SignedBlock> BlockChain<B> {
                 .get_block_index(&BlockId::Hash(best_block_hash))
                 .expect("Not found best block in the chain");
         }
+        bc.recover_canonical_chain();
+
+        // Load finalized block, genesis is final until anything else is finalized.
+        let finalized_hash = bc.storage.get(storage::COL_EXTRA, &bc.finalized_block_key);
+        if let Ok(Some(finalized_hash)) = finalized_hash {
+            let finalized_hash = CryptoHash::new(finalized_hash.as_ref());
+            *bc.finalized_header.write() = bc
+                .get_block_header_by_hash(&finalized_hash)
+                .expect("Not found finalized block in the chain");
+        }
 
         // Load best block into cache.
         bc
     }
 
+    /// Hit and miss counts of the block, header and index caches, keyed by column name.
+    pub fn cache_stats(&self) -> BTreeMap<String, CacheStats> {
+        let caches = [
+            (storage::COL_HEADERS, self.headers.read().stats()),
+            (storage::COL_BLOCKS, self.blocks.read().stats()),
+            (storage::COL_BLOCK_INDEX, self.index_to_hash.read().stats()),
+        ];
+        caches.iter().map(|(col, stats)| (storage::metrics::column_name(*col), *stats)).collect()
+    }
+
     pub fn best_block_index(&self) -> BlockIndex<B> {
         self.best_block_index.read().clone()
     }
@@ -180,7 +331,51 @@ impl<B: SignedBlock> BlockChain<B> {
         self.best_block().block_hash()
     }
 
-    /// Check if block already is known.
+    /// Returns the last finalized block.
+    pub fn finalized_block(&self) -> B {
+        let finalized_hash = self.finalized_header.read().block_hash();
+        self.get_block(&BlockId::Hash(finalized_hash)).expect("Finalized block must be known")
+    }
+
+    /// Marks the block on the best chain as final. The best chain will not be reorganized past
+    /// this block anymore. Finalizing an ancestor of the last finalized block does nothing.
+    pub fn finalize_block(&self, block_hash: &CryptoHash) -> Result<(), String> {
+        let header = self
+            .get_block_header_by_hash(block_hash)
+            .ok_or_else(|| format!("Block {:?} is not known", block_hash))?;
+        if self.get_block_hash_by_index(header.index()) != Some(*block_hash) {
+            return Err(format!("Block {:?} is not on the best chain", block_hash));
+        }
+        let mut finalized_header = self.finalized_header.write();
+        if header.index() <= finalized_header.index() {
+            return Ok(());
+        }
+        let mut db_transaction = self.storage.transaction();
+        db_transaction.put(storage::COL_EXTRA, &self.finalized_block_key, block_hash.as_ref());
+        self.storage.write(db_transaction).map_err(|e| format!("Database write failed: {}", e))?;
+        *finalized_header = header;
+        Ok(())
+    }
+
+    /// Sets the weigher used for the blocks inserted from now on. Cumulative weights of the
+    /// blocks that are already stored are kept.
+    pub fn set_weigher(&self, weigher: Arc<BlockWeigher<B>>) {
+        *self.weigher.write() = Some(weigher);
+    }
+
+    fn block_weight(&self, block: &B) -> u128 {
+        match self.weigher.read().as_ref() {
+            Some(weigher) => weigher.weight(block),
+            None => block.weight(),
+        }
+    }
+
+    pub fn set_header_verifier(&self, header_verifier: Arc<HeaderVerifier<B::SignedHeader>>) {
+        *self.header_verifier.write() = Some(header_verifier);
+    }
+
+    /// Check if block already is known. The body of the block may still be missing,
+    /// see `has_block`.
     pub fn is_known(&self, hash: &CryptoHash) -> bool {
         if self.headers.read().contains_key(hash.as_ref()) {
             return true;
@@ -191,69 +386,395 @@ impl<B: SignedBlock> BlockChain<B> {
         }
     }
 
-    fn update_best_block(&self, block_index: BlockIndex<B>) {
+    /// Check if the whole block is known, not only its header.
+    pub fn has_block(&self, hash: &CryptoHash) -> bool {
+        if self.blocks.read().contains_key(hash.as_ref()) {
+            return true;
+        }
+        match self.storage.get(storage::COL_BLOCKS, hash.as_ref()) {
+            Ok(Some(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Inserts a header whose block will be attached later with `insert_block`, so that
+    /// headers can be synced ahead of the bodies. The parent header must be known.
+    /// Returns false if the header is already known.
+    pub fn insert_header(&self, header: B::SignedHeader) -> Result<bool, String> {
+        let block_hash = header.block_hash();
+        if self.is_known(&block_hash) {
+            return Ok(false);
+        }
+        let parent = self
+            .get_block_header_by_hash(&header.parent_hash())
+            .ok_or_else(|| format!("Parent of header {:?} is not known", block_hash))?;
+        if header.index() != parent.index() + 1 {
+            return Err(format!(
+                "Header {:?} has index {}, but its parent has index {}",
+                block_hash,
+                header.index(),
+                parent.index()
+            ));
+        }
+        if let Some(header_verifier) = self.header_verifier.read().as_ref() {
+            header_verifier.verify(&header)?;
+        }
+        let mut transaction = ChainTransaction::new(self.storage.transaction());
+        self.put_header(&block_hash, header, &mut transaction);
+        self.write(transaction)?;
+        Ok(true)
+    }
+
+    /// Returns hashes of the blocks that have only their header stored, walking back from the
+    /// given header to the first block with a body. At most `max` hashes are returned, the
+    /// oldest first, so that bodies can be requested in the order they can be inserted.
+    pub fn get_missing_bodies(&self, block_hash: &CryptoHash, max: usize) -> Vec<CryptoHash> {
+        let mut missing: Vec<CryptoHash> = self
+            .iter_headers(block_hash)
+            .map(|header| header.block_hash())
+            .take_while(|hash| !self.has_block(hash))
+            .collect();
+        missing.reverse();
+        missing.truncate(max);
+        missing
+    }
+
+    /// Returns a stream of reorganizations of the best chain. Extending the best chain
+    /// with a child of the best block is not a reorganization.
+    pub fn subscribe_reorgs(&self) -> UnboundedReceiver<ChainReorg<B>> {
+        let (tx, rx) = unbounded();
+        self.reorg_subscribers.lock().push(tx);
+        rx
+    }
+
+    /// Databases written before block insertion became atomic may have the index of the best
+    /// chain out of sync with the best block. Rewrites the index from the best block down to
+    /// the first block that is indexed correctly and drops the entries above the best block.
+    fn recover_canonical_chain(&self) {
+        let best_header = self.best_block().header();
+        let mut transaction = ChainTransaction::new(self.storage.transaction());
+        let mut index = best_header.index() + 1;
+        while self.get_block_hash_by_index(index).is_some() {
+            transaction.delete_canonical_hash(index);
+            index += 1;
+        }
+        let mut header = best_header;
+        while self.get_block_hash_by_index(header.index()) != Some(header.block_hash()) {
+            transaction.put_canonical_hash(header.index(), &header.block_hash());
+            if header.block_hash() == self.genesis_hash {
+                break;
+            }
+            header = self
+                .get_block_header_by_hash(&header.parent_hash())
+                .expect("Ancestor of the best block is missing from the database");
+        }
+        if !transaction.is_empty() {
+            warn!(target: "chain", "Repaired index of the best chain");
+            self.write(transaction).expect("Database write failed");
+        }
+    }
+
+    fn update_best_block(
+        &self,
+        block_index: BlockIndex<B>,
+        transaction: &mut ChainTransaction<B>,
+    ) -> Option<ChainReorg<B>> {
         let block_hash = block_index.block.block_hash();
         let mut best_block_index = self.best_block_index.write();
+        let reorg = self.update_canonical_chain(
+            &best_block_index.block.header(),
+            &block_index.block,
+            transaction,
+        );
         *best_block_index = block_index;
-        let mut db_transaction = self.storage.transaction();
-        db_transaction.put(storage::COL_EXTRA, &self.best_block_key, block_hash.as_ref());
-        self.storage.write(db_transaction).expect("Database write failed");
+        transaction.db_transaction.put(
+            storage::COL_EXTRA,
+            &self.best_block_key,
+            block_hash.as_ref(),
+        );
+        reorg
+    }
+
+    /// Returns a stream of hashes of blocks that are missing to connect orphans.
+    pub fn subscribe_missing_blocks(&self) -> UnboundedReceiver<CryptoHash> {
+        let (tx, rx) = unbounded();
+        self.missing_block_subscribers.lock().push(tx);
+        rx
+    }
+
+    /// Sends the hash of the missing block to the subscribers, which fetch it from peers.
+    /// Used for the ancestors of the blocks that wait outside of the orphan pool.
+    pub fn request_missing_block(&self, hash: CryptoHash) {
+        self.missing_block_subscribers
+            .lock()
+            .retain(|subscriber| subscriber.unbounded_send(hash).is_ok());
+    }
+
+    /// Number of blocks waiting for their parent.
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.lock().len()
     }
 
     /// Inserts a verified block.
-    /// Returns true if block is disconnected.
+    /// Returns true if block is disconnected. Such block is kept in the orphan pool and
+    /// inserted once its parent arrives.
     pub fn insert_block(&self, block: B) -> bool {
+        self.insert_block_with_transaction(block, DBTransaction::new())
+    }
+
+    /// Inserts a verified block. All writes of the block, including the new best block,
+    /// are applied together with `db_transaction` (e.g. the state of the block) atomically.
+    pub fn insert_block_with_transaction(&self, block: B, db_transaction: DBTransaction) -> bool {
         let block_hash = block.block_hash();
-        if self.is_known(&block_hash) {
-            // TODO: known header but not known block.
+        if self.has_block(&block_hash) {
             return false;
         }
 
-        let mut cumulative_weight = 0;
+        // Block is connected once the body of the parent is known, its header is not enough.
+        // Writes of an orphan wait with it and are dropped if it's evicted.
+        let parent_hash = block.header().parent_hash();
+        if !self.has_block(&parent_hash) {
+            let missing_hash = {
+                let mut orphans = self.orphans.lock();
+                if !orphans.insert(block, db_transaction) {
+                    return true;
+                }
+                orphans.missing_ancestor(&parent_hash)
+            };
+            self.request_missing_block(missing_hash);
+            return true;
+        }
+
+        self.insert_connected_block(block, db_transaction);
+        self.insert_orphan_children(block_hash);
+        false
+    }
 
+    /// Inserts a verified block together with a verified block of `other` chain in one database
+    /// write that also applies `db_transaction`, e.g. a beacon block with its shard block and
+    /// their state. Both chains must share the database, parents of both blocks must be stored
+    /// and the blocks must not be.
+    pub fn insert_block_pair<C: SignedBlock>(
+        &self,
+        block: B,
+        other: &BlockChain<C>,
+        other_block: C,
+        db_transaction: DBTransaction,
+    ) -> Result<(), String> {
+        if !Arc::ptr_eq(&self.storage, &other.storage) {
+            return Err("Chains are stored in different databases".to_string());
+        }
+        let block_hash = block.block_hash();
+        let other_hash = other_block.block_hash();
+        if self.has_block(&block_hash) || other.has_block(&other_hash) {
+            return Err(format!("Block {:?} or {:?} is already stored", block_hash, other_hash));
+        }
+        if !self.has_block(&block.header().parent_hash())
+            || !other.has_block(&other_block.header().parent_hash())
+        {
+            return Err(format!("Parent of block {:?} or {:?} is missing", block_hash, other_hash));
+        }
+        let (mut other_transaction, other_reorg) =
+            other.prepare_connected_block(other_block, db_transaction);
+        let db_transaction = other_transaction.take_db_transaction();
+        let (mut transaction, reorg) = self.prepare_connected_block(block, db_transaction);
+        let db_transaction = transaction.take_db_transaction();
+        self.storage.write(db_transaction).map_err(|e| format!("Database write failed: {}", e))?;
+        other.update_caches(other_transaction);
+        other.notify_reorg(other_reorg);
+        self.update_caches(transaction);
+        self.notify_reorg(reorg);
+        other.insert_orphan_children(other_hash);
+        self.insert_orphan_children(block_hash);
+        Ok(())
+    }
+
+    /// Reconnects orphans that were waiting for the block and their descendants.
+    fn insert_orphan_children(&self, block_hash: CryptoHash) {
+        let mut parents = vec![block_hash];
+        while let Some(parent_hash) = parents.pop() {
+            let children = self.orphans.lock().remove_children(&parent_hash);
+            for (child, db_transaction) in children {
+                parents.push(child.block_hash());
+                self.insert_connected_block(child, db_transaction);
+            }
+        }
+    }
+
+    fn insert_connected_block(&self, block: B, db_transaction: DBTransaction) {
+        let (transaction, reorg) = self.prepare_connected_block(block, db_transaction);
+        self.write(transaction).expect("Database write failed");
+        self.notify_reorg(reorg);
+    }
+
+    /// Collects the writes of a block whose parent is stored, including the switch of the best
+    /// chain to it if it's heavier.
+    fn prepare_connected_block(
+        &self,
+        block: B,
+        db_transaction: DBTransaction,
+    ) -> (ChainTransaction<B>, Option<ChainReorg<B>>) {
+        let mut cumulative_weight = 0;
         let maybe_parent = self.get_block_index(&BlockId::Hash(block.header().parent_hash()));
-        let mut result = true;
         if let Some(parent_details) = maybe_parent {
             if parent_details.cumulative_weight > 0 {
-                cumulative_weight = block.weight() + parent_details.cumulative_weight;
+                cumulative_weight = self.block_weight(&block) + parent_details.cumulative_weight;
             }
-            result = false
         }
         let block_index = BlockIndex { block, cumulative_weight };
-        self.insert_block_index(&block_index);
+        let mut transaction = ChainTransaction::new(db_transaction);
+        self.insert_block_index(&block_index, &mut transaction);
+        let mut reorg = None;
         if block_index.cumulative_weight > self.best_block_index.read().cumulative_weight {
-            self.update_best_block(block_index);
+            let finalized_hash = self.finalized_header.read().block_hash();
+            let block_hash = block_index.block.block_hash();
+            // The block itself is not stored yet, but its parent is.
+            let parent_hash = block_index.block.header().parent_hash();
+            if self.is_ancestor(&finalized_hash, &parent_hash) {
+                reorg = self.update_best_block(block_index, &mut transaction);
+            } else {
+                warn!(
+                    target: "chain",
+                    "Not switching to block {:?} that conflicts with finalized block {:?}",
+                    block_hash,
+                    finalized_hash
+                );
+            }
         }
+        (transaction, reorg)
+    }
 
-        result
+    fn notify_reorg(&self, reorg: Option<ChainReorg<B>>) {
+        if let Some(reorg) = reorg {
+            // Drop subscribers whose receivers are gone.
+            self.reorg_subscribers
+                .lock()
+                .retain(|subscriber| subscriber.unbounded_send(reorg.clone()).is_ok());
+        }
     }
 
-    fn insert_block_index(&self, block_index: &BlockIndex<B>) {
-        let block = &block_index.block;
-        let block_hash = block.block_hash();
+    fn insert_block_index(
+        &self,
+        block_index: &BlockIndex<B>,
+        transaction: &mut ChainTransaction<B>,
+    ) {
+        let block_hash = block_index.block.block_hash();
 
         // Store block in db.
-        write_with_cache(
-            &self.storage,
-            storage::COL_BLOCKS,
-            &self.blocks,
-            block_hash.as_ref(),
-            &block_index,
-        );
-        write_with_cache(
-            &self.storage,
-            storage::COL_HEADERS,
-            &self.headers,
-            block_hash.as_ref(),
-            &block.header(),
-        );
-        write_with_cache(
-            &self.storage,
-            storage::COL_BLOCK_INDEX,
-            &self.index_to_hash,
-            &index_to_bytes(block.header().index()),
-            &block_hash,
-        );
+        transaction.put_block(&block_hash, block_index.clone());
+        self.put_header(&block_hash, block_index.block.header(), transaction);
+    }
+
+    /// Index of the ancestor that the skip pointer of the block with the given index leads to.
+    fn skip_index(&self, index: u64) -> u64 {
+        skip_index(index).max(self.genesis_index)
+    }
+
+    /// Adds the header together with its skip pointer. Blocks whose skip pointer would lead
+    /// to their parent, and blocks whose ancestor is not stored, don't get one.
+    fn put_header(
+        &self,
+        block_hash: &CryptoHash,
+        header: B::SignedHeader,
+        transaction: &mut ChainTransaction<B>,
+    ) {
+        let index = header.index();
+        if index > self.genesis_index + 1 {
+            let skip_index = self.skip_index(index);
+            if skip_index + 1 < index {
+                if let Some(skip) = self.get_ancestor_header(&header.parent_hash(), skip_index) {
+                    transaction.put_skip_pointer(block_hash, &skip.block_hash());
+                }
+            }
+        }
+        transaction.put_header(block_hash, header);
+    }
+
+    fn get_skip_pointer(&self, block_hash: &CryptoHash) -> Option<CryptoHash> {
+        match self.storage.get(storage::COL_SKIP_POINTERS, block_hash.as_ref()) {
+            Ok(Some(value)) => Some(CryptoHash::new(value.as_ref())),
+            _ => None,
+        }
+    }
+
+    /// Writes the transaction and then updates the caches with its entries.
+    fn write(&self, mut transaction: ChainTransaction<B>) -> Result<(), String> {
+        let db_transaction = transaction.take_db_transaction();
+        self.storage.write(db_transaction).map_err(|e| format!("Database write failed: {}", e))?;
+        self.update_caches(transaction);
+        Ok(())
+    }
+
+    /// Updates the caches with the entries of a written transaction.
+    fn update_caches(&self, transaction: ChainTransaction<B>) {
+        let ChainTransaction { headers, blocks, index_to_hash, .. } = transaction;
+        {
+            let mut cache = self.headers.write();
+            for (key, header) in headers {
+                cache.insert(key, header);
+            }
+        }
+        {
+            let mut cache = self.blocks.write();
+            for (key, block_index) in blocks {
+                cache.insert(key, block_index);
+            }
+        }
+        let mut cache = self.index_to_hash.write();
+        for (key, block_hash) in index_to_hash {
+            match block_hash {
+                Some(block_hash) => cache.insert(key, block_hash),
+                None => {
+                    cache.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Rewrites the index -> hash mapping when the best block changes from `old_best` to
+    /// `new_best`: walks back from the new best block until the branch joins the current
+    /// canonical chain, and drops indices above the new best block.
+    /// Returns the reorganization if some blocks left the best chain.
+    fn update_canonical_chain(
+        &self,
+        old_best: &B::SignedHeader,
+        new_best: &B,
+        transaction: &mut ChainTransaction<B>,
+    ) -> Option<ChainReorg<B>> {
+        let mut enacted = vec![];
+        let mut block = new_best.clone();
+        let ancestor_index = loop {
+            let header = block.header();
+            if self.get_block_hash_by_index(header.index()) == Some(header.block_hash()) {
+                break header.index();
+            }
+            enacted.push(block);
+            block = self
+                .get_block_index_by_hash(&header.parent_hash())
+                .expect("Parent of a connected block must be known")
+                .block;
+        };
+        enacted.reverse();
+        let retracted: Vec<B> = (ancestor_index + 1..=old_best.index())
+            .rev()
+            .map(|index| {
+                self.get_block(&BlockId::Number(index))
+                    .expect("Block on the best chain must be known")
+            })
+            .collect();
+
+        for block in enacted.iter() {
+            transaction.put_canonical_hash(block.header().index(), &block.block_hash());
+        }
+        for index in new_best.header().index() + 1..=old_best.index() {
+            transaction.delete_canonical_hash(index);
+        }
+
+        if retracted.is_empty() {
+            None
+        } else {
+            Some(ChainReorg { retracted, enacted })
+        }
     }
 
     fn get_block_hash_by_index(&self, index: u64) -> Option<CryptoHash> {
@@ -266,7 +787,12 @@ impl<B: SignedBlock> BlockChain<B> {
     }
 
     fn get_block_index_by_hash(&self, block_hash: &CryptoHash) -> Option<BlockIndex<B>> {
-        read_with_cache(&self.storage, storage::COL_BLOCKS, &self.blocks, block_hash.as_ref())
+        read_with_cache(
+            &self.storage,
+            storage::COL_BLOCKS,
+            &self.blocks,
+            block_hash.as_ref(),
+        )
     }
 
     pub fn get_block_index(&self, id: &BlockId) -> Option<BlockIndex<B>> {
@@ -282,7 +808,12 @@ impl<B: SignedBlock> BlockChain<B> {
     }
 
     fn get_block_header_by_hash(&self, block_hash: &CryptoHash) -> Option<B::SignedHeader> {
-        read_with_cache(&self.storage, storage::COL_HEADERS, &self.headers, block_hash.as_ref())
+        read_with_cache(
+            &self.storage,
+            storage::COL_HEADERS,
+            &self.headers,
+            block_hash.as_ref(),
+        )
     }
 
     pub fn get_header(&self, id: &BlockId) -> Option<B::SignedHeader> {
@@ -293,4 +824,112 @@ impl<B: SignedBlock> BlockChain<B> {
             BlockId::Hash(hash) => self.get_block_header_by_hash(hash),
         }
     }
+
+    fn is_canonical(&self, header: &B::SignedHeader) -> bool {
+        self.get_block_hash_by_index(header.index()) == Some(header.block_hash())
+    }
+
+    /// Returns the ancestor of the block with given index, or the block itself if the index
+    /// is its own. Walks back only until the branch joins the best chain and then looks the
+    /// ancestor up in the index of the best chain. Side branches and headers without bodies
+    /// are walked through the skip pointers, which takes O(log n) steps.
+    pub fn get_ancestor_header(
+        &self,
+        block_hash: &CryptoHash,
+        index: u64,
+    ) -> Option<B::SignedHeader> {
+        let mut header = self.get_block_header_by_hash(block_hash)?;
+        if index > header.index() {
+            return None;
+        }
+        while header.index() > index && !self.is_canonical(&header) {
+            let skip = match self.get_skip_pointer(&header.block_hash()) {
+                Some(skip_hash) if self.should_skip(header.index(), index) => self
+                    .get_block_header_by_hash(&skip_hash)
+                    .filter(|skip| skip.index() >= index),
+                _ => None,
+            };
+            header = match skip {
+                Some(skip) => skip,
+                None => self.get_block_header_by_hash(&header.parent_hash())?,
+            };
+        }
+        if header.index() == index {
+            Some(header)
+        } else {
+            self.get_header(&BlockId::Number(index))
+        }
+    }
+
+    /// Whether the walk from the block with the given index to the ancestor with index `target`
+    /// should follow the skip pointer rather than the parent. As in Bitcoin, the pointer is
+    /// not followed if it overshoots, or if the skip pointer of the parent is a better step.
+    fn should_skip(&self, index: u64, target: u64) -> bool {
+        let skip_index = self.skip_index(index);
+        let parent_skip_index = self.skip_index(index - 1);
+        skip_index == target
+            || (skip_index > target
+                && !(parent_skip_index + 2 < skip_index && parent_skip_index >= target))
+    }
+
+    /// Checks whether `ancestor` is on the path from `descendant` to genesis. A block is
+    /// considered to be its own ancestor.
+    pub fn is_ancestor(&self, ancestor: &CryptoHash, descendant: &CryptoHash) -> bool {
+        let ancestor_index = match self.get_block_header_by_hash(ancestor) {
+            Some(header) => header.index(),
+            None => return false,
+        };
+        self.get_ancestor_header(descendant, ancestor_index)
+            .map_or(false, |header| header.block_hash() == *ancestor)
+    }
+
+    /// Returns the latest block that is an ancestor of both blocks.
+    pub fn get_common_ancestor(
+        &self,
+        first: &CryptoHash,
+        second: &CryptoHash,
+    ) -> Option<B::SignedHeader> {
+        let first_index = self.get_block_header_by_hash(first)?.index();
+        let second_index = self.get_block_header_by_hash(second)?.index();
+        let index = first_index.min(second_index);
+        let mut first = self.get_ancestor_header(first, index)?;
+        let mut second = self.get_ancestor_header(second, index)?;
+        // Blocks with the same index on the best chain are the same block, so this loop only
+        // walks the side branches.
+        while first.block_hash() != second.block_hash() {
+            if self.is_canonical(&first) && self.is_canonical(&second) {
+                return None;
+            }
+            first = self.get_block_header_by_hash(&first.parent_hash())?;
+            second = self.get_block_header_by_hash(&second.parent_hash())?;
+        }
+        Some(first)
+    }
+
+    /// Iterates over the headers from the given block back to genesis.
+    pub fn iter_headers(&self, block_hash: &CryptoHash) -> HeaderIterator<B> {
+        HeaderIterator { chain: self, next: self.get_block_header_by_hash(block_hash) }
+    }
+}
+
+/// Iterator over the headers of a branch, from the newest one to genesis.
+pub struct HeaderIterator<'a, B: SignedBlock + 'a> {
+    chain: &'a BlockChain<B>,
+    next: Option<B::SignedHeader>,
+}
+
+impl<'a, B: SignedBlock> Iterator for HeaderIterator<'a, B> {
+    type Item = B::SignedHeader;
+
+    fn next(&mut self) -> Option<B::SignedHeader> {
+        let header = self.next.take()?;
+        if header.index() > 0 {
+            self.next = if self.chain.is_canonical(&header) {
+                self.chain.get_header(&BlockId::Number(header.index() - 1))
+            } else {
+                self.chain.get_block_header_by_hash(&header.parent_hash())
+            };
+        }
+        Some(header)
+    }
 }
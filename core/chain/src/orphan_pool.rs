@@ -0,0 +1,96 @@
+//! Blocks that arrived before their parent.
+use std::collections::{HashMap, VecDeque};
+
+use primitives::hash::CryptoHash;
+use storage::DBTransaction;
+
+use {SignedBlock, SignedHeader};
+
+/// Default number of orphans kept in the pool.
+pub const DEFAULT_MAX_ORPHANS: usize = 1024;
+
+/// Orphans are kept with data they need once they connect, by default the writes to apply.
+pub struct OrphanPool<B: SignedBlock, T = DBTransaction> {
+    /// Orphans with their data, indexed by the hash of their missing parent.
+    by_parent: HashMap<CryptoHash, Vec<(B, T)>>,
+    /// Maps hash of the orphan to hash of its parent.
+    parents: HashMap<CryptoHash, CryptoHash>,
+    /// Insertion order, the oldest orphans are evicted first.
+    order: VecDeque<CryptoHash>,
+    max_size: usize,
+}
+
+impl<B: SignedBlock, T> OrphanPool<B, T> {
+    pub fn new(max_size: usize) -> Self {
+        OrphanPool {
+            by_parent: HashMap::new(),
+            parents: HashMap::new(),
+            order: VecDeque::new(),
+            max_size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.parents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parents.is_empty()
+    }
+
+    pub fn contains(&self, hash: &CryptoHash) -> bool {
+        self.parents.contains_key(hash)
+    }
+
+    /// Adds the block with its data to the pool. Returns false if it's already there.
+    pub fn insert(&mut self, block: B, data: T) -> bool {
+        let hash = block.block_hash();
+        if self.contains(&hash) {
+            return false;
+        }
+        let parent_hash = block.header().parent_hash();
+        self.parents.insert(hash, parent_hash);
+        self.by_parent.entry(parent_hash).or_insert_with(Vec::new).push((block, data));
+        self.order.push_back(hash);
+        while self.len() > self.max_size {
+            let oldest = self.order.pop_front().expect("Pool is not empty");
+            self.remove(&oldest);
+        }
+        true
+    }
+
+    fn remove(&mut self, hash: &CryptoHash) {
+        if let Some(parent_hash) = self.parents.remove(hash) {
+            let is_empty = match self.by_parent.get_mut(&parent_hash) {
+                Some(children) => {
+                    children.retain(|(block, _)| block.block_hash() != *hash);
+                    children.is_empty()
+                }
+                None => false,
+            };
+            if is_empty {
+                self.by_parent.remove(&parent_hash);
+            }
+        }
+    }
+
+    /// Removes and returns orphans whose parent is the given block, with their data.
+    pub fn remove_children(&mut self, parent_hash: &CryptoHash) -> Vec<(B, T)> {
+        let children = self.by_parent.remove(parent_hash).unwrap_or_default();
+        for (child, _) in children.iter() {
+            self.parents.remove(&child.block_hash());
+        }
+        let parents = &self.parents;
+        self.order.retain(|hash| parents.contains_key(hash));
+        children
+    }
+
+    /// Returns the earliest missing ancestor of the orphans waiting for the given block.
+    pub fn missing_ancestor(&self, parent_hash: &CryptoHash) -> CryptoHash {
+        let mut hash = *parent_hash;
+        while let Some(parent_hash) = self.parents.get(&hash) {
+            hash = *parent_hash;
+        }
+        hash
+    }
+}
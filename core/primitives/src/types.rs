@@ -3,6 +3,8 @@ use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
 use std::fmt;
 
+use serde::Serialize;
+
 use ::traits::Payload;
 use hash::{CryptoHash, hash_struct};
 use signature::{PublicKey, Signature};
@@ -66,6 +68,14 @@ pub struct StakeTransaction {
     pub amount: Balance,
 }
 
+/// Withdraws money from the stake. It stays locked for the lockup period before it can be spent.
+#[derive(Hash, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct UnstakeTransaction {
+    pub nonce: u64,
+    pub originator: AccountId,
+    pub amount: Balance,
+}
+
 #[derive(Hash, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct SendMoneyTransaction {
     pub nonce: u64,
@@ -124,6 +134,41 @@ pub struct SwapKeyTransaction {
     pub new_key: Vec<u8>,
 }
 
+/// Reveals the next value of the hash chain of an authority for the randomness beacon.
+/// The first transaction of an account commits to the tip of the chain instead.
+#[derive(Hash, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct RevealRandomnessTransaction {
+    pub nonce: u64,
+    pub originator: AccountId,
+    pub value: CryptoHash,
+}
+
+/// Data signed by an authority, kept encoded so that the signature can be checked.
+#[derive(Hash, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct SignedEvidence {
+    pub data: Vec<u8>,
+    pub signature: Signature,
+}
+
+/// Proof that an authority violated the protocol, checked by `beacon::slashing`.
+#[derive(Hash, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum Misbehavior {
+    /// Two different beacon block headers with the same parent.
+    BeaconEquivocation(SignedEvidence, SignedEvidence),
+    /// Two different TxFlow message bodies of the same owner with the same parents or the
+    /// same number, so that neither of them approves the other.
+    TxFlowFork(SignedEvidence, SignedEvidence),
+}
+
+/// Reports misbehavior of an authority. Its stake is burned and it loses its seats.
+#[derive(Hash, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct SlashTransaction {
+    pub nonce: u64,
+    pub originator: AccountId,
+    pub offender: AccountId,
+    pub evidence: Misbehavior,
+}
+
 /// TODO: Call non-view function in the contracts.
 #[derive(Hash, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub enum TransactionBody {
@@ -133,6 +178,9 @@ pub enum TransactionBody {
     FunctionCall(FunctionCallTransaction),
     CreateAccount(CreateAccountTransaction),
     SwapKey(SwapKeyTransaction),
+    RevealRandomness(RevealRandomnessTransaction),
+    Unstake(UnstakeTransaction),
+    Slash(SlashTransaction),
 }
 
 impl TransactionBody {
@@ -144,6 +192,9 @@ impl TransactionBody {
             TransactionBody::FunctionCall(t) => t.nonce,
             TransactionBody::CreateAccount(t) => t.nonce,
             TransactionBody::SwapKey(t) => t.nonce,
+            TransactionBody::RevealRandomness(t) => t.nonce,
+            TransactionBody::Unstake(t) => t.nonce,
+            TransactionBody::Slash(t) => t.nonce,
         }
     }
 
@@ -155,6 +206,9 @@ impl TransactionBody {
             TransactionBody::FunctionCall(t) => t.originator.clone(),
             TransactionBody::CreateAccount(t) => t.originator.clone(),
             TransactionBody::SwapKey(t) => t.originator.clone(),
+            TransactionBody::RevealRandomness(t) => t.originator.clone(),
+            TransactionBody::Unstake(t) => t.originator.clone(),
+            TransactionBody::Slash(t) => t.originator.clone(),
         }
     }
 }
@@ -376,6 +430,9 @@ pub struct MessageDataBody<P> {
     pub owner_uid: UID,
     pub parents: HashSet<TxFlowHash>,
     pub epoch: u64,
+    /// Number of the message among the messages of its owner. Every message approves the
+    /// previous one, so two messages with the same number are a fork.
+    pub seq: u64,
     pub payload: P,
     /// Optional endorsement of this or other representative block.
     pub endorsements: Vec<Endorsement>,
@@ -390,6 +447,7 @@ impl<P: Hash> Hash for MessageDataBody<P> {
             h.hash(state);
         }
         self.epoch.hash(state);
+        self.seq.hash(state);
         //self.payload.hash(state);
         // TODO: Hash endorsements.
     }
@@ -405,12 +463,30 @@ impl<P: Hash> PartialEq for MessageDataBody<P> {
 
         self.owner_uid == other.owner_uid
             && self.epoch == other.epoch
+            && self.seq == other.seq
             && parents == other_parents
     }
 }
 
 impl<P: Hash> Eq for MessageDataBody<P> {}
 
+impl<P: Serialize> MessageDataBody<P> {
+    /// Hash signed by the owner of the message. Unlike the encoded body it doesn't depend on
+    /// the order in which the parents are stored.
+    pub fn signed_hash(&self) -> CryptoHash {
+        let mut parents: Vec<_> = self.parents.iter().collect();
+        parents.sort();
+        hash_struct(&(
+            self.owner_uid,
+            parents,
+            self.epoch,
+            self.seq,
+            &self.payload,
+            &self.endorsements,
+        ))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SignedMessageData<P> {
     /// Signature of the hash.
@@ -9,9 +9,10 @@ extern crate storage;
 use chain::{SignedBlock, SignedHeader};
 use primitives::hash::{CryptoHash, hash_struct};
 use primitives::types::{
-    AuthorityMask, MerkleHash, MultiSignature, PartialSignature,
+    AuthorityMask, BlockId, MerkleHash, MultiSignature, PartialSignature,
     Transaction, ShardId
 };
+use storage::{PruningMode, StateDb};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct ShardBlockHeader {
@@ -121,3 +122,37 @@ impl SignedBlock for SignedShardBlock {
 }
 
 pub type ShardBlockChain = chain::BlockChain<SignedShardBlock>;
+
+/// Prunes the states of the shard blocks which are more than the kept number of blocks behind
+/// the best block. Blocks are pruned along the canonical chain, so states of the forks from
+/// the pruned blocks are removed too.
+pub fn prune_state(shard_chain: &ShardBlockChain, state_db: &StateDb) -> Result<(), String> {
+    let keep_recent = match state_db.pruning_mode() {
+        PruningMode::Archive => return Ok(()),
+        PruningMode::KeepRecent(keep_recent) => keep_recent.max(1),
+    };
+    let get_header = |index| {
+        shard_chain
+            .get_header(&BlockId::Number(index))
+            .ok_or_else(|| format!("Shard block {} is not on the best chain", index))
+    };
+    // The state of the first block of the chain is not journaled.
+    let first_index = shard_chain
+        .get_header(&BlockId::Hash(shard_chain.genesis_hash))
+        .ok_or("Genesis of the shard chain is not known")?
+        .index()
+        + 1;
+    let first_index = first_index.max(state_db.canonical_index());
+    let last_index = match (shard_chain.best_index() + 1).checked_sub(keep_recent) {
+        Some(last_index) if last_index >= first_index => last_index,
+        _ => return Ok(()),
+    };
+    let mut parent_root = get_header(first_index - 1)?.body.merkle_root_state;
+    let mut transitions = vec![];
+    for index in first_index..=last_index {
+        let root = get_header(index)?.body.merkle_root_state;
+        transitions.push((parent_root, root));
+        parent_root = root;
+    }
+    state_db.prune(first_index, &transitions)
+}
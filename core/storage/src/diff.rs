@@ -0,0 +1,175 @@
+//! Difference between the states under two roots.
+use std::sync::Arc;
+
+use primitives::types::MerkleHash;
+
+use trie_walk::{load_node, nibbles_to_key, NodeRef, TrieNode};
+use {DBValue, StateDb};
+
+/// Keys that differ between an old and a new state. Values are taken from the new state.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    pub inserted: Vec<(Vec<u8>, Vec<u8>)>,
+    pub modified: Vec<(Vec<u8>, Vec<u8>)>,
+    pub deleted: Vec<Vec<u8>>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.inserted.is_empty() && self.modified.is_empty() && self.deleted.is_empty()
+    }
+}
+
+/// What is left of a leaf or an extension after a part of its partial key is walked.
+#[derive(Clone, PartialEq)]
+enum Tail {
+    Value(DBValue),
+    Node(NodeRef),
+}
+
+/// Subtree at some key in the walk: a stored node, or the rest of a leaf or an extension whose
+/// partial key starts with the given nibbles.
+#[derive(Clone, PartialEq)]
+enum Subtree {
+    Node(NodeRef),
+    Partial(Vec<u8>, Tail),
+}
+
+type Children = Vec<Option<Subtree>>;
+
+/// Splits the subtree into the value at its key and its subtrees by the next nibble.
+fn expand(state_db: &StateDb, subtree: Subtree) -> Result<(Option<DBValue>, Children), String> {
+    let mut children: Children = vec![None; 16];
+    match subtree {
+        Subtree::Node(node_ref) => match load_node(state_db, &node_ref)? {
+            TrieNode::Empty => Ok((None, children)),
+            TrieNode::Leaf(partial, value) => {
+                expand(state_db, Subtree::Partial(partial, Tail::Value(value)))
+            }
+            TrieNode::Extension(partial, child) => {
+                expand(state_db, Subtree::Partial(partial, Tail::Node(child)))
+            }
+            TrieNode::Branch(branch_children, value) => {
+                let children = branch_children.into_iter().map(|child| child.map(Subtree::Node));
+                Ok((value, children.collect()))
+            }
+        },
+        Subtree::Partial(ref partial, ref tail) if partial.is_empty() => match tail.clone() {
+            Tail::Value(value) => Ok((Some(value), children)),
+            Tail::Node(node_ref) => expand(state_db, Subtree::Node(node_ref)),
+        },
+        Subtree::Partial(partial, tail) => {
+            children[partial[0] as usize] = Some(Subtree::Partial(partial[1..].to_vec(), tail));
+            Ok((None, children))
+        }
+    }
+}
+
+/// Compares the subtrees at the key given in nibbles. Equal subtrees are skipped without
+/// loading them, so only the nodes on the paths to the changed keys are read.
+fn diff_subtrees(
+    state_db: &StateDb,
+    prefix: &mut Vec<u8>,
+    old: Option<Subtree>,
+    new: Option<Subtree>,
+    diff: &mut StateDiff,
+) -> Result<(), String> {
+    if old == new {
+        return Ok(());
+    }
+    let (old_value, old_children) = match old {
+        Some(old) => expand(state_db, old)?,
+        None => (None, vec![None; 16]),
+    };
+    let (new_value, new_children) = match new {
+        Some(new) => expand(state_db, new)?,
+        None => (None, vec![None; 16]),
+    };
+    match (old_value, new_value) {
+        (None, Some(value)) => diff.inserted.push((nibbles_to_key(prefix), value.to_vec())),
+        (Some(_), None) => diff.deleted.push(nibbles_to_key(prefix)),
+        (Some(old_value), Some(value)) => {
+            if old_value != value {
+                diff.modified.push((nibbles_to_key(prefix), value.to_vec()));
+            }
+        }
+        (None, None) => {}
+    }
+    for (nibble, (old, new)) in old_children.into_iter().zip(new_children.into_iter()).enumerate() {
+        prefix.push(nibble as u8);
+        diff_subtrees(state_db, prefix, old, new, diff)?;
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Walks the tries under `old_root` and `new_root` together in key order and collects the
+/// changes that turn the old state into the new one. All keys are sorted. Fails if a node of
+/// either trie is missing, e.g. because its state was pruned.
+pub fn diff_state(
+    state_db: Arc<StateDb>,
+    old_root: MerkleHash,
+    new_root: MerkleHash,
+) -> Result<StateDiff, String> {
+    let mut diff = StateDiff::default();
+    diff_subtrees(
+        &state_db,
+        &mut vec![],
+        Some(Subtree::Node(NodeRef::Hash(old_root))),
+        Some(Subtree::Node(NodeRef::Hash(new_root))),
+        &mut diff,
+    )?;
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use primitives::hash::hash;
+
+    use super::*;
+    use test_utils::create_state_db;
+    use StateDbUpdate;
+
+    fn update_state(
+        state_db: &Arc<StateDb>,
+        root: MerkleHash,
+        changes: &[(&str, Option<&str>)],
+    ) -> MerkleHash {
+        let mut state_db_update = StateDbUpdate::new(state_db.clone(), root);
+        for (key, value) in changes.iter() {
+            match value {
+                Some(value) => {
+                    state_db_update.set(key.as_bytes(), &DBValue::from_slice(value.as_bytes()))
+                }
+                None => state_db_update.delete(key.as_bytes()),
+            }
+        }
+        let (mut transaction, new_root) = state_db_update.finalize();
+        state_db.commit(&mut transaction).unwrap();
+        new_root
+    }
+
+    #[test]
+    fn test_diff_state() {
+        let state_db = Arc::new(create_state_db());
+        let old_root = update_state(
+            &state_db,
+            MerkleHash::default(),
+            &[("cat", Some("kitten")), ("dog", Some("puppy")), ("cow", Some("calf"))],
+        );
+        let new_root = update_state(
+            &state_db,
+            old_root,
+            &[("cat", None), ("dog", Some("hound")), ("dog2", Some("puppy2"))],
+        );
+        let diff = diff_state(state_db.clone(), old_root, new_root).unwrap();
+        assert_eq!(diff.inserted, vec![(b"dog2".to_vec(), b"puppy2".to_vec())]);
+        assert_eq!(diff.modified, vec![(b"dog".to_vec(), b"hound".to_vec())]);
+        assert_eq!(diff.deleted, vec![b"cat".to_vec()]);
+        assert!(diff_state(state_db.clone(), new_root, new_root).unwrap().is_empty());
+        let reverse = diff_state(state_db.clone(), new_root, old_root).unwrap();
+        assert_eq!(reverse.inserted, vec![(b"cat".to_vec(), b"kitten".to_vec())]);
+        assert_eq!(reverse.deleted, vec![b"dog2".to_vec()]);
+        assert!(diff_state(state_db, old_root, hash(&[1])).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+//! Simple append-only file storage. Every write is appended to a single log file, and the
+//! whole database is kept in memory and rebuilt by replaying the log on open.
+//! Meant for tests and embedded nodes that don't want to depend on RocksDB.
+//!
+//! Each transaction is written as one frame: the length of its operations, their hash and the
+//! operations themselves. A frame that is cut short or doesn't match its hash (e.g. after a
+//! crash) ends the log, and is cut off on open, so a transaction is replayed whole or not at all.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use kvdb::{DBOp, DBTransaction, DBValue, KeyValueDB};
+use parking_lot::{Mutex, RwLock};
+
+use primitives::hash::{hash, CryptoHash};
+
+const LOG_FILE_NAME: &str = "data.log";
+const OP_INSERT: u8 = 0;
+const OP_DELETE: u8 = 1;
+/// Encoding of the default column `None`.
+const NO_COLUMN: u32 = u32::max_value();
+
+type Column = BTreeMap<Vec<u8>, DBValue>;
+
+pub struct AppendOnlyFileDB {
+    path: PathBuf,
+    columns: RwLock<HashMap<Option<u32>, Column>>,
+    log: Mutex<BufWriter<File>>,
+    /// Transactions applied to `columns` but not written to the log yet.
+    pending: Mutex<Vec<DBTransaction>>,
+}
+
+fn encode_column(col: Option<u32>) -> u32 {
+    col.unwrap_or(NO_COLUMN)
+}
+
+fn decode_column(col: u32) -> Option<u32> {
+    if col == NO_COLUMN {
+        None
+    } else {
+        Some(col)
+    }
+}
+
+fn write_bytes<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    writer.write_u32::<LittleEndian>(data.len() as u32)?;
+    writer.write_all(data)
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32::<LittleEndian>()?;
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn write_insert<W: Write>(
+    writer: &mut W,
+    col: Option<u32>,
+    key: &[u8],
+    value: &[u8],
+) -> io::Result<()> {
+    writer.write_u8(OP_INSERT)?;
+    writer.write_u32::<LittleEndian>(encode_column(col))?;
+    write_bytes(writer, key)?;
+    write_bytes(writer, value)
+}
+
+fn write_op<W: Write>(writer: &mut W, op: &DBOp) -> io::Result<()> {
+    match op {
+        DBOp::Insert { col, key, value } => write_insert(writer, *col, key, value),
+        DBOp::Delete { col, key } => {
+            writer.write_u8(OP_DELETE)?;
+            writer.write_u32::<LittleEndian>(encode_column(*col))?;
+            write_bytes(writer, key)
+        }
+    }
+}
+
+fn read_record<R: Read>(reader: &mut R, op: u8) -> io::Result<(Option<u32>, Vec<u8>, Vec<u8>)> {
+    let col = decode_column(reader.read_u32::<LittleEndian>()?);
+    let key = read_bytes(reader)?;
+    let value = if op == OP_INSERT { read_bytes(reader)? } else { vec![] };
+    Ok((col, key, value))
+}
+
+/// Writes the operations as one frame.
+fn write_frame<W: Write>(writer: &mut W, ops: &[u8]) -> io::Result<()> {
+    write_bytes(writer, ops)?;
+    writer.write_all(hash(ops).as_ref())
+}
+
+/// Reads next frame from the log, returns `None` at the end of the log. A frame that is cut
+/// short or doesn't match its hash also ends the log.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let len = match reader.read_u32::<LittleEndian>() {
+        Ok(len) => len,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // The length of a torn frame can be garbage, so it's not preallocated.
+    let mut ops = vec![];
+    Read::take(&mut *reader, u64::from(len)).read_to_end(&mut ops)?;
+    let mut checksum = CryptoHash::default();
+    match reader.read_exact(checksum.as_mut()) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    Ok(if ops.len() == len as usize && hash(&ops) == checksum { Some(ops) } else { None })
+}
+
+fn encode_ops(ops: &[DBOp]) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    for op in ops {
+        write_op(&mut data, op)?;
+    }
+    Ok(data)
+}
+
+/// Applies the operations of a frame.
+fn apply_frame(columns: &mut HashMap<Option<u32>, Column>, ops: &[u8]) -> io::Result<()> {
+    let mut reader = Cursor::new(ops);
+    while (reader.position() as usize) < ops.len() {
+        let op = reader.read_u8()?;
+        let (col, key, value) = read_record(&mut reader, op)?;
+        let column = columns.entry(col).or_insert_with(Column::new);
+        match op {
+            OP_INSERT => {
+                column.insert(key, DBValue::from_vec(value));
+            }
+            OP_DELETE => {
+                column.remove(&key);
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown operation {}", op),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_op(columns: &mut HashMap<Option<u32>, Column>, op: &DBOp) {
+    match op {
+        DBOp::Insert { col, key, value } => {
+            columns.entry(*col).or_insert_with(Column::new).insert(key.to_vec(), value.clone());
+        }
+        DBOp::Delete { col, key } => {
+            if let Some(column) = columns.get_mut(col) {
+                column.remove(&key[..]);
+            }
+        }
+    }
+}
+
+impl AppendOnlyFileDB {
+    /// Opens the database in the given directory, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        fs::create_dir_all(path)?;
+        let path = path.join(LOG_FILE_NAME);
+        let mut columns: HashMap<Option<u32>, Column> = HashMap::new();
+        if path.exists() {
+            // Offset of the end of the last whole frame.
+            let mut offset = 0;
+            {
+                let mut reader = BufReader::new(File::open(&path)?);
+                while let Some(ops) = read_frame(&mut reader)? {
+                    apply_frame(&mut columns, &ops).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Corrupted frame at {} in {:?}: {}", offset, path, e),
+                        )
+                    })?;
+                    offset += 4 + ops.len() as u64 + CryptoHash::default().as_ref().len() as u64;
+                }
+            }
+            let file = OpenOptions::new().write(true).open(&path)?;
+            if file.metadata()?.len() != offset {
+                file.set_len(offset)?;
+                file.sync_all()?;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(AppendOnlyFileDB {
+            path,
+            columns: RwLock::new(columns),
+            log: Mutex::new(BufWriter::new(file)),
+            pending: Mutex::new(vec![]),
+        })
+    }
+
+    /// Rewrites the log so that it only contains the current values.
+    pub fn compact(&self) -> io::Result<()> {
+        self.flush()?;
+        let columns = self.columns.read();
+        let mut log = self.log.lock();
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut ops = vec![];
+            for (col, column) in columns.iter() {
+                for (key, value) in column.iter() {
+                    write_insert(&mut ops, *col, key, value)?;
+                }
+            }
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            write_frame(&mut writer, &ops)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        *log = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        Ok(())
+    }
+}
+
+impl KeyValueDB for AppendOnlyFileDB {
+    fn get(&self, col: Option<u32>, key: &[u8]) -> io::Result<Option<DBValue>> {
+        Ok(self.columns.read().get(&col).and_then(|column| column.get(key).cloned()))
+    }
+
+    fn get_by_prefix(&self, col: Option<u32>, prefix: &[u8]) -> Option<Box<[u8]>> {
+        self.iter_from_prefix(col, prefix).next().map(|(_, value)| value)
+    }
+
+    fn write_buffered(&self, transaction: DBTransaction) {
+        let mut columns = self.columns.write();
+        for op in transaction.ops.iter() {
+            apply_op(&mut columns, op);
+        }
+        self.pending.lock().push(transaction);
+    }
+
+    fn write(&self, transaction: DBTransaction) -> io::Result<()> {
+        self.write_buffered(transaction);
+        self.flush()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut pending = self.pending.lock();
+        let mut log = self.log.lock();
+        for transaction in pending.iter() {
+            write_frame(&mut *log, &encode_ops(&transaction.ops)?)?;
+        }
+        log.flush()?;
+        log.get_ref().sync_data()?;
+        pending.clear();
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self, col: Option<u32>) -> Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+        self.iter_from_prefix(col, &[])
+    }
+
+    fn iter_from_prefix<'a>(
+        &'a self,
+        col: Option<u32>,
+        prefix: &'a [u8],
+    ) -> Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+        let items: Vec<_> = match self.columns.read().get(&col) {
+            Some(column) => column
+                .range(prefix.to_vec()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .map(|(key, value)| {
+                    (key.clone().into_boxed_slice(), value.to_vec().into_boxed_slice())
+                })
+                .collect(),
+            None => vec![],
+        };
+        Box::new(items.into_iter())
+    }
+
+    fn restore(&self, new_db: &str) -> io::Result<()> {
+        let restored = AppendOnlyFileDB::open(Path::new(new_db))?;
+        *self.pending.lock() = vec![];
+        *self.columns.write() = restored.columns.into_inner();
+        fs::copy(&restored.path, &self.path)?;
+        *self.log.lock() = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("near_file_db_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = test_path("reopen");
+        {
+            let db = AppendOnlyFileDB::open(&path).unwrap();
+            let mut transaction = db.transaction();
+            transaction.put(Some(0), b"dog", b"puppy");
+            transaction.put(Some(0), b"cat", b"kitten");
+            transaction.put(None, b"best", b"block");
+            db.write(transaction).unwrap();
+            let mut transaction = db.transaction();
+            transaction.delete(Some(0), b"cat");
+            db.write(transaction).unwrap();
+        }
+        let db = AppendOnlyFileDB::open(&path).unwrap();
+        assert_eq!(db.get(Some(0), b"dog").unwrap().unwrap(), DBValue::from_slice(b"puppy"));
+        assert_eq!(db.get(Some(0), b"cat").unwrap(), None);
+        assert_eq!(db.get(None, b"best").unwrap().unwrap(), DBValue::from_slice(b"block"));
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = test_path("compact");
+        let db = AppendOnlyFileDB::open(&path).unwrap();
+        for i in 0..10u8 {
+            let mut transaction = db.transaction();
+            transaction.put(Some(1), b"key", &[i]);
+            transaction.put(Some(1), &[i], b"value");
+            db.write(transaction).unwrap();
+        }
+        let size = fs::metadata(path.join(LOG_FILE_NAME)).unwrap().len();
+        db.compact().unwrap();
+        assert!(fs::metadata(path.join(LOG_FILE_NAME)).unwrap().len() < size);
+        let db = AppendOnlyFileDB::open(&path).unwrap();
+        assert_eq!(db.get(Some(1), b"key").unwrap().unwrap(), DBValue::from_slice(&[9]));
+        assert_eq!(db.iter(Some(1)).count(), 11);
+        assert_eq!(db.iter_from_prefix(Some(1), b"ke").count(), 1);
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let path = test_path("torn_write");
+        let size = {
+            let db = AppendOnlyFileDB::open(&path).unwrap();
+            let mut transaction = db.transaction();
+            transaction.put(Some(0), b"dog", b"puppy");
+            db.write(transaction).unwrap();
+            let size = fs::metadata(path.join(LOG_FILE_NAME)).unwrap().len();
+            let mut transaction = db.transaction();
+            transaction.put(Some(0), b"cat", b"kitten");
+            transaction.delete(Some(0), b"dog");
+            db.write(transaction).unwrap();
+            size
+        };
+        // Crash in the middle of the second transaction.
+        let file = OpenOptions::new().write(true).open(path.join(LOG_FILE_NAME)).unwrap();
+        file.set_len(size + 10).unwrap();
+        {
+            let db = AppendOnlyFileDB::open(&path).unwrap();
+            assert_eq!(fs::metadata(path.join(LOG_FILE_NAME)).unwrap().len(), size);
+            assert_eq!(db.get(Some(0), b"dog").unwrap().unwrap(), DBValue::from_slice(b"puppy"));
+            assert_eq!(db.get(Some(0), b"cat").unwrap(), None);
+            let mut transaction = db.transaction();
+            transaction.put(Some(0), b"cow", b"calf");
+            db.write(transaction).unwrap();
+        }
+        let db = AppendOnlyFileDB::open(&path).unwrap();
+        assert_eq!(db.get(Some(0), b"dog").unwrap().unwrap(), DBValue::from_slice(b"puppy"));
+        assert_eq!(db.get(Some(0), b"cow").unwrap().unwrap(), DBValue::from_slice(b"calf"));
+    }
+}
@@ -1,78 +1,300 @@
+extern crate byteorder;
 extern crate hash256_std_hasher;
 extern crate hash_db;
 extern crate kvdb;
 extern crate kvdb_memorydb;
 extern crate kvdb_rocksdb;
+extern crate parking_lot;
 extern crate primitives;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate substrate_state_machine;
+extern crate substrate_trie;
+extern crate trie_db;
 
 #[cfg(test)]
 extern crate hex_literal;
 #[cfg(test)]
 extern crate memory_db;
 
-pub use kvdb::{DBValue, KeyValueDB};
+pub use kvdb::{DBTransaction, DBValue, KeyValueDB};
 use kvdb_rocksdb::{Database, DatabaseConfig};
+use parking_lot::Mutex;
 use primitives::hash::CryptoHash;
 use primitives::types::MerkleHash;
+use std::collections::{BTreeSet, VecDeque};
+use std::iter::{Map, Peekable};
+use std::ops::Bound;
+use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
 use substrate_storage::{CryptoHasher, Externalities, OverlayedChanges, StateExt, TrieBackend, Backend};
 pub use substrate_storage::TrieBackendTransaction;
+pub use diff::{diff_state, StateDiff};
+pub use file_db::AppendOnlyFileDB;
+pub use metrics::InstrumentedDB;
+pub use proof::{prove_state, verify_state_proof, StateProof};
+pub use pruning::{PruningMode, StateTransition};
+use pruning::JournalState;
+use trie_walk::TrieWalk;
 
+mod diff;
+mod file_db;
+pub mod metrics;
+pub mod migrations;
+mod proof;
+mod pruning;
+pub mod snapshot;
 mod substrate_storage;
 pub mod test_utils;
+mod trie_walk;
 
 pub const COL_STATE: Option<u32> = Some(0);
 pub const COL_EXTRA: Option<u32> = Some(1);
 pub const COL_BLOCKS: Option<u32> = Some(2);
 pub const COL_HEADERS: Option<u32> = Some(3);
 pub const COL_BLOCK_INDEX: Option<u32> = Some(4);
-pub const TOTAL_COLUMNS: Option<u32> = Some(5);
+pub const COL_STATE_RC: Option<u32> = Some(5);
+pub const COL_STATE_JOURNAL: Option<u32> = Some(6);
+pub const COL_SKIP_POINTERS: Option<u32> = Some(7);
+pub const COL_AUTHORITIES: Option<u32> = Some(8);
+pub const TOTAL_COLUMNS: Option<u32> = Some(9);
 
 /// Provides a way to access Storage and record changes with future commit.
-pub struct StateDbUpdate<'a> {
-    overlay: Box<OverlayedChanges>,
-    _backend: Box<TrieBackend>,
-    ext: StateExt<'a>,
+/// Owns both the changes and the backend, so it can be moved across threads and
+/// shared read-only between viewers.
+pub struct StateDbUpdate {
+    overlay: OverlayedChanges,
+    backend: TrieBackend,
+    state_db: Arc<StateDb>,
+    /// Stack of checkpoints. Every checkpoint records overlay values of the keys changed
+    /// after it was taken: `None` if the key was not in the overlay, `Some(None)` if deleted.
+    checkpoints: Vec<Vec<(Vec<u8>, Option<Option<Vec<u8>>>)>>,
+    /// Keys that were changed in the overlay, used to merge them into the iteration.
+    changed_keys: BTreeSet<Vec<u8>>,
 }
 
-impl<'a> StateDbUpdate<'a> {
+type StateItem = (Vec<u8>, DBValue);
+type BackendWalk = Peekable<Map<TrieWalk, fn(Result<StateItem, String>) -> StateItem>>;
+
+fn expect_backend(item: Result<StateItem, String>) -> StateItem {
+    item.expect("State of the backend must be available")
+}
+
+/// Iterator over `(key, value)` pairs of the state in lexicographic order of keys.
+/// Use `rev()` to iterate in reverse order. The trie is walked lazily from both ends, and
+/// uncommitted changes are merged in.
+pub struct StateIterator<'a> {
+    state: &'a StateDbUpdate,
+    front: BackendWalk,
+    back: BackendWalk,
+    /// Changed keys in the range. They are kept in memory by the overlay anyway.
+    changed_keys: VecDeque<Vec<u8>>,
+    /// Last keys reached from the front and from the back, the ends stop once they meet.
+    front_key: Option<Vec<u8>>,
+    back_key: Option<Vec<u8>>,
+}
+
+impl<'a> Iterator for StateIterator<'a> {
+    type Item = StateItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let from_backend = self.front.peek().map(|(key, _)| key.clone());
+            let from_overlay = self.changed_keys.front().cloned();
+            let key = match (from_backend, from_overlay) {
+                (None, None) => return None,
+                (Some(key), None) | (None, Some(key)) => key,
+                (Some(backend_key), Some(overlay_key)) => backend_key.min(overlay_key),
+            };
+            if self.back_key.as_ref().map_or(false, |back_key| key >= *back_key) {
+                return None;
+            }
+            let value = self.take_value(&key, false);
+            self.front_key = Some(key.clone());
+            if let Some(value) = value {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+impl<'a> DoubleEndedIterator for StateIterator<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let from_backend = self.back.peek().map(|(key, _)| key.clone());
+            let from_overlay = self.changed_keys.back().cloned();
+            let key = match (from_backend, from_overlay) {
+                (None, None) => return None,
+                (Some(key), None) | (None, Some(key)) => key,
+                (Some(backend_key), Some(overlay_key)) => backend_key.max(overlay_key),
+            };
+            if self.front_key.as_ref().map_or(false, |front_key| key <= *front_key) {
+                return None;
+            }
+            let value = self.take_value(&key, true);
+            self.back_key = Some(key.clone());
+            if let Some(value) = value {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+impl<'a> StateIterator<'a> {
+    /// Advances the given end past the key and returns its current value, if the key is not
+    /// deleted.
+    fn take_value(&mut self, key: &[u8], from_back: bool) -> Option<DBValue> {
+        let walk = if from_back { &mut self.back } else { &mut self.front };
+        let backend_value = match walk.peek() {
+            Some((backend_key, _)) if &backend_key[..] == key => true,
+            _ => false,
+        };
+        let backend_value = if backend_value { walk.next().map(|(_, value)| value) } else { None };
+        let changed_key =
+            if from_back { self.changed_keys.back() } else { self.changed_keys.front() };
+        if changed_key.map_or(false, |changed_key| &changed_key[..] == key) {
+            if from_back {
+                self.changed_keys.pop_back();
+            } else {
+                self.changed_keys.pop_front();
+            }
+            self.state.get(key)
+        } else {
+            backend_value
+        }
+    }
+}
+
+/// First key after all keys with the given prefix, `None` if there is no such key.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(byte) = end.pop() {
+        if byte < u8::max_value() {
+            end.push(byte + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+impl StateDbUpdate {
     pub fn new(state_db: Arc<StateDb>, root: MerkleHash) -> Self {
-        let backend = Box::new(TrieBackend::new(
-            state_db as Arc<substrate_state_machine::Storage<CryptoHasher>>,
+        let backend = TrieBackend::new(
+            state_db.clone() as Arc<substrate_state_machine::Storage<CryptoHasher>>,
             root,
-        ));
-        let backend_ptr = backend.as_ref() as *const TrieBackend;
-        let mut overlay = Box::new(OverlayedChanges::default());
-        let overlay_ptr = overlay.as_mut() as *mut OverlayedChanges;
+        );
         StateDbUpdate {
-            overlay,
-            _backend: backend,
-            ext: StateExt::new(unsafe { &mut *overlay_ptr }, unsafe { &*backend_ptr }, None),
+            overlay: OverlayedChanges::default(),
+            backend,
+            state_db,
+            checkpoints: vec![],
+            changed_keys: BTreeSet::new(),
         }
     }
+    fn ext(&mut self) -> StateExt {
+        StateExt::new(&mut self.overlay, &self.backend, None)
+    }
+    pub fn root(&self) -> MerkleHash {
+        *self.backend.root()
+    }
     pub fn get(&self, key: &[u8]) -> Option<DBValue> {
-        self.ext.storage(key).map(|v| DBValue::from_slice(&v))
+        match self.overlay.storage(key) {
+            Some(value) => value.map(DBValue::from_slice),
+            None => self
+                .backend
+                .storage(key)
+                .expect("State of the backend must be available")
+                .map(|v| DBValue::from_slice(&v)),
+        }
+    }
+    fn place(&mut self, key: &[u8], value: Option<Vec<u8>>) {
+        if let Some(checkpoint) = self.checkpoints.last_mut() {
+            let previous = self.overlay.storage(key).map(|v| v.map(|v| v.to_vec()));
+            checkpoint.push((key.to_vec(), previous));
+        }
+        self.changed_keys.insert(key.to_vec());
+        self.ext().place_storage(key.to_vec(), value);
     }
     pub fn set(&mut self, key: &[u8], value: &DBValue) {
-        self.ext.place_storage(key.to_vec(), Some(value.to_vec()));
+        self.place(key, Some(value.to_vec()));
     }
     pub fn delete(&mut self, key: &[u8]) {
-        self.ext.clear_storage(key);
+        self.place(key, None);
+    }
+    /// Calls `f` for every key with the given prefix, including uncommitted changes.
+    pub fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], mut f: F) {
+        for (key, _) in self.iter_prefix(prefix) {
+            f(&key);
+        }
     }
-    pub fn for_keys_with_prefix<F: FnMut(&[u8])>(&self, prefix: &[u8], f: F) {
-        self._backend.for_keys_with_prefix(prefix, f);
+    /// Iterates over keys in `[start, end)`, or all keys starting from `start` if there is no
+    /// `end`. Uncommitted changes are merged with the backend.
+    pub fn iter_range(&self, start: &[u8], end: Option<&[u8]>) -> StateIterator {
+        let walk = |reverse| {
+            let walk = TrieWalk::new(self.state_db.clone(), self.root(), start, end, reverse);
+            walk.map(expect_backend as fn(_) -> _).peekable()
+        };
+        let changed_keys = match end {
+            Some(end) if end <= start => VecDeque::new(),
+            _ => {
+                let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+                self.changed_keys.range::<[u8], _>((Bound::Included(start), end)).cloned().collect()
+            }
+        };
+        StateIterator {
+            state: self,
+            front: walk(false),
+            back: walk(true),
+            changed_keys,
+            front_key: None,
+            back_key: None,
+        }
+    }
+    /// Iterates over all keys with the given prefix.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> StateIterator {
+        let end = prefix_end(prefix);
+        self.iter_range(prefix, end.as_ref().map(|end| &end[..]))
     }
+    /// Starts a new checkpoint, nested in the current one if there is any.
+    pub fn checkpoint(&mut self) {
+        self.checkpoints.push(vec![]);
+    }
+    /// Reverts all changes since the last checkpoint and removes it.
+    pub fn rollback_to_checkpoint(&mut self) {
+        let checkpoint = self.checkpoints.pop().expect("No checkpoint to roll back to");
+        for (key, previous) in checkpoint.into_iter().rev() {
+            let value = match previous {
+                Some(value) => value,
+                // Key was not changed before the checkpoint, restore the value from the backend.
+                None => self.backend.storage(&key).expect("State of the backend must be available"),
+            };
+            self.ext().place_storage(key, value);
+        }
+    }
+    /// Removes the last checkpoint, keeping its changes. They become part of the enclosing
+    /// checkpoint and are reverted if it's rolled back.
+    pub fn discard_checkpoint(&mut self) {
+        let mut checkpoint = self.checkpoints.pop().expect("No checkpoint to discard");
+        if let Some(parent) = self.checkpoints.last_mut() {
+            parent.append(&mut checkpoint);
+        }
+    }
+    /// Commits all changes. Open checkpoints are discarded.
     pub fn commit(&mut self) {
+        self.checkpoints.clear();
         self.overlay.commit_prospective();
     }
+    /// Reverts all changes since the last commit. Open checkpoints are discarded.
     pub fn rollback(&mut self) {
+        self.checkpoints.clear();
         self.overlay.discard_prospective();
     }
     pub fn finalize(mut self) -> (TrieBackendTransaction, MerkleHash) {
-        let root_after = self.ext.storage_root();
-        let (storage_transaction, _changes_trie_transaction) = self.ext.transaction();
+        let mut ext = self.ext();
+        let root_after = ext.storage_root();
+        let (storage_transaction, _changes_trie_transaction) = ext.transaction();
         (storage_transaction, root_after)
     }
 }
@@ -84,29 +306,126 @@ pub type DiskStorage = Database;
 #[allow(dead_code)]
 pub struct StateDb {
     storage: Arc<KeyValueDB>,
+    pruning: PruningMode,
+    /// Journal of committed eras that are not pruned yet.
+    journal: Mutex<JournalState>,
     // TODO: for now.
     hashed_null_node: CryptoHash,
     null_node_data: DBValue,
 }
 
 impl StateDb {
+    /// Creates state db that keeps every committed root.
     pub fn new(storage: Arc<KeyValueDB>) -> Self {
-        StateDb {
+        StateDb::with_pruning(storage, PruningMode::Archive).expect("Failed to open state")
+    }
+
+    /// Fails if the state was written with a pruning mode of the other kind.
+    pub fn with_pruning(storage: Arc<KeyValueDB>, pruning: PruningMode) -> Result<Self, String> {
+        pruning::check_pruning_mode(storage.as_ref(), pruning)?;
+        let journal = pruning::read_journal_state(storage.as_ref());
+        Ok(StateDb {
             storage,
+            pruning,
+            journal: Mutex::new(journal),
             hashed_null_node: CryptoHash::default(),
             null_node_data: [0u8][..].into(),
-        }
+        })
+    }
+
+    pub fn pruning_mode(&self) -> PruningMode {
+        self.pruning
     }
+
+    /// Index of the first canonical block whose state is not pruned yet.
+    pub fn canonical_index(&self) -> u64 {
+        self.journal.lock().canonical_index
+    }
+
+    /// Writes the trie nodes of the state the chain starts from, e.g. genesis or a snapshot,
+    /// which may be built by several commits. Its nodes are counted right away, so it must be
+    /// committed only once if the state is pruned.
     pub fn commit(&self, transaction: &mut TrieBackendTransaction) -> std::io::Result<()> {
         let mut db_transaction = self.storage.transaction();
+        let (inserted, removed) = Self::drain_to(transaction, &mut db_transaction);
+        if self.pruning != PruningMode::Archive {
+            pruning::count_nodes(self.storage.as_ref(), &mut db_transaction, &inserted, &removed);
+        }
+        self.storage.write(db_transaction)
+    }
+
+    /// Adds the writes of the state transition of the block with given number to
+    /// `db_transaction`, so that they can be applied atomically with the block. Nodes removed
+    /// by the transaction are only deleted once the block is pruned with `prune`.
+    pub fn commit_to(
+        &self,
+        block_index: u64,
+        transition: StateTransition,
+        transaction: &mut TrieBackendTransaction,
+        db_transaction: &mut DBTransaction,
+    ) {
+        let (inserted, removed) = Self::drain_to(transaction, db_transaction);
+        if self.pruning != PruningMode::Archive {
+            pruning::journal_commit(
+                db_transaction,
+                &mut self.journal.lock(),
+                block_index,
+                transition,
+                inserted,
+                removed,
+            );
+        }
+    }
+
+    /// Same as `commit_to`, but writes the state transition right away.
+    pub fn commit_state(
+        &self,
+        block_index: u64,
+        transition: StateTransition,
+        transaction: &mut TrieBackendTransaction,
+    ) -> std::io::Result<()> {
+        let mut db_transaction = self.storage.transaction();
+        self.commit_to(block_index, transition, transaction, &mut db_transaction);
+        self.storage.write(db_transaction)
+    }
+
+    /// Prunes the states before the canonical blocks starting from `first_index`, given their
+    /// state transitions. The state after the last of them is kept, while the states of the
+    /// other blocks with the same numbers are removed. Later blocks are kept on every fork.
+    pub fn prune(&self, first_index: u64, canonical: &[StateTransition]) -> Result<(), String> {
+        if self.pruning == PruningMode::Archive || canonical.is_empty() {
+            return Ok(());
+        }
+        let mut journal = self.journal.lock();
+        let mut new_journal = *journal;
+        let mut db_transaction = self.storage.transaction();
+        pruning::canonicalize(
+            self.storage.as_ref(),
+            &mut db_transaction,
+            &mut new_journal,
+            first_index,
+            canonical,
+        )?;
+        self.storage.write(db_transaction).map_err(|e| format!("Database write failed: {}", e))?;
+        *journal = new_journal;
+        Ok(())
+    }
+
+    fn drain_to(
+        transaction: &mut TrieBackendTransaction,
+        db_transaction: &mut DBTransaction,
+    ) -> (Vec<(CryptoHash, u32)>, Vec<(CryptoHash, u32)>) {
+        let mut inserted = vec![];
+        let mut removed = vec![];
         for (k, (v, rc)) in transaction.drain() {
             if rc > 0 {
                 db_transaction.put(COL_STATE, k.as_ref(), &v.to_vec());
+                inserted.push((k, rc as u32));
             } else if rc < 0 {
-                db_transaction.delete(COL_STATE, k.as_ref());
+                removed.push((k, (-rc) as u32));
             }
         }
-        self.storage.write(db_transaction)
+        (inserted, removed)
     }
 }
 
@@ -123,15 +442,65 @@ impl substrate_state_machine::Storage<CryptoHasher> for StateDb {
     }
 }
 
+/// Opens the database and upgrades it to the current schema version.
 pub fn open_database(storage_path: &str) -> Database {
     let storage_config = DiskStorageConfig::with_columns(TOTAL_COLUMNS);
-    DiskStorage::open(&storage_config, storage_path).expect("Database wasn't open")
+    let db = DiskStorage::open(&storage_config, storage_path).expect("Database wasn't open");
+    check_schema(&db);
+    db
+}
+
+fn check_schema(storage: &KeyValueDB) {
+    if let Err(e) = migrations::migrate(storage) {
+        panic!("Database schema check failed: {}", e);
+    }
+}
+
+/// Database implementation that backs the `Storage`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    RocksDb,
+    /// Keeps everything in memory, nothing is persisted.
+    Memory,
+    /// Single append-only log file, see `AppendOnlyFileDB`.
+    AppendOnlyFile,
+}
+
+impl Default for StorageBackend {
+    fn default() -> Self {
+        StorageBackend::RocksDb
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rocksdb" => Ok(StorageBackend::RocksDb),
+            "memory" => Ok(StorageBackend::Memory),
+            "file" => Ok(StorageBackend::AppendOnlyFile),
+            _ => Err(format!("Unknown storage backend {}", s)),
+        }
+    }
+}
+
+pub fn open_storage(backend: StorageBackend, storage_path: &str) -> Arc<Storage> {
+    match backend {
+        StorageBackend::RocksDb => Arc::new(open_database(storage_path)),
+        StorageBackend::Memory => Arc::new(kvdb_memorydb::create(TOTAL_COLUMNS.unwrap())),
+        StorageBackend::AppendOnlyFile => {
+            let db = AppendOnlyFileDB::open(Path::new(storage_path)).expect("Database wasn't open");
+            check_schema(&db);
+            Arc::new(db)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use test_utils::create_state_db;
+    use test_utils::{create_memory_db, create_state_db};
 
     #[test]
     fn state_db() {
@@ -149,4 +518,209 @@ mod tests {
         state_db_update2.for_keys_with_prefix(b"dog", |key| { values.push(key.to_vec()) });
         assert_eq!(values, vec![b"dog".to_vec(), b"dog2".to_vec()]);
     }
+
+    fn commit_value(state_db: &Arc<StateDb>, root: MerkleHash, value: &[u8]) -> MerkleHash {
+        let mut state_db_update = StateDbUpdate::new(state_db.clone(), root);
+        state_db_update.set(b"dog", &DBValue::from_slice(value));
+        state_db_update.set(b"cat", &DBValue::from_slice(b"kitten"));
+        let (mut transaction, new_root) = state_db_update.finalize();
+        state_db.commit(&mut transaction).unwrap();
+        new_root
+    }
+
+    #[test]
+    fn state_db_update_nested_checkpoints() {
+        let state_db = Arc::new(create_state_db());
+        let root = commit_value(&state_db, CryptoHash::default(), b"puppy");
+        let mut state_db_update = StateDbUpdate::new(state_db.clone(), root);
+        state_db_update.checkpoint();
+        state_db_update.set(b"dog", &DBValue::from_slice(b"doggy"));
+        state_db_update.checkpoint();
+        state_db_update.set(b"dog", &DBValue::from_slice(b"hound"));
+        state_db_update.delete(b"cat");
+        state_db_update.set(b"cow", &DBValue::from_slice(b"calf"));
+        state_db_update.rollback_to_checkpoint();
+        assert_eq!(state_db_update.get(b"dog").unwrap(), DBValue::from_slice(b"doggy"));
+        assert_eq!(state_db_update.get(b"cat").unwrap(), DBValue::from_slice(b"kitten"));
+        assert_eq!(state_db_update.get(b"cow"), None);
+
+        state_db_update.checkpoint();
+        state_db_update.set(b"cow", &DBValue::from_slice(b"calf"));
+        state_db_update.discard_checkpoint();
+        assert_eq!(state_db_update.get(b"cow").unwrap(), DBValue::from_slice(b"calf"));
+        state_db_update.rollback_to_checkpoint();
+        assert_eq!(state_db_update.get(b"dog").unwrap(), DBValue::from_slice(b"puppy"));
+        assert_eq!(state_db_update.get(b"cow"), None);
+
+        // Rolled back changes don't affect the resulting root.
+        let (_, new_root) = state_db_update.finalize();
+        assert_eq!(new_root, root);
+    }
+
+    #[test]
+    fn state_db_update_iter() {
+        let state_db = Arc::new(create_state_db());
+        let root = commit_value(&state_db, CryptoHash::default(), b"puppy");
+        let mut state_db_update = StateDbUpdate::new(state_db.clone(), root);
+        state_db_update.set(b"dog2", &DBValue::from_slice(b"puppy2"));
+        state_db_update.set(b"cow", &DBValue::from_slice(b"calf"));
+        state_db_update.delete(b"cat");
+        let keys = |iter: StateIterator| iter.map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(
+            keys(state_db_update.iter_range(b"", None)),
+            vec![b"cow".to_vec(), b"dog".to_vec(), b"dog2".to_vec()]
+        );
+        assert_eq!(
+            keys(state_db_update.iter_range(b"d", None).rev()),
+            vec![b"dog2".to_vec(), b"dog".to_vec()]
+        );
+        assert_eq!(
+            keys(state_db_update.iter_range(b"cow", Some(b"dog2"))),
+            vec![b"cow".to_vec(), b"dog".to_vec()]
+        );
+        let values: Vec<_> = state_db_update.iter_prefix(b"dog").map(|(_, value)| value).collect();
+        assert_eq!(values, vec![DBValue::from_slice(b"puppy"), DBValue::from_slice(b"puppy2")]);
+        // Both ends stop where they meet.
+        let mut iter = state_db_update.iter_range(b"", None);
+        assert_eq!(iter.next().map(|(key, _)| key), Some(b"cow".to_vec()));
+        assert_eq!(iter.next_back().map(|(key, _)| key), Some(b"dog2".to_vec()));
+        assert_eq!(iter.next().map(|(key, _)| key), Some(b"dog".to_vec()));
+        assert_eq!(iter.next_back(), None);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn state_db_update_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<StateDbUpdate>();
+    }
+
+    #[test]
+    fn state_db_update_shared_between_threads() {
+        let state_db = Arc::new(create_state_db());
+        let root = commit_value(&state_db, CryptoHash::default(), b"puppy");
+        let mut state_db_update = StateDbUpdate::new(state_db.clone(), root);
+        state_db_update.set(b"cat", &DBValue::from_slice(b"tiger"));
+        let state_db_update = Arc::new(state_db_update);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let state_db_update = state_db_update.clone();
+                std::thread::spawn(move || {
+                    assert_eq!(state_db_update.get(b"dog").unwrap(), DBValue::from_slice(b"puppy"));
+                    assert_eq!(state_db_update.get(b"cat").unwrap(), DBValue::from_slice(b"tiger"));
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn state_db_archive_keeps_old_roots() {
+        let state_db = Arc::new(create_state_db());
+        let root1 = commit_value(&state_db, CryptoHash::default(), b"puppy");
+        commit_value(&state_db, root1, b"doggy");
+        let state_db_update = StateDbUpdate::new(state_db.clone(), root1);
+        assert_eq!(state_db_update.get(b"dog").unwrap(), DBValue::from_slice(b"puppy"));
+    }
+
+    fn commit_block(
+        state_db: &Arc<StateDb>,
+        index: u64,
+        root: MerkleHash,
+        value: &[u8],
+    ) -> MerkleHash {
+        let mut state_db_update = StateDbUpdate::new(state_db.clone(), root);
+        state_db_update.set(b"dog", &DBValue::from_slice(value));
+        state_db_update.set(b"cat", &DBValue::from_slice(b"kitten"));
+        let (mut transaction, new_root) = state_db_update.finalize();
+        state_db.commit_state(index, (root, new_root), &mut transaction).unwrap();
+        new_root
+    }
+
+    #[test]
+    fn state_db_pruning() {
+        let storage = Arc::new(create_memory_db());
+        let state_db =
+            Arc::new(StateDb::with_pruning(storage.clone(), PruningMode::KeepRecent(1)).unwrap());
+        let genesis = commit_value(&state_db, CryptoHash::default(), b"genesis");
+        let mut canonical = vec![];
+        let mut root = genesis;
+        for (index, value) in [b"puppy", b"doggy", b"hound"].iter().enumerate() {
+            let new_root = commit_block(&state_db, index as u64 + 1, root, *value);
+            canonical.push((root, new_root));
+            root = new_root;
+        }
+        let fork = commit_block(&state_db, 2, canonical[0].1, b"wolf");
+        let next_root = commit_block(&state_db, 4, root, b"pup");
+
+        // Nothing is removed until the blocks are pruned.
+        let state_db_update = StateDbUpdate::new(state_db.clone(), genesis);
+        assert_eq!(state_db_update.get(b"dog").unwrap(), DBValue::from_slice(b"genesis"));
+        assert!(state_db.prune(1, &[(root, genesis)]).is_err());
+        state_db.prune(1, &canonical).unwrap();
+        assert_eq!(state_db.canonical_index(), 4);
+
+        // Last pruned block and the blocks after it are kept.
+        for (root, value) in [(root, b"hound"), (next_root, b"pup")].iter() {
+            let state_db_update = StateDbUpdate::new(state_db.clone(), *root);
+            assert_eq!(state_db_update.get(b"dog").unwrap(), DBValue::from_slice(*value));
+        }
+        // Fork with the number of a pruned block is removed.
+        assert!(storage.get(COL_STATE, fork.as_ref()).unwrap().is_none());
+
+        // Only nodes of the last root are left, same as when committing it from scratch.
+        state_db.prune(4, &[(root, next_root)]).unwrap();
+        let fresh_storage = Arc::new(create_memory_db());
+        let fresh_state_db = Arc::new(StateDb::new(fresh_storage.clone()));
+        assert_eq!(commit_value(&fresh_state_db, CryptoHash::default(), b"pup"), next_root);
+        assert_eq!(storage.iter(COL_STATE).count(), fresh_storage.iter(COL_STATE).count());
+
+        // Journal boundaries are restored after restart.
+        let restarted = StateDb::with_pruning(storage.clone(), PruningMode::KeepRecent(1)).unwrap();
+        assert_eq!(
+            *restarted.journal.lock(),
+            JournalState { first_era: 5, next_era: 5, canonical_index: 5 }
+        );
+    }
+
+    #[test]
+    fn state_db_pruning_keeps_forks_above_pruned_block() {
+        let storage = Arc::new(create_memory_db());
+        let state_db =
+            Arc::new(StateDb::with_pruning(storage.clone(), PruningMode::KeepRecent(1)).unwrap());
+        let genesis = commit_value(&state_db, CryptoHash::default(), b"genesis");
+        let root1 = commit_block(&state_db, 1, genesis, b"puppy");
+        // Fork from the first block is journaled before the canonical block with its number.
+        let fork2 = commit_block(&state_db, 2, root1, b"wolf");
+        let fork3 = commit_block(&state_db, 3, fork2, b"coyote");
+        let root2 = commit_block(&state_db, 2, root1, b"doggy");
+        state_db.prune(1, &[(genesis, root1)]).unwrap();
+
+        for (root, value) in [(fork2, b"wolf"), (fork3, b"coyote"), (root2, b"doggy")].iter() {
+            let state_db_update = StateDbUpdate::new(state_db.clone(), *root);
+            assert_eq!(state_db_update.get(b"dog").unwrap(), DBValue::from_slice(*value));
+        }
+        // The era of the fork is still pending after a restart.
+        let restarted = StateDb::with_pruning(storage.clone(), PruningMode::KeepRecent(1)).unwrap();
+        assert_eq!(restarted.journal.lock().first_era, 1);
+
+        state_db.prune(2, &[(root1, root2)]).unwrap();
+        assert!(storage.get(COL_STATE, fork2.as_ref()).unwrap().is_none());
+        let state_db_update = StateDbUpdate::new(state_db.clone(), root2);
+        assert_eq!(state_db_update.get(b"dog").unwrap(), DBValue::from_slice(b"doggy"));
+    }
+
+    #[test]
+    fn state_db_pruning_mode_is_kept() {
+        let archive = Arc::new(create_memory_db());
+        commit_value(&Arc::new(StateDb::new(archive.clone())), CryptoHash::default(), b"puppy");
+        assert!(StateDb::with_pruning(archive.clone(), PruningMode::KeepRecent(1)).is_err());
+
+        let pruned = Arc::new(create_memory_db());
+        assert!(StateDb::with_pruning(pruned.clone(), PruningMode::KeepRecent(1)).is_ok());
+        assert!(StateDb::with_pruning(pruned.clone(), PruningMode::KeepRecent(10)).is_ok());
+        assert!(StateDb::with_pruning(pruned.clone(), PruningMode::Archive).is_err());
+    }
 }
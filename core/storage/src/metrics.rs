@@ -0,0 +1,263 @@
+//! Instrumented storage that counts reads and writes per column.
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+
+use kvdb::{DBOp, DBTransaction, DBValue, KeyValueDB};
+use parking_lot::Mutex;
+use primitives::traits::{Decode, Encode};
+
+use {
+    COL_AUTHORITIES, COL_BLOCKS, COL_BLOCK_INDEX, COL_EXTRA, COL_HEADERS, COL_SKIP_POINTERS,
+    COL_STATE, COL_STATE_JOURNAL, COL_STATE_RC, TOTAL_COLUMNS,
+};
+
+/// Key in `COL_EXTRA` under which the sizes of the columns are stored. They are written in the
+/// same transaction as the changes they count, so they survive restarts.
+const SIZES_KEY: &[u8] = b"column_sizes";
+
+/// Number of keys and total size of keys and values per column.
+type Sizes = BTreeMap<Option<u32>, (u64, u64)>;
+
+/// Human readable name of the column.
+pub fn column_name(col: Option<u32>) -> String {
+    let name = match col {
+        c if c == COL_STATE => "state",
+        c if c == COL_EXTRA => "extra",
+        c if c == COL_BLOCKS => "blocks",
+        c if c == COL_HEADERS => "headers",
+        c if c == COL_BLOCK_INDEX => "block_index",
+        c if c == COL_STATE_RC => "state_rc",
+        c if c == COL_STATE_JOURNAL => "state_journal",
+        c if c == COL_SKIP_POINTERS => "skip_pointers",
+        c if c == COL_AUTHORITIES => "authorities",
+        None => "default",
+        Some(c) => return format!("column_{}", c),
+    };
+    name.to_string()
+}
+
+/// Operations on a single column since the storage was opened.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ColumnStats {
+    pub reads: u64,
+    /// Reads that found a value.
+    pub read_hits: u64,
+    pub read_bytes: u64,
+    pub writes: u64,
+    pub written_bytes: u64,
+    pub deletes: u64,
+    /// Number of keys stored in the column.
+    pub keys: u64,
+    /// Total size of keys and values stored in the column.
+    pub bytes: u64,
+}
+
+/// Hits and misses of an in-memory cache in front of the storage.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Storage wrapper that records `ColumnStats` for every operation passing through it.
+pub struct InstrumentedDB {
+    db: Arc<KeyValueDB>,
+    stats: Mutex<HashMap<Option<u32>, ColumnStats>>,
+    /// Updated on every write, so the columns are never walked to count them.
+    sizes: Mutex<Sizes>,
+}
+
+fn is_sizes_key(col: Option<u32>, key: &[u8]) -> bool {
+    col == COL_EXTRA && key == SIZES_KEY
+}
+
+/// Reads the stored sizes of the columns.
+fn load_sizes(db: &KeyValueDB) -> Sizes {
+    match db.get(COL_EXTRA, SIZES_KEY) {
+        Ok(Some(data)) => Decode::decode(&data).expect("Failed to decode column sizes"),
+        _ => count_sizes(db),
+    }
+}
+
+/// Walks over every column. Only needed once for a storage written before the sizes were
+/// stored, e.g. a new one.
+fn count_sizes(db: &KeyValueDB) -> Sizes {
+    (0..TOTAL_COLUMNS.unwrap())
+        .map(|col| {
+            let col = Some(col);
+            let size = db
+                .iter(col)
+                .filter(|(key, _)| !is_sizes_key(col, key))
+                .fold((0, 0), |(keys, bytes), (key, value)| {
+                    (keys + 1, bytes + (key.len() + value.len()) as u64)
+                });
+            (col, size)
+        })
+        .collect()
+}
+
+impl InstrumentedDB {
+    pub fn new(db: Arc<KeyValueDB>) -> Self {
+        let sizes = load_sizes(db.as_ref());
+        InstrumentedDB { db, stats: Mutex::new(HashMap::new()), sizes: Mutex::new(sizes) }
+    }
+
+    /// Returns statistics of all columns, keyed by column name.
+    pub fn column_stats(&self) -> BTreeMap<String, ColumnStats> {
+        let counters = self.stats.lock().clone();
+        let sizes = self.sizes.lock();
+        (0..TOTAL_COLUMNS.unwrap())
+            .map(|col| {
+                let col = Some(col);
+                let mut stats = counters.get(&col).cloned().unwrap_or_default();
+                let (keys, bytes) = sizes.get(&col).cloned().unwrap_or_default();
+                stats.keys = keys;
+                stats.bytes = bytes;
+                (column_name(col), stats)
+            })
+            .collect()
+    }
+
+    fn record_read(&self, col: Option<u32>, value: Option<usize>) {
+        let mut stats = self.stats.lock();
+        let stats = stats.entry(col).or_insert_with(ColumnStats::default);
+        stats.reads += 1;
+        if let Some(len) = value {
+            stats.read_hits += 1;
+            stats.read_bytes += len as u64;
+        }
+    }
+}
+
+impl KeyValueDB for InstrumentedDB {
+    fn get(&self, col: Option<u32>, key: &[u8]) -> io::Result<Option<DBValue>> {
+        let result = self.db.get(col, key)?;
+        self.record_read(col, result.as_ref().map(|value| value.len()));
+        Ok(result)
+    }
+
+    fn get_by_prefix(&self, col: Option<u32>, prefix: &[u8]) -> Option<Box<[u8]>> {
+        let result = self.db.get_by_prefix(col, prefix);
+        self.record_read(col, result.as_ref().map(|value| value.len()));
+        result
+    }
+
+    fn write_buffered(&self, mut transaction: DBTransaction) {
+        // Held until the transaction is written, so that the sizes are updated in its order.
+        let mut sizes = self.sizes.lock();
+        {
+            let mut stats = self.stats.lock();
+            // Sizes of the values set earlier in this transaction.
+            let mut written: HashMap<(Option<u32>, Vec<u8>), Option<usize>> = HashMap::new();
+            for op in transaction.ops.iter() {
+                let (col, key, new_len) = match op {
+                    DBOp::Insert { col, key, value } => {
+                        let stats = stats.entry(*col).or_insert_with(ColumnStats::default);
+                        stats.writes += 1;
+                        stats.written_bytes += (key.len() + value.len()) as u64;
+                        (*col, key.to_vec(), Some(value.len()))
+                    }
+                    DBOp::Delete { col, key } => {
+                        stats.entry(*col).or_insert_with(ColumnStats::default).deletes += 1;
+                        (*col, key.to_vec(), None)
+                    }
+                };
+                if is_sizes_key(col, &key) {
+                    continue;
+                }
+                let old_len = match written.get(&(col, key.clone())) {
+                    Some(len) => *len,
+                    None => self.db.get(col, &key).ok().and_then(|value| value).map(|v| v.len()),
+                };
+                let size = sizes.entry(col).or_insert((0, 0));
+                if let Some(len) = old_len {
+                    size.0 = size.0.saturating_sub(1);
+                    size.1 = size.1.saturating_sub((key.len() + len) as u64);
+                }
+                if let Some(len) = new_len {
+                    size.0 += 1;
+                    size.1 += (key.len() + len) as u64;
+                }
+                written.insert((col, key), new_len);
+            }
+        }
+        if let Some(data) = sizes.encode() {
+            transaction.put(COL_EXTRA, SIZES_KEY, &data);
+        }
+        self.db.write_buffered(transaction)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.db.flush()
+    }
+
+    fn iter<'a>(&'a self, col: Option<u32>) -> Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+        self.db.iter(col)
+    }
+
+    fn iter_from_prefix<'a>(
+        &'a self,
+        col: Option<u32>,
+        prefix: &'a [u8],
+    ) -> Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+        self.db.iter_from_prefix(col, prefix)
+    }
+
+    fn restore(&self, new_db: &str) -> io::Result<()> {
+        let mut sizes = self.sizes.lock();
+        self.db.restore(new_db)?;
+        *sizes = load_sizes(self.db.as_ref());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::create_memory_db;
+
+    #[test]
+    fn test_column_stats() {
+        let db = InstrumentedDB::new(Arc::new(create_memory_db()));
+        let mut transaction = db.transaction();
+        transaction.put(COL_BLOCKS, b"dog", b"puppy");
+        transaction.put(COL_BLOCKS, b"cat", b"kitten");
+        transaction.delete(COL_HEADERS, b"cow");
+        db.write(transaction).unwrap();
+        assert!(db.get(COL_BLOCKS, b"dog").unwrap().is_some());
+        assert!(db.get(COL_BLOCKS, b"cow").unwrap().is_none());
+
+        let stats = db.column_stats();
+        let blocks = stats["blocks"];
+        assert_eq!((blocks.reads, blocks.read_hits, blocks.read_bytes), (2, 1, 5));
+        assert_eq!((blocks.writes, blocks.written_bytes), (2, 17));
+        assert_eq!((blocks.keys, blocks.bytes), (2, 17));
+        assert_eq!(stats["headers"].deletes, 1);
+        assert_eq!(stats["state"], ColumnStats::default());
+
+        // Sizes follow overwrites and deletes, and are kept in the storage.
+        let mut transaction = db.transaction();
+        transaction.put(COL_BLOCKS, b"cow", b"calf");
+        transaction.put(COL_BLOCKS, b"dog", b"hound");
+        transaction.delete(COL_BLOCKS, b"cat");
+        transaction.delete(COL_BLOCKS, b"cat");
+        db.write(transaction).unwrap();
+        let blocks = db.column_stats()["blocks"];
+        assert_eq!((blocks.writes, blocks.keys, blocks.bytes), (4, 2, 15));
+        let reopened = InstrumentedDB::new(db.db.clone());
+        let blocks = reopened.column_stats()["blocks"];
+        assert_eq!((blocks.writes, blocks.keys, blocks.bytes), (0, 2, 15));
+    }
+}
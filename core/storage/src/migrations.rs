@@ -0,0 +1,206 @@
+//! Versioning of the on-disk layout.
+//!
+//! The schema version is stored in `COL_EXTRA`. Databases created before versioning was
+//! introduced have no version and are treated as version 0. On open, the database is upgraded
+//! one version at a time by the migrations below, and the version is bumped after each step,
+//! so an interrupted upgrade resumes from the last completed step.
+//! A change that can't be applied to the stored chain rejects the old database instead,
+//! it has to be removed and the node synced from scratch.
+use kvdb::KeyValueDB;
+use primitives::traits::{Decode, Encode};
+
+use {COL_EXTRA, COL_STATE};
+
+/// Key in `COL_EXTRA` under which the schema version is stored.
+const DB_VERSION_KEY: &[u8] = b"db_version";
+
+/// Version of the layout written by this code. Equals the number of migrations.
+pub const DB_VERSION: u32 = 10;
+
+/// Upgrades the database from version `i` to `i + 1`, where `i` is the index in `MIGRATIONS`.
+pub type Migration = fn(&KeyValueDB) -> Result<(), String>;
+
+const MIGRATIONS: &[Migration] = &[
+    add_version_marker,
+    best_chain_block_index,
+    add_finalized_block,
+    add_skip_pointers_column,
+    add_authorities_column,
+    random_reveals_in_headers,
+    unstaking_in_runtime_data,
+    slashed_authorities_in_headers,
+    rewards_in_runtime_data,
+    authority_set_hash_in_headers,
+];
+
+/// 0 -> 1: introduces the version marker, the layout itself is unchanged.
+fn add_version_marker(_storage: &KeyValueDB) -> Result<(), String> {
+    Ok(())
+}
+
+/// 1 -> 2: the block index maps only the blocks of the best chain, older databases also
+/// map the blocks of side branches. The storage can't decode the headers to tell them
+/// apart, so the old chain is rejected.
+fn best_chain_block_index(_storage: &KeyValueDB) -> Result<(), String> {
+    reject_old_chain()
+}
+
+/// 2 -> 3: the last finalized block is stored in `COL_EXTRA`. Databases without it treat
+/// genesis as final until the next block is finalized, so nothing is written.
+fn add_finalized_block(_storage: &KeyValueDB) -> Result<(), String> {
+    Ok(())
+}
+
+/// 3 -> 4: headers are stored with a skip pointer to an earlier ancestor. Headers stored
+/// before have none and are walked through their parents, so nothing is rewritten.
+fn add_skip_pointers_column(_storage: &KeyValueDB) -> Result<(), String> {
+    Ok(())
+}
+
+/// 4 -> 5: the authority schedule is stored in `COL_AUTHORITIES`. Nothing is stored before,
+/// so the schedule is rebuilt from the chain on the next start.
+fn add_authorities_column(_storage: &KeyValueDB) -> Result<(), String> {
+    Ok(())
+}
+
+/// 5 -> 6: beacon headers carry the random reveals of their block. The block hashes
+/// change with the headers, so the old chain is rejected.
+fn random_reveals_in_headers(_storage: &KeyValueDB) -> Result<(), String> {
+    reject_old_chain()
+}
+
+/// 6 -> 7: the runtime data in the state holds the unstaked money and the lockup period.
+/// The blocks commit to the state roots, so the old state is rejected.
+fn unstaking_in_runtime_data(_storage: &KeyValueDB) -> Result<(), String> {
+    reject_old_chain()
+}
+
+/// 7 -> 8: beacon headers carry the authorities slashed in their block, and the
+/// authority schedule tracks the revoked seats. The block hashes change with the headers,
+/// so the old chain is rejected.
+fn slashed_authorities_in_headers(_storage: &KeyValueDB) -> Result<(), String> {
+    reject_old_chain()
+}
+
+/// 8 -> 9: the runtime data holds the total supply and the participation of the
+/// authorities for their rewards. The blocks commit to the state roots, so the old state
+/// is rejected.
+fn rewards_in_runtime_data(_storage: &KeyValueDB) -> Result<(), String> {
+    reject_old_chain()
+}
+
+/// 9 -> 10: beacon headers commit to the authority set of the next epoch. The block
+/// hashes change with the headers, so the old chain is rejected.
+fn authority_set_hash_in_headers(_storage: &KeyValueDB) -> Result<(), String> {
+    reject_old_chain()
+}
+
+/// Refuses a database whose chain can't be converted to the new layout.
+fn reject_old_chain() -> Result<(), String> {
+    Err("Chain format has changed, remove the database and sync from scratch".to_string())
+}
+
+/// Returns the stored version, `None` for databases without one.
+pub fn read_db_version(storage: &KeyValueDB) -> Result<Option<u32>, String> {
+    match storage.get(COL_EXTRA, DB_VERSION_KEY) {
+        Ok(Some(data)) => Decode::decode(data.as_ref())
+            .map(Some)
+            .ok_or_else(|| "Failed to decode database version".to_string()),
+        Ok(None) => Ok(None),
+        Err(e) => Err(format!("Failed to read database version: {}", e)),
+    }
+}
+
+fn write_db_version(storage: &KeyValueDB, version: u32) -> Result<(), String> {
+    let data = version.encode().ok_or("Failed to encode database version")?;
+    let mut db_transaction = storage.transaction();
+    db_transaction.put(COL_EXTRA, DB_VERSION_KEY, &data);
+    storage.write(db_transaction).map_err(|e| format!("Failed to write database version: {}", e))
+}
+
+fn is_empty(storage: &KeyValueDB) -> bool {
+    storage.iter(COL_EXTRA).next().is_none() && storage.iter(COL_STATE).next().is_none()
+}
+
+fn migrate_with(storage: &KeyValueDB, migrations: &[Migration]) -> Result<u32, String> {
+    let latest = migrations.len() as u32;
+    let mut version = match read_db_version(storage)? {
+        Some(version) => version,
+        None if is_empty(storage) => {
+            write_db_version(storage, latest)?;
+            return Ok(latest);
+        }
+        None => 0,
+    };
+    if version > latest {
+        return Err(format!(
+            "Database version {} is newer than the supported version {}",
+            version, latest
+        ));
+    }
+    while version < latest {
+        migrations[version as usize](storage)
+            .map_err(|e| format!("Migration from version {} failed: {}", version, e))?;
+        version += 1;
+        write_db_version(storage, version)?;
+    }
+    Ok(version)
+}
+
+/// Checks the schema version and upgrades the database to `DB_VERSION` if needed.
+/// Empty databases are stamped with the current version.
+pub fn migrate(storage: &KeyValueDB) -> Result<u32, String> {
+    migrate_with(storage, MIGRATIONS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::create_memory_db;
+    use COL_BLOCKS;
+
+    #[test]
+    fn test_fresh_database() {
+        let storage = create_memory_db();
+        assert_eq!(migrate(&storage), Ok(DB_VERSION));
+        assert_eq!(read_db_version(&storage), Ok(Some(DB_VERSION)));
+        assert_eq!(migrate(&storage), Ok(DB_VERSION));
+    }
+
+    #[test]
+    fn test_step_by_step_migration() {
+        let storage = create_memory_db();
+        let mut db_transaction = storage.transaction();
+        db_transaction.put(COL_EXTRA, b"best", b"block");
+        storage.write(db_transaction).unwrap();
+
+        let migrations: &[Migration] = &[
+            |_| Ok(()),
+            |storage| {
+                let mut db_transaction = storage.transaction();
+                db_transaction.put(COL_BLOCKS, b"migrated", b"yes");
+                storage.write(db_transaction).map_err(|e| e.to_string())
+            },
+            |_| Err("broken".to_string()),
+        ];
+        assert!(migrate_with(&storage, migrations).is_err());
+        assert_eq!(read_db_version(&storage), Ok(Some(2)));
+        assert!(storage.get(COL_BLOCKS, b"migrated").unwrap().is_some());
+        assert_eq!(migrate_with(&storage, &migrations[..2]), Ok(2));
+    }
+
+    #[test]
+    fn test_old_chain_is_rejected() {
+        let storage = create_memory_db();
+        write_db_version(&storage, 1).unwrap();
+        assert!(migrate(&storage).is_err());
+        assert_eq!(read_db_version(&storage), Ok(Some(1)));
+    }
+
+    #[test]
+    fn test_newer_database() {
+        let storage = create_memory_db();
+        write_db_version(&storage, DB_VERSION + 1).unwrap();
+        assert!(migrate(&storage).is_err());
+    }
+}
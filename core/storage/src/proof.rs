@@ -0,0 +1,97 @@
+//! Merkle proofs of inclusion and exclusion of state keys.
+use std::sync::Arc;
+
+use primitives::types::MerkleHash;
+use substrate_state_machine::{prove_read, read_proof_check};
+
+use substrate_storage::{CryptoHasher, Storage, TrieBackend};
+use StateDb;
+
+/// Proof that `key` has `value` under the state `root`. A proof with `value` equal to `None`
+/// proves that the key is absent.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StateProof {
+    pub root: MerkleHash,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    /// Encoded trie nodes visited on the way from the root to the key.
+    pub nodes: Vec<Vec<u8>>,
+}
+
+impl StateProof {
+    /// Checks the proof against its own root. Only requires `CryptoHasher`, no storage.
+    pub fn verify(&self) -> bool {
+        match verify_state_proof(self.root, &self.key, self.nodes.clone()) {
+            Ok(value) => value == self.value,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Generates a proof for the value of `key` under the given `root`.
+pub fn prove_state(
+    state_db: Arc<StateDb>,
+    root: MerkleHash,
+    key: &[u8],
+) -> Result<StateProof, String> {
+    let backend = TrieBackend::new(state_db as Arc<Storage<CryptoHasher>>, root);
+    let (value, nodes) =
+        prove_read(backend, key).map_err(|e| format!("Failed to generate proof: {}", e))?;
+    Ok(StateProof { root, key: key.to_vec(), value, nodes })
+}
+
+/// Recomputes the path to `key` from the proof nodes and returns the proven value.
+pub fn verify_state_proof(
+    root: MerkleHash,
+    key: &[u8],
+    nodes: Vec<Vec<u8>>,
+) -> Result<Option<Vec<u8>>, String> {
+    read_proof_check::<CryptoHasher>(root, nodes, key)
+        .map_err(|e| format!("Invalid proof: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::create_state_db;
+    use {DBValue, StateDbUpdate};
+
+    fn create_state() -> (Arc<StateDb>, MerkleHash) {
+        let state_db = Arc::new(create_state_db());
+        let mut state_db_update = StateDbUpdate::new(state_db.clone(), MerkleHash::default());
+        state_db_update.set(b"dog", &DBValue::from_slice(b"puppy"));
+        state_db_update.set(b"dog2", &DBValue::from_slice(b"puppy2"));
+        state_db_update.set(b"cat", &DBValue::from_slice(b"kitten"));
+        let (mut transaction, root) = state_db_update.finalize();
+        state_db.commit(&mut transaction).unwrap();
+        (state_db, root)
+    }
+
+    #[test]
+    fn test_inclusion_proof() {
+        let (state_db, root) = create_state();
+        let proof = prove_state(state_db, root, b"dog").unwrap();
+        assert_eq!(proof.value, Some(b"puppy".to_vec()));
+        assert!(proof.verify());
+    }
+
+    #[test]
+    fn test_exclusion_proof() {
+        let (state_db, root) = create_state();
+        let proof = prove_state(state_db, root, b"dog3").unwrap();
+        assert_eq!(proof.value, None);
+        assert!(proof.verify());
+    }
+
+    #[test]
+    fn test_tampered_proof() {
+        let (state_db, root) = create_state();
+        let mut proof = prove_state(state_db.clone(), root, b"dog").unwrap();
+        proof.value = Some(b"kitten".to_vec());
+        assert!(!proof.verify());
+
+        let mut proof = prove_state(state_db, root, b"dog").unwrap();
+        proof.nodes.pop();
+        assert!(!proof.verify());
+    }
+}
@@ -0,0 +1,269 @@
+//! Reference-counted garbage collection of state trie nodes.
+//!
+//! Every block commits its state in a new journal era that records its block number, its state
+//! transition, the trie nodes it inserted and the nodes of the parent state it stopped
+//! referencing. Trie nodes carry a persistent reference count in `COL_STATE_RC`, which only
+//! covers the canonicalized states and the state the chain starts from. Once a block is deep
+//! enough in the canonical chain, the eras of the blocks up to its number are canonicalized:
+//! insertions and removals of canonical blocks are counted and insertions of blocks on abandoned
+//! forks are dropped. Eras of later blocks stay in the journal, whichever fork they are on.
+//! Nodes whose reference count is zero and that are not inserted by a pending era are deleted
+//! from `COL_STATE`.
+use std::collections::{HashMap, HashSet};
+
+use kvdb::{DBTransaction, KeyValueDB};
+use primitives::hash::CryptoHash;
+use primitives::traits::{Decode, Encode};
+use primitives::types::MerkleHash;
+use primitives::utils::index_to_bytes;
+
+use {COL_EXTRA, COL_STATE, COL_STATE_JOURNAL, COL_STATE_RC};
+
+/// Key in `COL_EXTRA` under which the journal boundaries are stored.
+const STATE_JOURNAL_KEY: &[u8] = b"state_journal";
+/// Key in `COL_EXTRA` under which it's stored whether the reference counts are kept.
+const STATE_PRUNING_KEY: &[u8] = b"state_pruning";
+
+/// Defines which trie nodes are kept in the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PruningMode {
+    /// Never remove trie nodes. Every committed root stays readable.
+    Archive,
+    /// Keep only nodes reachable from the states of the given number of most recent blocks
+    /// of the canonical chain. Reorganizations deeper than that can't be applied.
+    KeepRecent(u64),
+}
+
+impl Default for PruningMode {
+    fn default() -> Self {
+        PruningMode::Archive
+    }
+}
+
+/// Roots of the state before and after a block.
+pub type StateTransition = (MerkleHash, MerkleHash);
+
+/// Boundaries of the journal: eras before `first_era` are canonicalized, eras in
+/// `[first_era, next_era)` may still be pending.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct JournalState {
+    pub first_era: u64,
+    pub next_era: u64,
+    /// Index of the next canonical block whose era is canonicalized.
+    pub canonical_index: u64,
+}
+
+/// State transition of a block with the nodes it inserted and stopped referencing.
+#[derive(Serialize, Deserialize, Default)]
+struct JournalRecord {
+    block_index: u64,
+    transition: StateTransition,
+    inserted: Vec<(CryptoHash, u32)>,
+    removed: Vec<(CryptoHash, u32)>,
+}
+
+/// Reference counts touched by a single write, layered on top of the database.
+struct RefCounts<'a> {
+    storage: &'a KeyValueDB,
+    changed: HashMap<CryptoHash, i64>,
+}
+
+impl<'a> RefCounts<'a> {
+    fn new(storage: &'a KeyValueDB) -> Self {
+        RefCounts { storage, changed: HashMap::new() }
+    }
+
+    fn get(&self, key: &CryptoHash) -> i64 {
+        if let Some(rc) = self.changed.get(key) {
+            return *rc;
+        }
+        match self.storage.get(COL_STATE_RC, key.as_ref()) {
+            Ok(Some(data)) => {
+                Decode::decode(data.as_ref()).expect("Failed to decode reference count")
+            }
+            Ok(None) => 0,
+            Err(e) => panic!("Failed to read reference count: {}", e),
+        }
+    }
+
+    fn add(&mut self, key: &CryptoHash, delta: i64) {
+        let rc = self.get(key) + delta;
+        self.changed.insert(*key, rc);
+    }
+
+    /// Writes the new counts. Nodes are deleted only if nothing references them anymore and
+    /// no pending era inserted them. A negative count means that the node was never counted,
+    /// so it's kept.
+    fn write_to(self, db_transaction: &mut DBTransaction, pending: &HashSet<CryptoHash>) {
+        for (key, rc) in self.changed {
+            if rc > 0 {
+                let data = Encode::encode(&rc).expect("Error serializing reference count");
+                db_transaction.put(COL_STATE_RC, key.as_ref(), &data);
+            } else {
+                db_transaction.delete(COL_STATE_RC, key.as_ref());
+                if rc == 0 && !pending.contains(&key) {
+                    db_transaction.delete(COL_STATE, key.as_ref());
+                }
+            }
+        }
+    }
+}
+
+fn read_record(storage: &KeyValueDB, era: u64) -> Result<Option<JournalRecord>, String> {
+    match storage.get(COL_STATE_JOURNAL, &index_to_bytes(era)) {
+        Ok(Some(data)) => {
+            Decode::decode(data.as_ref()).map(Some).ok_or_else(|| "Failed to decode journal".into())
+        }
+        Ok(None) => Ok(None),
+        Err(e) => Err(format!("Failed to read journal record {}: {}", era, e)),
+    }
+}
+
+/// Reads the journal boundaries. Eras are recorded in the transactions of blocks, which may
+/// be written out of order, so the next era is after the last recorded one.
+pub fn read_journal_state(storage: &KeyValueDB) -> JournalState {
+    let mut journal: JournalState = match storage.get(COL_EXTRA, STATE_JOURNAL_KEY) {
+        Ok(Some(data)) => Decode::decode(data.as_ref()).expect("Failed to decode state journal"),
+        _ => JournalState::default(),
+    };
+    for (key, _) in storage.iter(COL_STATE_JOURNAL) {
+        let era: u64 = Decode::decode(key.as_ref()).expect("Failed to decode journal era");
+        journal.next_era = journal.next_era.max(era + 1);
+    }
+    journal
+}
+
+/// Checks that the database was always opened with the same kind of pruning mode. Nodes of
+/// an archive are not counted and would be deleted by the pruning while still in use, so a
+/// database that has state can't change between archive and pruned.
+pub fn check_pruning_mode(storage: &KeyValueDB, pruning: PruningMode) -> Result<(), String> {
+    let is_pruned = pruning != PruningMode::Archive;
+    let stored: Option<bool> = match storage.get(COL_EXTRA, STATE_PRUNING_KEY) {
+        Ok(Some(data)) => {
+            Some(Decode::decode(data.as_ref()).ok_or("Failed to decode pruning mode")?)
+        }
+        Ok(None) => None,
+        Err(e) => return Err(format!("Failed to read pruning mode: {}", e)),
+    };
+    let was_pruned = match stored {
+        Some(was_pruned) => was_pruned,
+        None if storage.iter(COL_STATE).next().is_some() => false,
+        None => {
+            let mut db_transaction = storage.transaction();
+            let data = is_pruned.encode().ok_or("Error serializing pruning mode")?;
+            db_transaction.put(COL_EXTRA, STATE_PRUNING_KEY, &data);
+            return storage
+                .write(db_transaction)
+                .map_err(|e| format!("Database write failed: {}", e));
+        }
+    };
+    if was_pruned != is_pruned {
+        return Err(format!(
+            "State is {} and can't be opened {}, import a state snapshot into a new database",
+            if was_pruned { "pruned" } else { "archived" },
+            if is_pruned { "with pruning" } else { "as archive" },
+        ));
+    }
+    Ok(())
+}
+
+/// Counts the inserted and removed nodes right away, for the states which are not journaled.
+pub fn count_nodes(
+    storage: &KeyValueDB,
+    db_transaction: &mut DBTransaction,
+    inserted: &[(CryptoHash, u32)],
+    removed: &[(CryptoHash, u32)],
+) {
+    let mut ref_counts = RefCounts::new(storage);
+    for (key, rc) in inserted.iter() {
+        ref_counts.add(key, i64::from(*rc));
+    }
+    for (key, rc) in removed.iter() {
+        ref_counts.add(key, -i64::from(*rc));
+    }
+    ref_counts.write_to(db_transaction, &HashSet::new());
+}
+
+/// Records the state transition of the block with given number in a new era. Nothing is
+/// counted until it's canonicalized.
+pub fn journal_commit(
+    db_transaction: &mut DBTransaction,
+    journal: &mut JournalState,
+    block_index: u64,
+    transition: StateTransition,
+    inserted: Vec<(CryptoHash, u32)>,
+    removed: Vec<(CryptoHash, u32)>,
+) {
+    let record = JournalRecord { block_index, transition, inserted, removed };
+    let data = Encode::encode(&record).expect("Error serializing journal record");
+    db_transaction.put(COL_STATE_JOURNAL, &index_to_bytes(journal.next_era), &data);
+    journal.next_era += 1;
+}
+
+/// Canonicalizes the eras of the blocks up to the last given transition. `canonical` are the
+/// transitions of the canonical blocks starting from `first_index`. Other eras of blocks with
+/// these numbers belong to abandoned forks, eras of later blocks are kept.
+pub fn canonicalize(
+    storage: &KeyValueDB,
+    db_transaction: &mut DBTransaction,
+    journal: &mut JournalState,
+    first_index: u64,
+    canonical: &[StateTransition],
+) -> Result<(), String> {
+    if first_index < journal.canonical_index {
+        return Err(format!("State of block {} is already pruned", first_index));
+    }
+    let end_index = first_index + canonical.len() as u64;
+    let mut records = vec![];
+    let mut counted = vec![false; canonical.len()];
+    let mut pending = HashSet::new();
+    let mut first_pending_era = None;
+    for era in journal.first_era..journal.next_era {
+        let record = match read_record(storage, era)? {
+            Some(record) => record,
+            None => continue,
+        };
+        if record.block_index >= end_index {
+            pending.extend(record.inserted.iter().map(|(key, _)| *key));
+            first_pending_era = first_pending_era.or(Some(era));
+            continue;
+        }
+        // Blocks with the same transition have the same nodes, only one of them counts.
+        let is_canonical = match record.block_index.checked_sub(first_index) {
+            Some(offset) => {
+                let offset = offset as usize;
+                let is_canonical = !counted[offset] && record.transition == canonical[offset];
+                counted[offset] |= is_canonical;
+                is_canonical
+            }
+            None => false,
+        };
+        records.push((record, is_canonical));
+        db_transaction.delete(COL_STATE_JOURNAL, &index_to_bytes(era));
+    }
+    if let Some(offset) = counted.iter().position(|counted| !counted) {
+        return Err(format!("State transition {:?} is not journaled", canonical[offset]));
+    }
+
+    let mut ref_counts = RefCounts::new(storage);
+    for (record, is_canonical) in records {
+        if is_canonical {
+            for (key, rc) in record.inserted.iter() {
+                ref_counts.add(key, i64::from(*rc));
+            }
+            for (key, rc) in record.removed.iter() {
+                ref_counts.add(key, -i64::from(*rc));
+            }
+        } else {
+            for (key, _) in record.inserted.iter() {
+                ref_counts.add(key, 0);
+            }
+        }
+    }
+    ref_counts.write_to(db_transaction, &pending);
+    journal.first_era = first_pending_era.unwrap_or(journal.next_era);
+    journal.canonical_index = end_index;
+    let data = journal.encode().ok_or("Error serializing state journal")?;
+    db_transaction.put(COL_EXTRA, STATE_JOURNAL_KEY, &data);
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! Export and import of the whole state trie under a given root.
+//!
+//! Snapshot layout: a length-prefixed `SnapshotHeader` followed by length-prefixed
+//! `SnapshotChunk`s until the end of the stream. Every chunk carries the hash of its key-value
+//! pairs, and the importer rebuilds the trie chunk by chunk, so neither side needs to hold the
+//! whole state in memory.
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use primitives::hash::{hash_struct, CryptoHash};
+use primitives::traits::{Decode, Encode};
+use primitives::types::MerkleHash;
+
+use substrate_storage::{Backend, CryptoHasher, Storage, TrieBackend};
+use {DBValue, StateDb, StateDbUpdate};
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+/// Default number of key-value pairs per chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024;
+/// Chunks larger than this are rejected by the importer.
+const MAX_ENCODED_LENGTH: u64 = 1 << 30;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    pub version: u32,
+    /// State root that the snapshot reproduces.
+    pub root: MerkleHash,
+    /// Opaque data supplied by the exporter, e.g. the block the state belongs to.
+    pub metadata: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotChunk {
+    pub pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Hash of the encoded `pairs`.
+    pub hash: CryptoHash,
+}
+
+impl SnapshotChunk {
+    fn new(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Self {
+        let hash = hash_struct(&pairs);
+        SnapshotChunk { pairs, hash }
+    }
+
+    fn is_valid(&self) -> bool {
+        hash_struct(&self.pairs) == self.hash
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_item<W: Write, T: Encode>(writer: &mut W, item: &T) -> io::Result<()> {
+    let data = item.encode().ok_or_else(|| invalid_data("Failed to encode item".to_string()))?;
+    writer.write_u64::<LittleEndian>(data.len() as u64)?;
+    writer.write_all(&data)
+}
+
+/// Reads next item, returns `None` at the end of the stream.
+fn read_item<R: Read, T: Decode>(reader: &mut R) -> io::Result<Option<T>> {
+    let len = match reader.read_u64::<LittleEndian>() {
+        Ok(len) => len,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > MAX_ENCODED_LENGTH {
+        return Err(invalid_data(format!("Snapshot item of {} bytes is too large", len)));
+    }
+    let mut data = vec![0; len as usize];
+    reader.read_exact(&mut data)?;
+    Decode::decode(&data)
+        .map(Some)
+        .ok_or_else(|| invalid_data("Failed to decode snapshot item".to_string()))
+}
+
+/// Writes all key-value pairs under `root` into `writer`. Returns number of written chunks.
+pub fn export_snapshot<W: Write>(
+    state_db: Arc<StateDb>,
+    root: MerkleHash,
+    metadata: Vec<u8>,
+    chunk_size: usize,
+    writer: &mut W,
+) -> io::Result<u64> {
+    let header = SnapshotHeader { version: SNAPSHOT_VERSION, root, metadata };
+    write_item(writer, &header)?;
+
+    let backend = TrieBackend::new(state_db as Arc<Storage<CryptoHasher>>, root);
+    let mut pairs = vec![];
+    let mut num_chunks = 0;
+    let mut result = Ok(());
+    backend.for_keys_with_prefix(&[], |key| {
+        if result.is_err() {
+            return;
+        }
+        match backend.storage(key) {
+            Ok(Some(value)) => pairs.push((key.to_vec(), value)),
+            Ok(None) => return,
+            Err(e) => {
+                result = Err(invalid_data(format!("Failed to read state: {}", e)));
+                return;
+            }
+        }
+        if pairs.len() >= chunk_size {
+            let chunk = SnapshotChunk::new(pairs.split_off(0));
+            result = write_item(writer, &chunk);
+            num_chunks += 1;
+        }
+    });
+    result?;
+    if !pairs.is_empty() {
+        write_item(writer, &SnapshotChunk::new(pairs))?;
+        num_chunks += 1;
+    }
+    writer.flush()?;
+    Ok(num_chunks)
+}
+
+/// Rebuilds the state from the snapshot and commits it into `state_db`.
+/// Fails if any chunk is corrupted or the rebuilt root differs from the one in the header.
+pub fn import_snapshot<R: Read>(
+    state_db: Arc<StateDb>,
+    reader: &mut R,
+) -> io::Result<SnapshotHeader> {
+    let header: SnapshotHeader = read_item(reader)?
+        .ok_or_else(|| invalid_data("Snapshot header is missing".to_string()))?;
+    if header.version != SNAPSHOT_VERSION {
+        return Err(invalid_data(format!("Unsupported snapshot version {}", header.version)));
+    }
+
+    let mut root = MerkleHash::default();
+    let mut chunk_index = 0;
+    while let Some(chunk) = read_item::<_, SnapshotChunk>(reader)? {
+        if !chunk.is_valid() {
+            return Err(invalid_data(format!("Snapshot chunk {} is corrupted", chunk_index)));
+        }
+        let mut state_db_update = StateDbUpdate::new(state_db.clone(), root);
+        for (key, value) in chunk.pairs.iter() {
+            state_db_update.set(key, &DBValue::from_slice(value));
+        }
+        let (mut transaction, new_root) = state_db_update.finalize();
+        state_db.commit(&mut transaction)?;
+        root = new_root;
+        chunk_index += 1;
+    }
+    if root != header.root {
+        return Err(invalid_data(format!(
+            "Imported state root {} doesn't match snapshot root {}",
+            root, header.root
+        )));
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::create_state_db;
+
+    fn create_state(num_keys: u32) -> (Arc<StateDb>, MerkleHash) {
+        let state_db = Arc::new(create_state_db());
+        let mut state_db_update = StateDbUpdate::new(state_db.clone(), MerkleHash::default());
+        for i in 0..num_keys {
+            let key = format!("key{}", i);
+            state_db_update.set(key.as_bytes(), &DBValue::from_slice(&i.encode().unwrap()));
+        }
+        let (mut transaction, root) = state_db_update.finalize();
+        state_db.commit(&mut transaction).unwrap();
+        (state_db, root)
+    }
+
+    #[test]
+    fn test_export_import() {
+        let (state_db, root) = create_state(100);
+        let mut data = vec![];
+        let num_chunks = export_snapshot(state_db, root, b"meta".to_vec(), 30, &mut data).unwrap();
+        assert_eq!(num_chunks, 4);
+
+        let new_state_db = Arc::new(create_state_db());
+        let header = import_snapshot(new_state_db.clone(), &mut data.as_slice()).unwrap();
+        assert_eq!(header.root, root);
+        assert_eq!(header.metadata, b"meta".to_vec());
+        let state_db_update = StateDbUpdate::new(new_state_db, root);
+        assert_eq!(
+            state_db_update.get(b"key42").unwrap(),
+            DBValue::from_slice(&42u32.encode().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_import_corrupted() {
+        let (state_db, root) = create_state(10);
+        let mut data = vec![];
+        export_snapshot(state_db, root, vec![], DEFAULT_CHUNK_SIZE, &mut data).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        let new_state_db = Arc::new(create_state_db());
+        assert!(import_snapshot(new_state_db, &mut data.as_slice()).is_err());
+    }
+}
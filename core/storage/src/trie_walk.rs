@@ -0,0 +1,188 @@
+//! Lazy walks over the nodes of a state trie. A node is loaded from the storage only when the
+//! walk reaches it, so iterating over a range only touches the nodes on the way to its keys.
+use std::sync::Arc;
+
+use primitives::hash::CryptoHash;
+use primitives::types::MerkleHash;
+use substrate_trie::NodeCodec;
+use trie_db::node::Node;
+use trie_db::{NibbleSlice, NodeCodec as NodeCodecT};
+
+use substrate_storage::{CryptoHasher, Storage};
+use {DBValue, StateDb};
+
+type Codec = NodeCodec<CryptoHasher>;
+
+/// Reference to a node from its parent: the hash of the node, or the node itself if it's small
+/// enough to be inlined into the parent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeRef {
+    Hash(CryptoHash),
+    Inline(Vec<u8>),
+}
+
+impl NodeRef {
+    fn from_data(data: &[u8]) -> Self {
+        match Codec::try_decode_hash(data) {
+            Some(hash) => NodeRef::Hash(hash),
+            None => NodeRef::Inline(data.to_vec()),
+        }
+    }
+}
+
+/// Decoded trie node. Partial keys are given in nibbles.
+pub enum TrieNode {
+    Empty,
+    Leaf(Vec<u8>, DBValue),
+    Extension(Vec<u8>, NodeRef),
+    /// Children by their first nibble, and the value of the key that ends at the branch.
+    Branch(Vec<Option<NodeRef>>, Option<DBValue>),
+}
+
+fn to_nibbles(slice: &NibbleSlice) -> Vec<u8> {
+    (0..slice.len()).map(|i| slice.at(i)).collect()
+}
+
+pub fn key_to_nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter().flat_map(|byte| vec![byte >> 4, byte & 0x0f]).collect()
+}
+
+pub fn nibbles_to_key(nibbles: &[u8]) -> Vec<u8> {
+    nibbles.chunks(2).map(|pair| (pair[0] << 4) | pair.get(1).cloned().unwrap_or(0)).collect()
+}
+
+fn join_nibbles(prefix: &[u8], partial: &[u8]) -> Vec<u8> {
+    let mut nibbles = prefix.to_vec();
+    nibbles.extend_from_slice(partial);
+    nibbles
+}
+
+/// Loads the node from the storage. Fails if the node is missing, e.g. if its state was pruned.
+pub fn load_node(state_db: &StateDb, node_ref: &NodeRef) -> Result<TrieNode, String> {
+    let data = match node_ref {
+        NodeRef::Hash(hash) => {
+            state_db.get(hash)?.ok_or_else(|| format!("Trie node {:?} is missing", hash))?
+        }
+        NodeRef::Inline(data) => DBValue::from_slice(data),
+    };
+    let node = Codec::decode(&data).map_err(|e| format!("Failed to decode trie node: {:?}", e))?;
+    Ok(match node {
+        Node::Empty => TrieNode::Empty,
+        Node::Leaf(partial, value) => {
+            TrieNode::Leaf(to_nibbles(&partial), DBValue::from_slice(value))
+        }
+        Node::Extension(partial, child) => {
+            TrieNode::Extension(to_nibbles(&partial), NodeRef::from_data(child))
+        }
+        Node::Branch(children, value) => TrieNode::Branch(
+            children
+                .iter()
+                .map(|child| if child.is_empty() { None } else { Some(NodeRef::from_data(child)) })
+                .collect(),
+            value.map(DBValue::from_slice),
+        ),
+    })
+}
+
+/// Pending part of the walk, with the nibbles of the key leading to it.
+enum Step {
+    Node(Vec<u8>, NodeRef),
+    Value(Vec<u8>, DBValue),
+}
+
+/// Walk over the values of the trie with keys in `[start, end)`, in the order of keys or in the
+/// reverse order. Subtrees outside of the range are skipped without loading them.
+pub struct TrieWalk {
+    state_db: Arc<StateDb>,
+    stack: Vec<Step>,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    reverse: bool,
+}
+
+impl TrieWalk {
+    pub fn new(
+        state_db: Arc<StateDb>,
+        root: MerkleHash,
+        start: &[u8],
+        end: Option<&[u8]>,
+        reverse: bool,
+    ) -> Self {
+        TrieWalk {
+            state_db,
+            stack: vec![Step::Node(vec![], NodeRef::Hash(root))],
+            start: key_to_nibbles(start),
+            end: end.map(key_to_nibbles),
+            reverse,
+        }
+    }
+
+    /// Whether the subtree under the given nibbles can have keys in the range.
+    fn may_contain(&self, prefix: &[u8]) -> bool {
+        let len = prefix.len().min(self.start.len());
+        prefix >= &self.start[..len] && self.end.as_ref().map_or(true, |end| prefix < &end[..])
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        key >= &self.start[..] && self.end.as_ref().map_or(true, |end| key < &end[..])
+    }
+
+    fn push_node(&mut self, prefix: Vec<u8>, node: TrieNode) {
+        match node {
+            TrieNode::Empty => {}
+            TrieNode::Leaf(partial, value) => {
+                self.stack.push(Step::Value(join_nibbles(&prefix, &partial), value))
+            }
+            TrieNode::Extension(partial, child) => {
+                self.stack.push(Step::Node(join_nibbles(&prefix, &partial), child))
+            }
+            TrieNode::Branch(children, value) => {
+                let value = value.map(|value| Step::Value(prefix.clone(), value));
+                let children: Vec<_> = children
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(i, child)| {
+                        child.map(|child| Step::Node(join_nibbles(&prefix, &[i as u8]), child))
+                    })
+                    .collect();
+                // The stack is popped from the end, the value of the branch goes first.
+                if self.reverse {
+                    self.stack.extend(value);
+                    self.stack.extend(children);
+                } else {
+                    self.stack.extend(children.into_iter().rev());
+                    self.stack.extend(value);
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for TrieWalk {
+    type Item = Result<(Vec<u8>, DBValue), String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(step) = self.stack.pop() {
+            match step {
+                Step::Value(key, value) => {
+                    if self.contains(&key) {
+                        return Some(Ok((nibbles_to_key(&key), value)));
+                    }
+                }
+                Step::Node(prefix, node_ref) => {
+                    if !self.may_contain(&prefix) {
+                        continue;
+                    }
+                    match load_node(&self.state_db, &node_ref) {
+                        Ok(node) => self.push_node(prefix, node),
+                        Err(e) => {
+                            self.stack.clear();
+                            return Some(Err(e));
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
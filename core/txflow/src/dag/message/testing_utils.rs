@@ -36,6 +36,7 @@ where
         owner_uid,
         parents: (&parents).into_iter().map(|m| m.computed_hash).collect(),
         epoch,
+        seq: 0,
         payload: FakePayload {},
         endorsements: vec![],
     };
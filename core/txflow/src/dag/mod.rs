@@ -2,11 +2,13 @@ mod message;
 mod reporter;
 
 use primitives::signature::DEFAULT_SIGNATURE;
-use primitives::traits::{Payload, WitnessSelector};
+use primitives::traits::{Encode, Payload, Signer, WitnessSelector};
 use primitives::types::*;
 
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::Arc;
 
 use self::message::Message;
 pub use self::reporter::{
@@ -35,6 +37,12 @@ pub struct DAG<
     /// Store last message from each participant in the DAG.
     /// In case of a fork only one is stored arbitrarely.
     recent_message: HashMap<UID, &'a Message<'a, P>>,
+    /// Messages of each participant by their number. In case of a fork the first one is stored.
+    numbered_messages: HashMap<(UID, u64), &'a Message<'a, P>>,
+    /// Number of the next message created by the current owner.
+    next_seq: u64,
+    /// Signs the messages created by the current owner.
+    signer: Arc<Signer>,
     /// All epochs that were already published.
     published_epochs: HashSet<u64>,
     /// All messages that were ever published.
@@ -47,13 +55,21 @@ pub struct DAG<
 }
 
 impl<'a, P: 'a + Payload, W: WitnessSelector, M: 'a + MisbehaviorReporter> DAG<'a, P, W, M> {
-    pub fn new(owner_uid: UID, starting_epoch: u64, witness_selector: &'a W) -> Self {
+    pub fn new(
+        owner_uid: UID,
+        starting_epoch: u64,
+        witness_selector: &'a W,
+        signer: Arc<Signer>,
+    ) -> Self {
         DAG {
             owner_uid,
             arena: Arena::new(),
             messages: HashSet::new(),
             roots: HashSet::new(),
             recent_message: HashMap::new(),
+            numbered_messages: HashMap::new(),
+            next_seq: 0,
+            signer,
             published_epochs: HashSet::new(),
             published_messages: HashSet::new(),
             witness_selector,
@@ -108,15 +124,34 @@ impl<'a, P: 'a + Payload, W: WitnessSelector, M: 'a + MisbehaviorReporter> DAG<'
     /// (related to the number of messages).
     ///
     /// Example: (A0 -> A2 -> A4 -> ... and A1 -> A3 -> A5 -> ...)
+    ///
+    /// Every message approves the previous message of its owner, so a message that doesn't
+    /// approve the head has the number of an earlier message. This pair is reported, because
+    /// the two signed messages prove the fork on their own.
     fn detect_fork(&self, message: &Message<'a, P>) -> Option<(TxFlowHash, TxFlowHash)> {
-        match self.recent_message.get(&message.data.body.owner_uid) {
+        let body = &message.data.body;
+        match self.recent_message.get(&body.owner_uid) {
             Some(head) if !message.approve(head) => {
-                Some((head.computed_hash, message.computed_hash))
+                let other =
+                    self.numbered_messages.get(&(body.owner_uid, body.seq)).unwrap_or(head);
+                Some((other.computed_hash, message.computed_hash))
             }
             _ => None,
         }
     }
 
+    /// Checks that the message approves the previous message of its owner.
+    fn follows_previous(&self, message: &Message<'a, P>) -> bool {
+        let body = &message.data.body;
+        if body.seq == 0 {
+            return true;
+        }
+        match self.numbered_messages.get(&(body.owner_uid, body.seq - 1)) {
+            Some(previous) => message.approve(previous),
+            None => false,
+        }
+    }
+
     // Does inefficient DFS collecting parents from under the given representative.
     fn collect_parents(&mut self, message: &'a Message<'a, P>, parents: &mut Vec<&'a Message<'a, P>>) {
         if self.published_messages.contains(message) {
@@ -160,6 +195,11 @@ impl<'a, P: 'a + Payload, W: WitnessSelector, M: 'a + MisbehaviorReporter> DAG<'
     /// Verify correctness of this message regarding txflow protocol.
     /// Report all misbehavior as soon as they are detected.
     fn verify_message(&mut self, message: &Message<'a, P>) -> Result<(), &'static str> {
+        // Check the number
+        if !self.follows_previous(message) {
+            return Err("Message doesn't approve the previous message of its owner");
+        }
+
         // Check epoch
         if message.computed_epoch != message.data.body.epoch {
             let mb = ViolationType::BadEpoch(message.computed_hash);
@@ -210,10 +250,12 @@ impl<'a, P: 'a + Payload, W: WitnessSelector, M: 'a + MisbehaviorReporter> DAG<'
         }
 
         let owner = message.data.body.owner_uid;
+        let seq = message.data.body.seq;
         let message_ptr = self.arena.alloc(message).as_ref() as *const Message<'a, P>;
         self.messages.insert(unsafe { &*message_ptr });
         self.roots.insert(unsafe { &*message_ptr });
         self.recent_message.insert(owner, unsafe { &*message_ptr });
+        self.numbered_messages.entry((owner, seq)).or_insert(unsafe { &*message_ptr });
 
         // Compute consensuses enabled by this message.
         let consensuses = self.publishable_to_consensus(unsafe { &*message_ptr });
@@ -234,6 +276,7 @@ impl<'a, P: 'a + Payload, W: WitnessSelector, M: 'a + MisbehaviorReporter> DAG<'
                 owner_uid: self.owner_uid,
                 parents: (&self.roots).iter().map(|m| m.computed_hash).collect(),
                 epoch: 0, // Will be computed later.
+                seq: self.next_seq,
                 payload,
                 endorsements,
             },
@@ -241,10 +284,13 @@ impl<'a, P: 'a + Payload, W: WitnessSelector, M: 'a + MisbehaviorReporter> DAG<'
         message.parents = self.roots.clone();
         message.init(true, false, self.starting_epoch, self.witness_selector);
         message.assume_computed_hash_epoch();
+        message.data.owner_sig = self.signer.sign(&message.data.body.signed_hash());
 
         // Finally, take ownership of the new root.
         let message_ptr = self.arena.alloc(message).as_ref() as *const Message<'a, P>;
         self.messages.insert(unsafe { &*message_ptr });
+        self.numbered_messages.insert((self.owner_uid, self.next_seq), unsafe { &*message_ptr });
+        self.next_seq += 1;
         self.roots.clear();
         self.roots.insert(unsafe { &*message_ptr });
 
@@ -254,14 +300,42 @@ impl<'a, P: 'a + Payload, W: WitnessSelector, M: 'a + MisbehaviorReporter> DAG<'
     }
 }
 
+impl<'a, P: 'a + Payload, W: WitnessSelector> DAG<'a, P, W, DAGMisbehaviorReporter> {
+    /// Takes the violations reported so far and returns the evidence of the forks among them,
+    /// with the owners of the messages.
+    pub fn take_fork_evidence(&self) -> Vec<(UID, Misbehavior)> {
+        let violations = mem::replace(&mut self.misbehavior.borrow_mut().violations, vec![]);
+        let evidence = |hash: TxFlowHash| {
+            let data = &self.messages.get(&hash)?.data;
+            Some(SignedEvidence { data: data.body.encode()?, signature: data.owner_sig.clone() })
+        };
+        violations
+            .into_iter()
+            .filter_map(|violation| match violation {
+                ViolationType::ForkAttempt(first, second) => {
+                    let owner_uid = self.messages.get(&first)?.data.body.owner_uid;
+                    let misbehavior = Misbehavior::TxFlowFork(evidence(first)?, evidence(second)?);
+                    Some((owner_uid, misbehavior))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use primitives::signer::InMemorySigner;
     use primitives::types::UID;
     use std::collections::{HashMap, HashSet};
     use typed_arena::Arena;
 
+    fn signer() -> Arc<Signer> {
+        Arc::new(InMemorySigner::default())
+    }
+
     struct FakeWitnessSelector {
         schedule: HashMap<u64, HashSet<UID>>,
     }
@@ -293,7 +367,7 @@ mod tests {
         let selector = FakeWitnessSelector::new();
         let data_arena = Arena::new();
         let mut all_messages = vec![];
-        let mut dag: DAG<_, _, DAGMisbehaviorReporter> = DAG::new(0, 0, &selector);
+        let mut dag: DAG<_, _, DAGMisbehaviorReporter> = DAG::new(0, 0, &selector, signer());
 
         // Parent have greater epoch than children
         let (a, b);
@@ -326,7 +400,7 @@ mod tests {
         let selector = FakeWitnessSelector::new();
         let data_arena = Arena::new();
         let mut all_messages = vec![];
-        let mut dag: DAG<_, _, DAGMisbehaviorReporter> = DAG::new(0, 0, &selector);
+        let mut dag: DAG<_, _, DAGMisbehaviorReporter> = DAG::new(0, 0, &selector, signer());
 
         let (a, b);
         simple_bare_messages!(data_arena, all_messages [[0, 0; 1, 0; 3, 0;] => 0, 1 => a;]);
@@ -350,7 +424,7 @@ mod tests {
         let selector = FakeWitnessSelector::new();
         let data_arena = Arena::new();
         let mut all_messages = vec![];
-        let mut dag: DAG<_, _> = DAG::new(0, 0, &selector);
+        let mut dag: DAG<_, _> = DAG::new(0, 0, &selector, signer());
         let (a, b);
         simple_bare_messages!(data_arena, all_messages [[0, 0 => a; 1, 2;] => 2, 3 => b;]);
         simple_bare_messages!(data_arena, all_messages [[=> a; 3, 4;] => 4, 5;]);
@@ -367,7 +441,7 @@ mod tests {
         let selector = FakeWitnessSelector::new();
         let data_arena = Arena::new();
         let mut all_messages = vec![];
-        let mut dag: DAG<_, _> = DAG::new(0, 0, &selector);
+        let mut dag: DAG<_, _> = DAG::new(0, 0, &selector, signer());
         let (a, b, c, d, e);
         simple_bare_messages!(data_arena, all_messages [[0, 0 => a; 1, 2 => b;] => 2, 3 => c;]);
         simple_bare_messages!(data_arena, all_messages [[=> a; 3, 4 => d;] => 4, 5 => e;]);
@@ -395,7 +469,7 @@ mod tests {
         let selector = FakeWitnessSelector::new();
         let data_arena = Arena::new();
         let mut all_messages = vec![];
-        let mut dag: DAG<_, _> = DAG::new(0, 0, &selector);
+        let mut dag: DAG<_, _> = DAG::new(0, 0, &selector, signer());
         let (a, b, c, d, e);
         simple_bare_messages!(data_arena, all_messages [[0, 0 => a; 1, 2 => b;] => 2, 3 => c;]);
 
@@ -418,7 +492,7 @@ mod tests {
     fn movable() {
         let selector = FakeWitnessSelector::new();
         let data_arena = Arena::new();
-        let mut dag: DAG<_, _> = DAG::new(0, 0, &selector);
+        let mut dag: DAG<_, _> = DAG::new(0, 0, &selector, signer());
         let (a, b);
         // Add some messages.
         {
@@ -441,13 +515,12 @@ mod tests {
         }
     }
 
-    /// Unfinished test
     #[test]
     fn notice_simple_fork() {
         let selector = FakeWitnessSelector::new();
         let data_arena = Arena::new();
         let mut all_messages = vec![];
-        let mut dag: DAG<_, _, DAGMisbehaviorReporter> = DAG::new(0, 0, &selector);
+        let mut dag: DAG<_, _, DAGMisbehaviorReporter> = DAG::new(0, 0, &selector, signer());
 
         let a;
 
@@ -458,6 +531,9 @@ mod tests {
             assert!(dag.add_existing_message((*m).clone()).is_ok());
         }
 
-        println!("Violations: {}", &dag.misbehavior.borrow().violations.len());
+        let forks = dag.take_fork_evidence();
+        assert_eq!(forks.len(), 1);
+        assert_eq!(forks[0].0, 3);
+        assert!(dag.take_fork_evidence().is_empty());
     }
 }
@@ -48,5 +48,7 @@ pub enum ViolationType {
     /// Someone pretending being another participant maybe.
     InvalidSignature(TxFlowHash),
     /// Two messages from the same participant that are not approved by each other.
+    /// Messages with the same parents or the same number are evidence for
+    /// `Misbehavior::TxFlowFork`.
     ForkAttempt(TxFlowHash, TxFlowHash),
 }
@@ -24,6 +24,7 @@ pub fn simple_bare_message(
         owner_uid,
         parents: parents.into_iter().map(|m| m.hash).collect(),
         epoch,
+        seq: 0,
         payload: ::testing_utils::FakePayload {},
         endorsements: vec![],
     };
@@ -7,11 +7,13 @@ use futures::future::{join_all, lazy};
 use futures::sync::mpsc;
 use futures::{Future, Sink, Stream};
 use rand;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::timer::Delay;
 
-use primitives::traits::{Payload, WitnessSelector};
+use primitives::signer::InMemorySigner;
+use primitives::traits::{Payload, Signer, WitnessSelector};
 use primitives::types::GossipBody;
 
 /// Fake witness selector that does not rotate the witnesses.
@@ -89,6 +91,14 @@ pub fn spawn_all(num_witnesses: u64) {
         let mut inc_gossip_tx_vec = vec![];
         let mut inc_payload_tx_vec = vec![];
         let mut out_gossip_rx_vec = vec![];
+        let signers: Vec<Arc<Signer>> = (0..num_witnesses)
+            .map(|_| Arc::new(InMemorySigner::default()) as Arc<Signer>)
+            .collect();
+        let public_keys: HashMap<_, _> = signers
+            .iter()
+            .enumerate()
+            .map(|(owner_uid, signer)| (owner_uid as u64, signer.public_key()))
+            .collect();
 
         // Spawn tasks
         for owner_uid in 0..num_witnesses {
@@ -97,6 +107,7 @@ pub fn spawn_all(num_witnesses: u64) {
             let (out_gossip_tx, _out_gossip_rx) = mpsc::channel(1024);
             let (control_tx, control_rx) = mpsc::channel(1024);
             let (consensus_tx, _consensus_rx) = mpsc::channel(1024);
+            let (misbehavior_tx, _misbehavior_rx) = mpsc::channel(1024);
             let witness_selector = Box::new(FakeWitnessSelector::new(owner_uid, num_witnesses));
 
             inc_gossip_tx_vec.push(inc_gossip_tx);
@@ -119,6 +130,9 @@ pub fn spawn_all(num_witnesses: u64) {
                     starting_epoch,
                     gossip_size,
                     witness_selector,
+                    signer: signers[owner_uid as usize].clone(),
+                    public_keys: public_keys.clone(),
+                    misbehavior_sender: misbehavior_tx,
                 }))
                 .map(|_| ())
                 .map_err(|e| println!("Error sending control {}", e));
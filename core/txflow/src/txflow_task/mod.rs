@@ -1,17 +1,18 @@
 use std::borrow::{Borrow, BorrowMut};
 use std::collections::{HashMap, HashSet};
 use std::mem;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use futures::sync::mpsc;
 use futures::{stream, Async, Future, Poll, Sink, Stream};
 use tokio::timer::Delay;
 
-use dag::DAG;
-use primitives::signature::DEFAULT_SIGNATURE;
-use primitives::traits::{Payload, WitnessSelector};
+use dag::{DAGMisbehaviorReporter, DAG};
+use primitives::signature::{verify_signature, PublicKey, DEFAULT_SIGNATURE};
+use primitives::traits::{Payload, Signer, WitnessSelector};
 use primitives::types::{
-    ConsensusBlockBody, Gossip, GossipBody, SignedMessageData, TxFlowHash, UID,
+    ConsensusBlockBody, Gossip, GossipBody, Misbehavior, SignedMessageData, TxFlowHash, UID,
 };
 
 pub mod beacon_witness_selector;
@@ -29,6 +30,12 @@ pub struct State<W: WitnessSelector> {
     /// The size of the random sample of witnesses that we draw every time we gossip.
     pub gossip_size: usize,
     pub witness_selector: Box<W>,
+    /// Signs the messages of the owner.
+    pub signer: Arc<Signer>,
+    /// Keys of the witnesses, messages that are not signed by their owner are dropped.
+    pub public_keys: HashMap<UID, PublicKey>,
+    /// Receives the evidence of the forks created by the witnesses.
+    pub misbehavior_sender: mpsc::Sender<(UID, Misbehavior)>,
 }
 
 /// An enum that we use to start and stop the TxFlow task.
@@ -64,7 +71,7 @@ pub fn spawn_task<
 /// and consensuses. Currently produces only stream of gossips, TODO stream of consensuses.
 pub struct TxFlowTask<'a, P: 'a + Payload, W: WitnessSelector> {
     state: Option<State<W>>,
-    dag: Option<Box<DAG<'a, P, W>>>,
+    dag: Option<Box<DAG<'a, P, W, DAGMisbehaviorReporter>>>,
     messages_receiver: mpsc::Receiver<Gossip<P>>,
     payload_receiver: mpsc::Receiver<P>,
     messages_sender: mpsc::Sender<Gossip<P>>,
@@ -125,13 +132,13 @@ impl<'a, P: Payload, W: WitnessSelector> TxFlowTask<'a, P, W> {
 
     /// Mutable reference to the DAG.
     #[inline]
-    fn dag_as_mut(&mut self) -> &mut DAG<'a, P, W> {
+    fn dag_as_mut(&mut self) -> &mut DAG<'a, P, W, DAGMisbehaviorReporter> {
         self.dag.as_mut().expect(UNINITIALIZED_DAG_ERR).borrow_mut()
     }
 
     /// Immutable reference to the DAG.
     #[inline]
-    fn dag_as_ref(&self) -> &DAG<'a, P, W> {
+    fn dag_as_ref(&self) -> &DAG<'a, P, W, DAGMisbehaviorReporter> {
         self.dag.as_ref().expect(UNINITIALIZED_DAG_ERR).borrow()
     }
 
@@ -163,6 +170,30 @@ impl<'a, P: Payload, W: WitnessSelector> TxFlowTask<'a, P, W> {
         }));
     }
 
+    /// Sends the evidence of the forks found by the DAG so far.
+    fn send_fork_evidence(&self) {
+        let forks = self.dag_as_ref().take_fork_evidence();
+        if forks.is_empty() {
+            return;
+        }
+        let state = self.state.as_ref().expect(UNINITIALIZED_STATE_ERR);
+        let copied_tx = state.misbehavior_sender.clone();
+        tokio::spawn(copied_tx.send_all(stream::iter_ok(forks)).map(|_| ()).map_err(|e| {
+            error!("Failure in the sub-task {:?}", e);
+        }));
+    }
+
+    /// Whether the message is signed by its owner.
+    fn is_signed(&self, message: &SignedMessageData<P>) -> bool {
+        let state = self.state.as_ref().expect(UNINITIALIZED_STATE_ERR);
+        match state.public_keys.get(&message.body.owner_uid) {
+            Some(public_key) => {
+                verify_signature(&message.owner_sig, &message.body.signed_hash(), public_key)
+            }
+            None => false,
+        }
+    }
+
     fn send_consensuses(&self, consensuses: Vec<ConsensusBlockBody<P>>) {
         let copied_tx = self.consensus_sender.clone();
         tokio::spawn(copied_tx.send_all(stream::iter_ok(consensuses)).map(|_| ()).map_err(|e| {
@@ -177,8 +208,13 @@ impl<'a, P: Payload, W: WitnessSelector> TxFlowTask<'a, P, W> {
         let hash = message.hash;
         match self.dag_as_mut().add_existing_message(message) {
             Ok(consensuses) => self.send_consensuses(consensuses),
-            Err(e) => panic!("Attempted to add invalid message to the DAG {}", e),
+            Err(e) => {
+                // Messages that were blocked by this one stay blocked.
+                warn!("Dropped invalid message {}: {}", hash, e);
+                return;
+            }
         };
+        self.send_fork_evidence();
         // Get messages that were blocked by this one.
         // Also start removing it from the collections `pending_messages` and `unknown_hashes` that
         // keep track of the blockers.
@@ -226,6 +262,10 @@ impl<'a, P: Payload, W: WitnessSelector> TxFlowTask<'a, P, W> {
         {
             return;
         }
+        if !self.is_signed(&message) {
+            warn!("Message {} is not signed by its owner {}", message.hash, message.body.owner_uid);
+            return;
+        }
 
         let unknown_hashes: HashSet<TxFlowHash> = (&message.body.parents)
             .iter()
@@ -296,9 +336,13 @@ impl<'a, P: Payload, W: WitnessSelector> TxFlowTask<'a, P, W> {
         let witness_ptr = self.witness_selector() as *const W;
         // Since we are controlling the creation of the DAG by encapsulating it here
         // this code is safe.
-        self.dag = Some(Box::new(DAG::new(self.owner_uid(), self.starting_epoch(), unsafe {
-            &*witness_ptr
-        })));
+        let signer = self.state.as_ref().expect(UNINITIALIZED_STATE_ERR).signer.clone();
+        self.dag = Some(Box::new(DAG::new(
+            self.owner_uid(),
+            self.starting_epoch(),
+            unsafe { &*witness_ptr },
+            signer,
+        )));
     }
 }
 
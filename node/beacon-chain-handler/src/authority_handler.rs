@@ -1,28 +1,82 @@
-use beacon::authority::{Authority, SelectedAuthority};
-use beacon::types::SignedBeaconBlock;
+use beacon::authority::{epoch_of_block, Authority, SelectedAuthority};
+use beacon::finality::{self, BlockVote, VotePool};
+use beacon::randomness::HashChain;
+use beacon::slashing::{find_equivocations, verify_misbehavior};
+use beacon::types::{BeaconBlockChain, SignedBeaconBlock, SignedBeaconBlockHeader};
 use chain::{SignedBlock, SignedHeader};
 use futures::sync::mpsc::{Receiver, Sender};
 use futures::{Future, Sink, Stream};
-use primitives::types::{AccountId, UID};
-use std::collections::HashMap;
+use node_runtime::state_viewer::StateDbViewer;
+use parking_lot::RwLock;
+use primitives::hash::{hash_struct, CryptoHash};
+use primitives::traits::Signer;
+use primitives::types::{
+    AccountId, Misbehavior, RevealRandomnessTransaction, SignedTransaction, SlashTransaction,
+    TransactionBody, UID,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use txflow::txflow_task::beacon_witness_selector::BeaconWitnessSelector;
 use txflow::txflow_task::{Control, State};
 
+/// Input of the authority task: a block that was imported or produced, a vote for a block
+/// received from the network, or evidence of a fork found by TxFlow.
+enum AuthorityEvent {
+    Block(SignedBeaconBlock),
+    Vote(BlockVote),
+    Fork(UID, Misbehavior),
+}
+
 pub fn spawn_authority_task(
     mut authority_handler: AuthorityHandler,
     new_block_rx: Receiver<SignedBeaconBlock>,
+    vote_rx: Receiver<BlockVote>,
+    misbehavior_rx: Receiver<(UID, Misbehavior)>,
     authority_tx: Sender<HashMap<UID, SelectedAuthority>>,
     control_tx: Sender<Control<BeaconWitnessSelector>>,
 ) {
     let task = new_block_rx
-        .map(move |block| {
+        .map(AuthorityEvent::Block)
+        .select(vote_rx.map(AuthorityEvent::Vote))
+        .select(misbehavior_rx.map(|(owner_uid, misbehavior)| {
+            AuthorityEvent::Fork(owner_uid, misbehavior)
+        }))
+        .filter_map(move |event| {
+            let block = match event {
+                AuthorityEvent::Block(block) => block,
+                AuthorityEvent::Vote(vote) => {
+                    authority_handler.process_vote(vote);
+                    return None;
+                }
+                AuthorityEvent::Fork(owner_uid, evidence) => {
+                    authority_handler.report_fork(owner_uid, evidence);
+                    return None;
+                }
+            };
             let index = block.header().index();
-            authority_handler.authority.process_block_header(&block.header());
-            // get authorities for the next block
-            let next_authorities =
-                authority_handler.authority.get_authorities(index + 1).unwrap_or_else(|_| {
+            authority_handler.authority.write().process_block_header(&block.header());
+            authority_handler.report_equivocations(block.header());
+            authority_handler.vote(&block);
+            let (next_authorities, next_epoch) = {
+                let authority = authority_handler.authority.read();
+                match finality::process_block_header(
+                    &authority_handler.beacon_chain,
+                    &authority,
+                    &mut authority_handler.votes,
+                    &block.header(),
+                ) {
+                    Ok(true) => info!(target: "finality", "Block #{} is finalized", index),
+                    Ok(false) => (),
+                    Err(e) => {
+                        warn!(target: "finality", "Failed to finalize block #{}: {}", index, e)
+                    }
+                }
+                // get authorities for the next block
+                let next_authorities = authority.get_authorities(index + 1).unwrap_or_else(|_| {
                     panic!("failed to get authorities for block index {}", index + 1)
                 });
+                (next_authorities, epoch_of_block(index + 1, authority.epoch_length()))
+            };
 
             let mut uid_to_authority_map = HashMap::new();
             let mut owner_uid = None;
@@ -34,17 +88,26 @@ pub fn spawn_authority_task(
             }
 
             if let Some(owner_uid) = owner_uid {
+                authority_handler.reveal_randomness(next_epoch);
                 if !authority_handler.started {
                     authority_handler.started = true;
                     let witness_selector = Box::new(BeaconWitnessSelector::new(
                         uid_to_authority_map.keys().cloned().collect(),
                         owner_uid,
                     ));
+                    let public_keys = uid_to_authority_map
+                        .iter()
+                        .map(|(uid, authority)| (*uid, authority.public_key))
+                        .collect();
+                    authority_handler.txflow_authorities = uid_to_authority_map.clone();
                     let control = Control::Reset(State {
                         owner_uid,
                         starting_epoch: 0,
                         gossip_size: 1, // TODO: Use adaptive gossip size.
                         witness_selector,
+                        signer: authority_handler.signer.clone(),
+                        public_keys,
+                        misbehavior_sender: authority_handler.misbehavior_tx.clone(),
                     });
                     let start_task = control_tx
                         .clone()
@@ -62,7 +125,7 @@ pub fn spawn_authority_task(
                     .map_err(|err| error!("Error sending control to TxFlow {}", err));
                 tokio::spawn(stop_task);
             }
-            uid_to_authority_map
+            Some(uid_to_authority_map)
         })
         .forward(
             authority_tx.sink_map_err(|err| error!("Error sending payload down the sink: {}", err)),
@@ -72,14 +135,223 @@ pub fn spawn_authority_task(
     tokio::spawn(task);
 }
 pub struct AuthorityHandler {
-    authority: Authority,
+    /// Shared with the beacon chain, which weighs blocks by the stake of their signers.
+    authority: Arc<RwLock<Authority>>,
     account_id: AccountId,
+    beacon_chain: Arc<BeaconBlockChain>,
     /// whether the node has started consensus
     started: bool,
+    signer: Arc<Signer>,
+    /// Reads the nonce and the last revealed value of the account.
+    state_viewer: StateDbViewer,
+    /// Receives the `RevealRandomness` and `Slash` transactions of the account.
+    transactions_tx: Sender<SignedTransaction>,
+    hash_chain: HashChain,
+    /// Last epoch in which a value was revealed.
+    last_reveal_epoch: Option<u64>,
+    /// Nonce of the last submitted transaction, which may not be in the state yet.
+    last_nonce: Option<u64>,
+    /// Headers of the recent blocks by their parent, to find the ones signed on the same parent.
+    recent_headers: HashMap<CryptoHash, Vec<SignedBeaconBlockHeader>>,
+    /// Votes of the authorities for the blocks that are not final yet.
+    votes: VotePool,
+    /// Sends the votes of the node to the network.
+    vote_tx: Sender<BlockVote>,
+    /// Index of the last block the node voted for. The node votes once per index, voting
+    /// for two blocks on the same index would help both of them to be finalized.
+    last_vote_index: Option<u64>,
+    /// Authorities of the running TxFlow by their UID.
+    txflow_authorities: HashMap<UID, SelectedAuthority>,
+    /// Receives the evidence of the forks found by TxFlow.
+    misbehavior_tx: Sender<(UID, Misbehavior)>,
+    /// Authorities already reported for a TxFlow fork. A fork of one message is reported
+    /// again by every message that builds on it.
+    reported_forks: HashSet<AccountId>,
 }
 
 impl AuthorityHandler {
-    pub fn new(authority: Authority, account_id: AccountId) -> Self {
-        AuthorityHandler { authority, account_id, started: false }
+    pub fn new(
+        authority: Arc<RwLock<Authority>>,
+        beacon_chain: Arc<BeaconBlockChain>,
+        signer: Arc<Signer>,
+        state_viewer: StateDbViewer,
+        transactions_tx: Sender<SignedTransaction>,
+        vote_tx: Sender<BlockVote>,
+        misbehavior_tx: Sender<(UID, Misbehavior)>,
+    ) -> Self {
+        AuthorityHandler {
+            authority,
+            account_id: signer.account_id(),
+            beacon_chain,
+            started: false,
+            hash_chain: HashChain::for_signer(signer.as_ref()),
+            signer,
+            state_viewer,
+            transactions_tx,
+            last_reveal_epoch: None,
+            last_nonce: None,
+            recent_headers: HashMap::new(),
+            votes: VotePool::new(),
+            vote_tx,
+            last_vote_index: None,
+            txflow_authorities: HashMap::new(),
+            misbehavior_tx,
+            reported_forks: HashSet::new(),
+        }
+    }
+
+    /// Co-signs the block if the node holds a seat in its slot and broadcasts the vote.
+    fn vote(&mut self, block: &SignedBeaconBlock) {
+        let index = block.header().index();
+        if self.last_vote_index.map_or(false, |last_index| last_index >= index) {
+            return;
+        }
+        let authorities = self.authority.read().get_authorities(index).unwrap_or_default();
+        if !authorities.iter().any(|authority| authority.account_id == self.account_id) {
+            return;
+        }
+        let vote = BlockVote::new(index, block.hash, self.signer.as_ref());
+        if let Err(e) = self.votes.add_vote(&self.authority.read(), vote.clone()) {
+            warn!(target: "finality", "Failed to vote for block #{}: {}", index, e);
+            return;
+        }
+        self.last_vote_index = Some(index);
+        let vote_task = self
+            .vote_tx
+            .clone()
+            .send(vote)
+            .map(|_| ())
+            .map_err(|err| error!("Error sending vote {}", err));
+        tokio::spawn(vote_task);
+    }
+
+    /// Counts the vote received from the network and finalizes its block if it's complete.
+    fn process_vote(&mut self, vote: BlockVote) {
+        let index = vote.index;
+        let authority = self.authority.read();
+        match finality::process_vote(&self.beacon_chain, &authority, &mut self.votes, vote) {
+            Ok(true) => info!(target: "finality", "Block #{} is finalized", index),
+            Ok(false) => (),
+            Err(e) => debug!(target: "finality", "Ignoring vote for block #{}: {}", index, e),
+        }
+    }
+
+    /// Signs the transaction with the next nonce of the account and submits it.
+    fn submit_transaction<F>(&mut self, make_body: F) -> Result<(), String>
+    where
+        F: FnOnce(u64) -> TransactionBody,
+    {
+        let root = self.state_viewer.get_root();
+        let account = self.state_viewer.view_account_at(&self.account_id, root)?;
+        let nonce = self.last_nonce.map_or(account.nonce, |nonce| nonce.max(account.nonce)) + 1;
+        let body = make_body(nonce);
+        let signature = self.signer.sign(&hash_struct(&body));
+        let submit_task = self
+            .transactions_tx
+            .clone()
+            .send(SignedTransaction::new(signature, body))
+            .map(|_| ())
+            .map_err(|err| error!("Error submitting transaction {}", err));
+        tokio::spawn(submit_task);
+        self.last_nonce = Some(nonce);
+        Ok(())
+    }
+
+    /// Compares the header with the other headers on the same parent and submits a `Slash`
+    /// transaction for every authority of the slot that signed both.
+    fn report_equivocations(&mut self, header: SignedBeaconBlockHeader) {
+        let index = header.index();
+        let epoch_length = self.authority.read().epoch_length();
+        // Siblings arrive close to each other, so headers older than an epoch are dropped.
+        self.recent_headers.retain(|_, headers| headers[0].index() + epoch_length > index);
+        let authorities = self.authority.read().get_authorities(index).unwrap_or_default();
+        let mut offenses = vec![];
+        {
+            let siblings =
+                self.recent_headers.entry(header.body.parent_hash).or_insert_with(Vec::new);
+            if siblings.iter().any(|sibling| sibling.hash == header.hash) {
+                return;
+            }
+            let mut reported = HashSet::new();
+            for sibling in siblings.iter() {
+                for (offender, evidence) in find_equivocations(&authorities, sibling, &header) {
+                    if reported.insert(offender.clone()) {
+                        offenses.push((offender, evidence));
+                    }
+                }
+            }
+            siblings.push(header);
+        }
+        for (offender, evidence) in offenses {
+            warn!(target: "slashing", "{} signed two blocks #{}", offender, index);
+            let originator = self.account_id.clone();
+            let submitted = self.submit_transaction(|nonce| {
+                TransactionBody::Slash(SlashTransaction { nonce, originator, offender, evidence })
+            });
+            if let Err(e) = submitted {
+                warn!(target: "slashing", "Failed to report equivocation: {}", e);
+            }
+        }
+    }
+
+    /// Submits a `Slash` transaction for the owner of the forked TxFlow messages, once the
+    /// evidence checks against its key.
+    fn report_fork(&mut self, owner_uid: UID, evidence: Misbehavior) {
+        let offender = match self.txflow_authorities.get(&owner_uid) {
+            Some(authority) => match verify_misbehavior(&evidence, &authority.public_key) {
+                Ok(()) => authority.account_id.clone(),
+                Err(e) => {
+                    debug!(target: "slashing", "Ignoring fork of witness {}: {}", owner_uid, e);
+                    return;
+                }
+            },
+            None => return,
+        };
+        if !self.reported_forks.insert(offender.clone()) {
+            return;
+        }
+        warn!(target: "slashing", "{} forked TxFlow", offender);
+        let originator = self.account_id.clone();
+        let submitted = self.submit_transaction(|nonce| {
+            TransactionBody::Slash(SlashTransaction { nonce, originator, offender, evidence })
+        });
+        if let Err(e) = submitted {
+            warn!(target: "slashing", "Failed to report fork: {}", e);
+        }
+    }
+
+    /// Submits the next value of the hash chain for the randomness beacon, once per epoch in
+    /// which the node holds a seat. The first transaction commits to the tip of the chain.
+    fn reveal_randomness(&mut self, epoch: u64) {
+        if self.last_reveal_epoch.map_or(false, |last_epoch| last_epoch >= epoch) {
+            return;
+        }
+        let root = self.state_viewer.get_root();
+        let value = match self.state_viewer.get_random_commitment_at(&self.account_id, root) {
+            None => self.hash_chain.commitment(),
+            Some(last_value) => match self.hash_chain.next_reveal(&last_value) {
+                Some(value) => value,
+                None => {
+                    warn!(
+                        target: "randomness",
+                        "Hash chain of {} is used up or doesn't match its commitment",
+                        self.account_id
+                    );
+                    return;
+                }
+            },
+        };
+        let originator = self.account_id.clone();
+        let submitted = self.submit_transaction(|nonce| {
+            TransactionBody::RevealRandomness(RevealRandomnessTransaction {
+                nonce,
+                originator,
+                value,
+            })
+        });
+        match submitted {
+            Ok(()) => self.last_reveal_epoch = Some(epoch),
+            Err(e) => warn!(target: "randomness", "Failed to reveal randomness: {}", e),
+        }
     }
 }
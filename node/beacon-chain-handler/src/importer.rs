@@ -7,18 +7,21 @@ use futures::{Future, future, Stream, Sink};
 use futures::sync::mpsc::{Receiver, Sender};
 use parking_lot::RwLock;
 
+use beacon::authority::Authority;
 use beacon::types::{SignedBeaconBlock, BeaconBlockChain};
+use chain::orphan_pool::{OrphanPool, DEFAULT_MAX_ORPHANS};
 use chain::SignedBlock;
 use node_runtime::{ApplyState, Runtime};
 use primitives::hash::CryptoHash;
 use primitives::types::BlockId;
 use shard::{SignedShardBlock, ShardBlockChain};
-use storage::StateDb;
+use storage::{DBTransaction, StateDb};
 
 pub fn spawn_block_importer(
     beacon_chain: Arc<BeaconBlockChain>,
     shard_chain: Arc<ShardBlockChain>,
     runtime: Arc<RwLock<Runtime>>,
+    authority: Arc<RwLock<Authority>>,
     state_db: Arc<StateDb>,
     receiver: Receiver<SignedBeaconBlock>,
     new_block_tx: Sender<SignedBeaconBlock>,
@@ -27,6 +30,7 @@ pub fn spawn_block_importer(
         beacon_chain,
         shard_chain,
         runtime,
+        authority,
         state_db,
         new_block_tx,
     );
@@ -41,9 +45,11 @@ pub struct BlockImporter {
     beacon_chain: Arc<BeaconBlockChain>,
     shard_chain: Arc<ShardBlockChain>,
     runtime: Arc<RwLock<Runtime>>,
+    /// Maps the signatures of the parent block to the seats, which are rewarded for them.
+    authority: Arc<RwLock<Authority>>,
     state_db: Arc<StateDb>,
-    /// Stores blocks that cannot be added yet.
-    pending_beacon_blocks: HashMap<CryptoHash, SignedBeaconBlock>,
+    /// Blocks whose parent is not added yet. They are executed once the parent is added.
+    orphans: OrphanPool<SignedBeaconBlock, ()>,
     pending_shard_blocks: HashMap<CryptoHash, SignedShardBlock>,
     new_block_tx: Sender<SignedBeaconBlock>,
 }
@@ -53,6 +59,7 @@ impl BlockImporter {
         beacon_chain: Arc<BeaconBlockChain>,
         shard_chain: Arc<ShardBlockChain>,
         runtime: Arc<RwLock<Runtime>>,
+        authority: Arc<RwLock<Authority>>,
         state_db: Arc<StateDb>,
         new_block_tx: Sender<SignedBeaconBlock>,
     ) -> Self {
@@ -60,8 +67,9 @@ impl BlockImporter {
             beacon_chain,
             shard_chain,
             runtime,
+            authority,
             state_db,
-            pending_beacon_blocks: HashMap::new(),
+            orphans: OrphanPool::new(DEFAULT_MAX_ORPHANS),
             pending_shard_blocks: HashMap::new(),
             new_block_tx,
         }
@@ -77,7 +85,27 @@ impl BlockImporter {
         self.pending_shard_blocks.entry(hash).or_insert(shard_block);
     }
 
-    fn add_block(&self, beacon_block: SignedBeaconBlock, shard_block: SignedShardBlock) {
+    /// Executes the block on top of its parent and adds it if it's valid. Returns whether the
+    /// block was added.
+    fn add_block(&mut self, beacon_block: SignedBeaconBlock) -> bool {
+        let shard_block_hash = beacon_block.body.header.shard_block_hash;
+        let pending_shard_block = self.pending_shard_blocks.remove(&shard_block_hash);
+        // The state of a stored shard block is stored too, only the beacon block is written.
+        let shard_block_stored = pending_shard_block.is_none();
+        let shard_block = match pending_shard_block {
+            Some(shard_block) => shard_block,
+            None => match self.shard_chain.get_block(&BlockId::Hash(shard_block_hash)) {
+                Some(shard_block) => shard_block,
+                None => {
+                    info!(
+                        "Shard block {:?} of beacon block {:?} is not known",
+                        shard_block_hash,
+                        beacon_block.block_hash()
+                    );
+                    return false;
+                }
+            },
+        };
         let parent_hash = beacon_block.body.header.parent_hash;
         let parent_shard_hash = shard_block.body.header.parent_hash;
         // we can unwrap because parent is guaranteed to exist
@@ -90,9 +118,14 @@ impl BlockImporter {
         let prev_shard_header = prev_shard_block.header();
         let apply_state = ApplyState {
             root: prev_shard_header.body.merkle_root_state,
-            block_index: prev_header.body.index,
+            block_index: prev_header.body.index + 1,
             parent_block_hash: parent_hash,
             shard_id: shard_block.body.header.shard_id,
+            participation: self.authority.read().get_participation(
+                prev_header.body.index,
+                &prev_header.hash,
+                &prev_header.signature,
+            ),
         };
         let apply_result = self.runtime.write().check(
             &apply_state,
@@ -100,78 +133,105 @@ impl BlockImporter {
             &shard_block.body.transactions
         );
         match apply_result {
-            Some((mut db_transaction, root)) => {
-                if root != prev_shard_header.body.merkle_root_state {
+            Some((mut db_transaction, root, random_reveals, slashed_authorities)) => {
+                if root != shard_block.body.header.merkle_root_state {
                     info!(
                         "Merkle root {} is not equal to received {} after applying the transactions from {:?}",
-                        prev_shard_header.body.merkle_root_state,
                         root,
+                        shard_block.body.header.merkle_root_state,
+                        beacon_block
+                    );
+                    return false;
+                }
+                if random_reveals != beacon_block.body.header.random_reveals {
+                    info!(
+                        "Random reveals {:?} are not equal to received {:?} in block {:?}",
+                        random_reveals,
+                        beacon_block.body.header.random_reveals,
+                        beacon_block
+                    );
+                    return false;
+                }
+                if slashed_authorities != beacon_block.body.header.slashed_authorities {
+                    info!(
+                        "Slashed authorities {:?} are not equal to received {:?} in block {:?}",
+                        slashed_authorities,
+                        beacon_block.body.header.slashed_authorities,
+                        beacon_block
+                    );
+                    return false;
+                }
+                let authority_set_hash = self.authority
+                    .read()
+                    .get_authority_set_hash(beacon_block.body.header.index);
+                if authority_set_hash != Ok(beacon_block.body.header.authority_set_hash) {
+                    info!(
+                        "Authority set hash {:?} is not equal to received {:?} in block {:?}",
+                        authority_set_hash,
+                        beacon_block.body.header.authority_set_hash,
                         beacon_block
                     );
-                    return;
+                    return false;
                 }
-                self.state_db.commit(&mut db_transaction).ok();
-                self.shard_chain.insert_block(shard_block);
-                self.beacon_chain.insert_block(beacon_block);
+                if shard_block_stored {
+                    self.beacon_chain.insert_block(beacon_block);
+                } else {
+                    let mut state_transaction = DBTransaction::new();
+                    self.state_db.commit_to(
+                        shard_block.body.header.index,
+                        (apply_state.root, root),
+                        &mut db_transaction,
+                        &mut state_transaction,
+                    );
+                    if let Err(e) = self.beacon_chain.insert_block_pair(
+                        beacon_block,
+                        &self.shard_chain,
+                        shard_block,
+                        state_transaction,
+                    ) {
+                        warn!("Failed to store the block: {}", e);
+                        return false;
+                    }
+                }
+                if let Err(e) = shard::prune_state(&self.shard_chain, &self.state_db) {
+                    warn!("Failed to prune the state: {}", e);
+                }
+                true
             }
             None => {
                 info!(
                     "Found incorrect transaction in block {:?}",
                     beacon_block
                 );
-                return;
-            }
-        }
-    }
-
-    fn blocks_to_process(&mut self) -> (Vec<SignedBeaconBlock>, HashMap<CryptoHash, SignedBeaconBlock>) {
-        let mut part_add = vec![];
-        let mut part_pending = HashMap::default();
-        for (hash, other) in self.pending_beacon_blocks.drain() {
-            if self.beacon_chain.is_known(&other.body.header.parent_hash) && (
-                self.shard_chain.is_known(&other.body.header.shard_block_hash) ||
-                    self.pending_shard_blocks.contains_key(&other.body.header.shard_block_hash)) {
-                part_add.push(other);
-            } else {
-                part_pending.insert(hash, other);
+                false
             }
         }
-        (part_add, part_pending)
     }
 
     pub fn import_beacon_block(&mut self, beacon_block: SignedBeaconBlock) {
-        // Check if this block was either already added, or it is already pending, or it has
+        // Check if this block was either already added, or it is already waiting, or it has
         // invalid signature.
         let hash = beacon_block.block_hash();
-        if self.beacon_chain.is_known(&hash)
-            || self.pending_beacon_blocks.contains_key(&hash)
+        if self.beacon_chain.has_block(&hash)
+            || self.orphans.contains(&hash)
             || !self.validate_signature(&beacon_block) {
             return
         }
-        self.pending_beacon_blocks.insert(hash, beacon_block);
-
-        let mut blocks_to_add: Vec<SignedBeaconBlock> = vec![];
-
-        // Loop until we run out of blocks to add.
-        loop {
-            // Only keep those blocks in `pending_blocks` that are still pending.
-            // Otherwise put it in `blocks_to_add`.
-            let (part_add, part_pending) = self.blocks_to_process();
-            blocks_to_add.extend(part_add);
-            self.pending_beacon_blocks = part_pending;
+        // Wait for the parent and ask peers for the earliest missing ancestor.
+        let parent_hash = beacon_block.body.header.parent_hash;
+        if !self.beacon_chain.has_block(&parent_hash) {
+            if self.orphans.insert(beacon_block, ()) {
+                let missing_hash = self.orphans.missing_ancestor(&parent_hash);
+                self.beacon_chain.request_missing_block(missing_hash);
+            }
+            return;
+        }
 
-            // Get the next block to add, unless there are no more blocks left.
-            let next_beacon_block = match blocks_to_add.pop() {
-                Some(b) => b,
-                None => break,
-            };
+        // Add the block, then the orphans that were waiting for it and their descendants.
+        // Descendants of an invalid block are dropped.
+        let mut blocks_to_add = vec![beacon_block];
+        while let Some(next_beacon_block) = blocks_to_add.pop() {
             let hash = next_beacon_block.block_hash();
-            if self.beacon_chain.is_known(&hash) { continue; }
-
-            let next_shard_block = self.pending_shard_blocks
-                .remove(&next_beacon_block.body.header.shard_block_hash)
-                .expect("Expected to have shard block present when processing beacon block");
-
             tokio::spawn({
                 let block_tx = self.new_block_tx.clone();
                 block_tx
@@ -181,7 +241,11 @@ impl BlockImporter {
                         error!("failed to send new block: {:?}", e);
                     })
             });
-            self.add_block(next_beacon_block, next_shard_block);
+            let is_added = self.add_block(next_beacon_block);
+            let children = self.orphans.remove_children(&hash);
+            if is_added {
+                blocks_to_add.extend(children.into_iter().map(|(block, _)| block));
+            }
         }
     }
 }
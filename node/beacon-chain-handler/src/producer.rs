@@ -1,45 +1,71 @@
 //! ConsensusHandler consumes consensuses, retrieves the most recent state, computes the new
-//! state, signs it and puts in on the BeaconChain.
+//! state, signs it and puts in on the BeaconChain. It also follows reorganizations of the
+//! BeaconChain, so that transactions of the abandoned branch are not lost.
+use std::collections::HashSet;
+use std::mem;
 use std::sync::Arc;
 
 use futures::{Future, future, Stream, Sink};
 use futures::sync::mpsc::{Sender, Receiver};
 use parking_lot::RwLock;
 
+use beacon::authority::Authority;
 use beacon::types::{SignedBeaconBlock, BeaconBlockChain};
-use chain::SignedBlock;
+use chain::{ChainReorg, SignedBlock};
 use node_runtime::{ApplyState, Runtime};
 use primitives::traits::Signer;
 use primitives::types::BlockId;
-use primitives::types::{ConsensusBlockBody, ChainPayload};
+use primitives::types::{
+    ConsensusBlockBody, ChainPayload, ReceiptTransaction, SignedTransaction, Transaction,
+};
 use shard::{SignedShardBlock, ShardBlockChain};
-use storage::StateDb;
+use storage::{DBTransaction, StateDb};
 use std::io;
 use std::io::prelude::*;
 
 pub type ChainConsensusBlockBody = ConsensusBlockBody<ChainPayload>;
 
+/// Inputs of the producer, processed one at a time.
+enum ProducerEvent {
+    Consensus(ChainConsensusBlockBody),
+    Reorg(ChainReorg<SignedBeaconBlock>),
+}
+
 pub fn spawn_block_producer(
     beacon_chain: Arc<BeaconBlockChain>,
     shard_chain: Arc<ShardBlockChain>,
     runtime: Arc<RwLock<Runtime>>,
+    authority: Arc<RwLock<Authority>>,
     signer: Arc<Signer>,
     state_db: Arc<StateDb>,
     receiver: Receiver<ChainConsensusBlockBody>,
     block_announce_tx: Sender<SignedBeaconBlock>,
     new_block_tx: Sender<SignedBeaconBlock>,
+    transactions_tx: Sender<SignedTransaction>,
+    receipts_tx: Sender<ReceiptTransaction>,
 ) {
+    // Reorgs caused by both produced and imported blocks are handled here.
+    let reorgs = beacon_chain.subscribe_reorgs();
     let beacon_block_producer = BlockProducer::new(
         beacon_chain,
         shard_chain,
         runtime,
+        authority,
         signer,
         state_db,
         block_announce_tx,
         new_block_tx,
+        transactions_tx,
+        receipts_tx,
     );
-    let task = receiver.fold(beacon_block_producer, |beacon_block_producer, body| {
-        beacon_block_producer.produce_block(body);
+    let events = receiver
+        .map(ProducerEvent::Consensus)
+        .select(reorgs.map(ProducerEvent::Reorg));
+    let task = events.fold(beacon_block_producer, |beacon_block_producer, event| {
+        match event {
+            ProducerEvent::Consensus(body) => beacon_block_producer.produce_block(body),
+            ProducerEvent::Reorg(reorg) => beacon_block_producer.handle_reorg(reorg),
+        }
         future::ok(beacon_block_producer)
     }).and_then(|_| Ok(()));
     tokio::spawn(task);
@@ -49,10 +75,14 @@ pub struct BlockProducer {
     beacon_chain: Arc<BeaconBlockChain>,
     shard_chain: Arc<ShardBlockChain>,
     runtime: Arc<RwLock<Runtime>>,
+    /// Maps the signatures of the parent block to the seats, which are rewarded for them.
+    authority: Arc<RwLock<Authority>>,
     signer: Arc<Signer>,
     state_db: Arc<StateDb>,
     block_announce_tx: Sender<SignedBeaconBlock>,
     new_block_tx: Sender<SignedBeaconBlock>,
+    transactions_tx: Sender<SignedTransaction>,
+    receipts_tx: Sender<ReceiptTransaction>,
 }
 
 impl BlockProducer {
@@ -60,24 +90,95 @@ impl BlockProducer {
         beacon_chain: Arc<BeaconBlockChain>,
         shard_chain: Arc<ShardBlockChain>,
         runtime: Arc<RwLock<Runtime>>,
+        authority: Arc<RwLock<Authority>>,
         signer: Arc<Signer>,
         state_db: Arc<StateDb>,
         block_announce_tx: Sender<SignedBeaconBlock>,
         new_block_tx: Sender<SignedBeaconBlock>,
+        transactions_tx: Sender<SignedTransaction>,
+        receipts_tx: Sender<ReceiptTransaction>,
     ) -> Self {
         Self {
             beacon_chain,
             shard_chain,
             runtime,
+            authority,
             signer,
             state_db,
             block_announce_tx,
-            new_block_tx
+            new_block_tx,
+            transactions_tx,
+            receipts_tx,
+        }
+    }
+
+    fn get_shard_blocks(&self, blocks: &[SignedBeaconBlock]) -> Vec<SignedShardBlock> {
+        blocks.iter()
+            .filter_map(|block| {
+                self.shard_chain.get_block(&BlockId::Hash(block.body.header.shard_block_hash))
+            })
+            .collect()
+    }
+
+    /// The producer keeps no state of its own: every block is produced on top of the state of
+    /// the best block at that moment, so after the reorg it follows the new best block.
+    /// Transactions and receipts that were included only in the retracted blocks are sent back
+    /// to be included again.
+    pub fn handle_reorg(&self, reorg: ChainReorg<SignedBeaconBlock>) {
+        let retracted = self.get_shard_blocks(&reorg.retracted);
+        let enacted = self.get_shard_blocks(&reorg.enacted);
+        if let (Some(old_best), Some(new_best)) = (retracted.first(), enacted.last()) {
+            info!(
+                target: "block_producer",
+                "Reorg: {} blocks retracted, {} enacted, best state root {} -> {}",
+                reorg.retracted.len(),
+                reorg.enacted.len(),
+                old_best.body.header.merkle_root_state,
+                new_best.body.header.merkle_root_state,
+            );
+        }
+        let included: HashSet<&Transaction> = enacted.iter()
+            .flat_map(|block| block.body.transactions.iter())
+            .collect();
+        for transaction in retracted.iter().flat_map(|block| block.body.transactions.iter()) {
+            if included.contains(transaction) {
+                continue;
+            }
+            match transaction.clone() {
+                Transaction::SignedTransaction(transaction) => {
+                    tokio::spawn({
+                        let transactions_tx = self.transactions_tx.clone();
+                        transactions_tx
+                            .send(transaction)
+                            .map(|_| ())
+                            .map_err(|e| error!("Error re-sending transaction: {:?}", e))
+                    });
+                }
+                Transaction::Receipt(receipt) => {
+                    tokio::spawn({
+                        let receipts_tx = self.receipts_tx.clone();
+                        receipts_tx
+                            .send(receipt)
+                            .map(|_| ())
+                            .map_err(|e| error!("Error re-sending receipt: {:?}", e))
+                    });
+                }
+            }
         }
     }
 
     pub fn produce_block(&self, body: ChainConsensusBlockBody) {
         // TODO: verify signature
+        // Seats of the authorities whose messages are part of the consensus. The messages are
+        // not verified, so the mask only decides the kickout, rewards go by the signatures.
+        let mut authority_mask = vec![];
+        for message in body.messages.iter() {
+            let uid = message.body.owner_uid as usize;
+            if authority_mask.len() <= uid {
+                authority_mask.resize(uid + 1, false);
+            }
+            authority_mask[uid] = true;
+        }
         let mut transactions = body.messages.into_iter()
             .flat_map(|message| message.body.payload.body)
             .collect();
@@ -92,6 +193,11 @@ impl BlockProducer {
             parent_block_hash: last_block.block_hash(),
             block_index: last_block.body.header.index + 1,
             shard_id,
+            participation: self.authority.read().get_participation(
+                last_block.body.header.index,
+                &last_block.hash,
+                &last_block.signature,
+            ),
         };
         loop {
             let mut apply_result = self.runtime.write().apply(
@@ -99,7 +205,14 @@ impl BlockProducer {
                 &last_shard_block.body.new_receipts,
                 transactions
             );
-            self.state_db.commit(&mut apply_result.transaction).ok();
+            // The state is written together with the beacon and the shard block.
+            let mut db_transaction = DBTransaction::new();
+            self.state_db.commit_to(
+                last_shard_block.body.header.index + 1,
+                (apply_state.root, apply_result.root),
+                &mut apply_result.transaction,
+                &mut db_transaction,
+            );
             let mut shard_block = SignedShardBlock::new(
                 shard_id,
                 last_shard_block.body.header.index + 1,
@@ -108,18 +221,37 @@ impl BlockProducer {
                 apply_result.filtered_transactions,
                 apply_result.new_receipts,
             );
-            let mut block = SignedBeaconBlock::new(
+            let authority_set_hash = self.authority
+                .read()
+                .get_authority_set_hash(last_block.body.header.index + 1)
+                .expect("Authorities of the next epoch should be known");
+            let mut block = SignedBeaconBlock::with_commitments(
                 last_block.body.header.index + 1,
                 last_block.block_hash(),
                 apply_result.authority_proposals,
-                shard_block.block_hash()
+                shard_block.block_hash(),
+                apply_result.random_reveals,
+                apply_result.slashed_authorities,
+                authority_set_hash,
             );
+            // Blocks with the receipts are not part of the consensus, nobody is marked in them.
+            block.authority_mask = mem::replace(&mut authority_mask, vec![]);
             let signature = shard_block.sign(&*self.signer);
             shard_block.add_signature(signature);
             let signature = block.sign(&*self.signer);
             block.add_signature(signature);
-            self.shard_chain.insert_block(shard_block.clone());
-            self.beacon_chain.insert_block(block.clone());
+            if let Err(e) = self.beacon_chain.insert_block_pair(
+                block.clone(),
+                &self.shard_chain,
+                shard_block.clone(),
+                db_transaction,
+            ) {
+                error!(target: "block_producer", "Failed to store the produced block: {}", e);
+                return;
+            }
+            if let Err(e) = shard::prune_state(&self.shard_chain, &self.state_db) {
+                warn!(target: "block_producer", "Failed to prune the state: {}", e);
+            }
             info!(target: "block_producer", "Block body: {:?}", block.body);
             info!(target: "block_producer", "Shard block body: {:?}", shard_block.body);
             io::stdout().flush().expect("Could not flush stdout");
@@ -147,6 +279,11 @@ impl BlockProducer {
                 shard_id,
                 parent_block_hash: shard_block.block_hash(),
                 block_index: shard_block.body.header.index + 1,
+                participation: self.authority.read().get_participation(
+                    block.body.header.index,
+                    &block.hash,
+                    &block.signature,
+                ),
             };
             transactions = vec![];
             last_shard_block = shard_block;
@@ -16,6 +16,8 @@ struct ChainSpecRef {
     genesis_wasm: Vec<u8>,
     beacon_chain_epoch_length: u64,
     beacon_chain_num_seats_per_slot: u64,
+    beacon_chain_lockup_epochs: u64,
+    inflation_rate_bps: u64,
     boot_nodes: Vec<String>,
 }
 
@@ -28,16 +30,20 @@ pub fn serialize_chain_spec(chain_spec: ChainSpec) -> String {
 }
 
 pub fn deserialize_chain_spec(config: &str) -> ChainSpec {
-    serde_json::from_str(config)
+    let chain_spec = serde_json::from_str(config)
         .map(|ChainSpecDeserializer(c)| c)
-        .expect("Error deserializing the chain spec.")
+        .expect("Error deserializing the chain spec.");
+    chain_spec.validate().expect("Invalid chain spec");
+    chain_spec
 }
 
 pub fn get_default_chain_spec() -> ChainSpec {
     let data = include_bytes!("../res/default_chain.json");
-    serde_json::from_slice(data)
+    let chain_spec = serde_json::from_slice(data)
         .map(|ChainSpecDeserializer(c)| c)
-        .expect("Error deserializing the default chain spec.")
+        .expect("Error deserializing the default chain spec.");
+    chain_spec.validate().expect("Invalid default chain spec");
+    chain_spec
 }
 
 pub fn read_or_default_chain_spec(chain_spec_path: &Option<PathBuf>) -> ChainSpec {
@@ -78,6 +84,8 @@ fn test_deserialize() {
         "genesis_wasm": [0,1],
         "beacon_chain_epoch_length": 10,
         "beacon_chain_num_seats_per_slot": 100,
+        "beacon_chain_lockup_epochs": 4,
+        "inflation_rate_bps": 10,
         "boot_nodes": [],
     });
     let spec = deserialize_chain_spec(&data.to_string());
@@ -86,3 +94,19 @@ fn test_deserialize() {
         ("alice".to_string(), "6fgp5mkRgsTWfd5UWw1VwHbNLLDYeLxrxw3jrkCeXNWq".to_string(), 50)
     );
 }
+
+#[test]
+#[should_panic(expected = "Invalid chain spec")]
+fn test_short_lockup_is_rejected() {
+    let data = json!({
+        "accounts": [["alice", "6fgp5mkRgsTWfd5UWw1VwHbNLLDYeLxrxw3jrkCeXNWq", 100]],
+        "initial_authorities": [("alice", "6fgp5mkRgsTWfd5UWw1VwHbNLLDYeLxrxw3jrkCeXNWq", 50)],
+        "genesis_wasm": [0,1],
+        "beacon_chain_epoch_length": 10,
+        "beacon_chain_num_seats_per_slot": 100,
+        "beacon_chain_lockup_epochs": 1,
+        "inflation_rate_bps": 10,
+        "boot_nodes": [],
+    });
+    deserialize_chain_spec(&data.to_string());
+}
@@ -27,9 +27,12 @@ use std::path::PathBuf;
 use std::str::FromStr;
 use txflow::txflow_task::beacon_witness_selector::BeaconWitnessSelector;
 use primitives::types::ChainPayload;
+use storage::{PruningMode, StorageBackend};
+use snapshot::SnapshotCommand;
 
 pub mod chain_spec;
 pub mod service;
+pub mod snapshot;
 
 pub fn get_service_config() -> service::ServiceConfig {
     let default_p2p_port = service::DEFAULT_P2P_PORT.to_string();
@@ -98,6 +101,37 @@ pub fn get_service_config() -> service::ServiceConfig {
                 ])
                 .default_value(&default_log_level)
                 .takes_value(true),
+        ).arg(
+            Arg::with_name("storage_backend")
+                .long("storage-backend")
+                .value_name("STORAGE_BACKEND")
+                .help("Set the database implementation used for the storage.")
+                .possible_values(&["rocksdb", "memory", "file"])
+                .default_value("rocksdb")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("state_pruning")
+                .long("state-pruning")
+                .value_name("STATE_PRUNING")
+                .help(
+                    "Specify the number of most recent blocks whose state is kept, \
+                     or \"archive\" to keep all states. Can't be changed for existing state.",
+                )
+                .default_value("archive")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("export_state_snapshot")
+                .long("export-state-snapshot")
+                .value_name("FILE")
+                .help("Export the state of the best shard block into the file and exit.")
+                .conflicts_with("import_state_snapshot")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("import_state_snapshot")
+                .long("import-state-snapshot")
+                .value_name("FILE")
+                .help("Import the state snapshot into a new storage and run from its block.")
+                .takes_value(true),
         ).arg(
             Arg::with_name("account_id")
             .value_name("ACCOUNT_ID")
@@ -159,6 +193,29 @@ pub fn get_service_config() -> service::ServiceConfig {
         .value_of("public_key")
         .map(String::from);
 
+    let storage_backend = matches
+        .value_of("storage_backend")
+        .map(StorageBackend::from_str)
+        .unwrap()
+        .unwrap();
+
+    let state_pruning = matches
+        .value_of("state_pruning")
+        .map(|x| match x {
+            "archive" => PruningMode::Archive,
+            x => PruningMode::KeepRecent(x.parse::<u64>().unwrap()),
+        })
+        .unwrap();
+
+    let snapshot_command = matches
+        .value_of("export_state_snapshot")
+        .map(|x| SnapshotCommand::Export(PathBuf::from(x)))
+        .or_else(|| {
+            matches
+                .value_of("import_state_snapshot")
+                .map(|x| SnapshotCommand::Import(PathBuf::from(x)))
+        });
+
     service::ServiceConfig {
         base_path,
         account_id,
@@ -167,6 +224,9 @@ pub fn get_service_config() -> service::ServiceConfig {
         log_level,
         p2p_port,
         rpc_port,
+        storage_backend,
+        state_pruning,
+        snapshot_command,
         boot_nodes,
         test_network_key_seed,
     }
@@ -13,6 +13,7 @@ use futures::sync::mpsc::{channel, Receiver, Sender};
 use parking_lot::{Mutex, RwLock};
 
 use beacon::authority::{Authority, SelectedAuthority};
+use beacon::finality::BlockVote;
 use beacon::types::{BeaconBlockChain, SignedBeaconBlock, SignedBeaconBlockHeader};
 use beacon_chain_handler;
 use beacon_chain_handler::authority_handler::{spawn_authority_task, AuthorityHandler};
@@ -29,7 +30,11 @@ use primitives::types::{
     AccountId, ChainPayload, Gossip, ReceiptTransaction, SignedTransaction, UID,
 };
 use shard::{ShardBlockChain, SignedShardBlock};
-use storage::{StateDb, Storage};
+use snapshot::{
+    clean_partial_import, export_state_snapshot, import_state_snapshot, read_chain_start,
+    SnapshotCommand,
+};
+use storage::{InstrumentedDB, KeyValueDB, PruningMode, StateDb, StorageBackend, COL_BLOCKS};
 use txflow::txflow_task::beacon_witness_selector::BeaconWitnessSelector;
 use txflow::txflow_task::Control;
 
@@ -37,14 +42,15 @@ const STORAGE_PATH: &str = "storage/db";
 const NETWORK_CONFIG_PATH: &str = "storage";
 const KEY_STORE_PATH: &str = "storage/keystore";
 
-fn get_storage(base_path: &Path) -> Arc<Storage> {
+fn get_storage(base_path: &Path, backend: StorageBackend) -> Arc<InstrumentedDB> {
     let mut storage_path = base_path.to_owned();
     storage_path.push(STORAGE_PATH);
     match fs::canonicalize(storage_path.clone()) {
         Ok(path) => info!("Opening storage database at {:?}", path),
         _ => info!("Could not resolve {:?} path", storage_path),
     };
-    Arc::new(storage::open_database(&storage_path.to_string_lossy()))
+    let storage = storage::open_storage(backend, &storage_path.to_string_lossy());
+    Arc::new(InstrumentedDB::new(storage))
 }
 
 fn spawn_rpc_server_task(
@@ -53,12 +59,20 @@ fn spawn_rpc_server_task(
     shard_chain: &Arc<ShardBlockChain>,
     state_db: Arc<StateDb>,
     beacon_chain: Arc<BeaconBlockChain>,
+    authority: Arc<RwLock<Authority>>,
+    storage: Arc<InstrumentedDB>,
 ) {
     let state_db_viewer = StateDbViewer::new(shard_chain.clone(), state_db);
     let rpc_port = rpc_port.unwrap_or(DEFAULT_P2P_PORT);
     let http_addr = Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), rpc_port));
-    let http_api =
-        HttpApi::new(state_db_viewer, transactions_tx, beacon_chain, shard_chain.clone());
+    let http_api = HttpApi::new(
+        state_db_viewer,
+        transactions_tx,
+        beacon_chain,
+        shard_chain.clone(),
+        authority,
+        storage,
+    );
     node_http::server::spawn_server(http_api, http_addr);
 }
 
@@ -76,6 +90,8 @@ fn spawn_network_tasks(
     out_gossip_rx: Receiver<Gossip<ChainPayload>>,
     beacon_block_rx: Receiver<SignedBeaconBlock>,
     authority_rx: Receiver<HashMap<UID, SelectedAuthority>>,
+    inc_vote_tx: Sender<BlockVote>,
+    out_vote_rx: Receiver<BlockVote>,
 ) {
     let (net_messages_tx, net_messages_rx) = channel(1024);
     let protocol_config = ProtocolConfig::new_with_default_id(account_id);
@@ -87,6 +103,7 @@ fn spawn_network_tasks(
         receipts_tx,
         net_messages_tx.clone(),
         inc_gossip_tx,
+        inc_vote_tx,
     );
     let mut network_config = network::service::NetworkConfiguration::new();
     let mut network_config_path = base_path.to_owned();
@@ -108,6 +125,7 @@ fn spawn_network_tasks(
         beacon_block_rx,
         authority_rx,
         out_gossip_rx,
+        out_vote_rx,
     );
 }
 
@@ -139,6 +157,13 @@ pub struct ServiceConfig {
     pub chain_spec_path: Option<PathBuf>,
     pub log_level: log::LevelFilter,
     pub rpc_port: u16,
+    /// Database implementation for the storage.
+    pub storage_backend: StorageBackend,
+    /// Which state roots are kept in the storage.
+    pub state_pruning: PruningMode,
+    /// Export the state snapshot instead of running the node, or import it and run the node
+    /// from its block.
+    pub snapshot_command: Option<SnapshotCommand>,
 
     // Network configuration
     pub p2p_port: u16,
@@ -155,6 +180,9 @@ impl Default for ServiceConfig {
             chain_spec_path: None,
             log_level: DEFAULT_LOG_LEVEL,
             rpc_port: DEFAULT_RPC_PORT,
+            storage_backend: StorageBackend::RocksDb,
+            state_pruning: PruningMode::Archive,
+            snapshot_command: None,
             p2p_port: DEFAULT_P2P_PORT,
             boot_nodes: vec![],
             test_network_key_seed: None,
@@ -176,7 +204,7 @@ where
         + 'static,
 {
     // Create shared-state objects.
-    let storage = get_storage(&config.base_path);
+    let storage = get_storage(&config.base_path, config.storage_backend);
     let chain_spec = chain_spec::read_or_default_chain_spec(&config.chain_spec_path);
     let boot_nodes = if chain_spec.boot_nodes.is_empty() {
         config.boot_nodes.clone()
@@ -189,19 +217,70 @@ where
         }
     };
 
-    let state_db = Arc::new(StateDb::new(storage.clone()));
+    if let Err(e) = clean_partial_import(storage.as_ref()) {
+        panic!("Failed to clean up the state snapshot import: {}", e);
+    }
+    let state_db = match StateDb::with_pruning(storage.clone(), config.state_pruning) {
+        Ok(state_db) => Arc::new(state_db),
+        Err(e) => panic!("Failed to open the state: {}", e),
+    };
     let runtime = Arc::new(RwLock::new(Runtime::new(state_db.clone())));
-    let genesis_root = runtime.write().apply_genesis_state(
-        &chain_spec.accounts,
-        &chain_spec.genesis_wasm,
-        &chain_spec.initial_authorities,
-    );
 
-    let shard_genesis = SignedShardBlock::genesis(genesis_root);
-    let genesis = SignedBeaconBlock::genesis(shard_genesis.block_hash());
+    configure_logging(config.log_level);
+    // The chains start from the blocks of the imported state snapshot instead of genesis.
+    let chain_start = match config.snapshot_command {
+        Some(SnapshotCommand::Import(ref path)) => {
+            match import_state_snapshot(
+                storage.as_ref(),
+                state_db.clone(),
+                path,
+                chain_spec.beacon_chain_epoch_length,
+            ) {
+                Ok(chain_start) => Some(chain_start),
+                Err(e) => panic!("Failed to import the state snapshot: {}", e),
+            }
+        }
+        _ => read_chain_start(storage.as_ref()),
+    };
+    let (shard_genesis, genesis) = match chain_start {
+        Some(chain_start) => (chain_start.shard_block, chain_start.beacon_block),
+        None => {
+            let (mut genesis_transaction, genesis_root) = runtime.read().genesis_state(
+                &chain_spec.accounts,
+                &chain_spec.genesis_wasm,
+                &chain_spec.initial_authorities,
+                chain_spec.beacon_chain_epoch_length,
+                chain_spec.beacon_chain_lockup_epochs,
+                chain_spec.inflation_rate_bps,
+            );
+            let shard_genesis = SignedShardBlock::genesis(genesis_root);
+            // The pruning counts the nodes of the genesis state, so it's committed only once.
+            if let Ok(None) = storage.get(COL_BLOCKS, shard_genesis.block_hash().as_ref()) {
+                state_db.commit(&mut genesis_transaction).expect("Failed to commit genesis state");
+            }
+            let genesis = SignedBeaconBlock::genesis(shard_genesis.block_hash());
+            (shard_genesis, genesis)
+        }
+    };
     let shard_chain = Arc::new(ShardBlockChain::new(shard_genesis, storage.clone()));
     let beacon_chain = Arc::new(BeaconBlockChain::new(genesis, storage.clone()));
 
+    let authority_config = chain_spec::get_authority_config(&chain_spec);
+    let authority =
+        Arc::new(RwLock::new(Authority::new(authority_config, &beacon_chain, storage.clone())));
+    beacon_chain.set_weigher(authority.clone());
+    beacon_chain.set_header_verifier(authority.clone());
+
+    if let Some(SnapshotCommand::Export(ref path)) = config.snapshot_command {
+        let authority = authority.read();
+        if let Err(e) =
+            export_state_snapshot(&beacon_chain, &shard_chain, &authority, state_db.clone(), path)
+        {
+            error!(target: "service", "{}", e);
+        }
+        return;
+    }
+
     let mut key_file_path = config.base_path.to_path_buf();
     key_file_path.push(KEY_STORE_PATH);
     let signer = Arc::new(InMemorySigner::from_key_file(
@@ -209,11 +288,6 @@ where
         key_file_path.as_path(),
         config.public_key.clone(),
     ));
-    let authority_config = chain_spec::get_authority_config(&chain_spec);
-    let authority = Authority::new(authority_config, &beacon_chain);
-    let authority_handler = AuthorityHandler::new(authority, config.account_id.clone());
-
-    configure_logging(config.log_level);
     tokio::run(future::lazy(move || {
         // TODO: TxFlow should be listening on these transactions.
         let (transactions_tx, transactions_rx) = channel(1024);
@@ -223,28 +297,53 @@ where
             &shard_chain.clone(),
             state_db.clone(),
             beacon_chain.clone(),
+            authority.clone(),
+            storage.clone(),
         );
 
         // Create a task that receives new blocks from importer/producer
         // and send the authority information to consensus
+        let (inc_vote_tx, inc_vote_rx) = channel(1024);
+        let (out_vote_tx, out_vote_rx) = channel(1024);
+        let (misbehavior_tx, misbehavior_rx) = channel(1024);
+        let authority_handler = AuthorityHandler::new(
+            authority.clone(),
+            beacon_chain.clone(),
+            signer.clone(),
+            StateDbViewer::new(shard_chain.clone(), state_db.clone()),
+            transactions_tx.clone(),
+            out_vote_tx,
+            misbehavior_tx,
+        );
         let (new_block_tx, new_block_rx) = channel(1024);
         let (authority_tx, authority_rx) = channel(1024);
         let (consensus_control_tx, consensus_control_rx) = channel(1024);
-        spawn_authority_task(authority_handler, new_block_rx, authority_tx, consensus_control_tx);
+        spawn_authority_task(
+            authority_handler,
+            new_block_rx,
+            inc_vote_rx,
+            misbehavior_rx,
+            authority_tx,
+            consensus_control_tx,
+        );
 
         // Create a task that consumes the consensuses
         // and produces the beacon chain blocks.
         let (beacon_block_consensus_body_tx, beacon_block_consensus_body_rx) = channel(1024);
         let (beacon_block_announce_tx, beacon_block_announce_rx) = channel(1024);
+        let (receipts_tx, receipts_rx) = channel(1024);
         beacon_chain_handler::producer::spawn_block_producer(
             beacon_chain.clone(),
             shard_chain.clone(),
             runtime.clone(),
+            authority.clone(),
             signer.clone(),
             state_db.clone(),
             beacon_block_consensus_body_rx,
             beacon_block_announce_tx,
             new_block_tx.clone(),
+            transactions_tx.clone(),
+            receipts_tx.clone(),
         );
 
         // Create task that can import beacon chain blocks from other peers.
@@ -253,6 +352,7 @@ where
             beacon_chain.clone(),
             shard_chain.clone(),
             runtime.clone(),
+            authority.clone(),
             state_db.clone(),
             beacon_block_rx,
             new_block_tx,
@@ -261,7 +361,6 @@ where
         // Spawn protocol and the network_task.
         // Note, that network and RPC are using the same channels
         // to send transactions and receipts for processing.
-        let (receipts_tx, receipts_rx) = channel(1024);
         let (inc_gossip_tx, inc_gossip_rx) = channel(1024);
         let (out_gossip_tx, out_gossip_rx) = channel(1024);
         spawn_network_tasks(
@@ -278,6 +377,8 @@ where
             out_gossip_rx,
             beacon_block_announce_rx,
             authority_rx,
+            inc_vote_tx,
+            out_vote_rx,
         );
 
         // Spawn consensus tasks.
@@ -0,0 +1,150 @@
+//! Command line entry points for exporting and importing state snapshots.
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use beacon::authority::{Authority, AuthorityCheckpoint};
+use beacon::types::{BeaconBlockChain, SignedBeaconBlock};
+use chain::SignedBlock;
+use primitives::hash::hash_struct;
+use primitives::traits::{Decode, Encode};
+use primitives::types::BlockId;
+use shard::{ShardBlockChain, SignedShardBlock};
+use storage::snapshot::{export_snapshot, import_snapshot, DEFAULT_CHUNK_SIZE};
+use storage::{
+    StateDb, Storage, COL_AUTHORITIES, COL_BLOCKS, COL_EXTRA, COL_STATE, COL_STATE_JOURNAL,
+    COL_STATE_RC,
+};
+
+/// Key in `COL_EXTRA` under which the chain start is stored if the state was imported.
+const CHAIN_START_KEY: &[u8] = b"snapshot_chain_start";
+/// Key in `COL_EXTRA` that is present while the state is being imported.
+const IMPORT_MARKER_KEY: &[u8] = b"snapshot_import";
+
+pub enum SnapshotCommand {
+    /// Write the state of the best shard block into the file.
+    Export(PathBuf),
+    /// Load the state from the file into a new storage and run the node from its block.
+    Import(PathBuf),
+}
+
+/// Blocks the state of the snapshot belongs to, stored as snapshot metadata. The chains of a
+/// node that imported the snapshot start from these blocks instead of genesis.
+#[derive(Serialize, Deserialize)]
+pub struct ChainStart {
+    pub beacon_block: SignedBeaconBlock,
+    pub shard_block: SignedShardBlock,
+    /// Authorities around the beacon block.
+    pub authority: AuthorityCheckpoint,
+}
+
+/// Exports the state under the shard block of the best beacon block.
+pub fn export_state_snapshot(
+    beacon_chain: &BeaconBlockChain,
+    shard_chain: &ShardBlockChain,
+    authority: &Authority,
+    state_db: Arc<StateDb>,
+    path: &Path,
+) -> Result<(), String> {
+    let beacon_block = beacon_chain.best_block();
+    let shard_block = shard_chain
+        .get_block(&BlockId::Hash(beacon_block.body.header.shard_block_hash))
+        .ok_or("Shard block of the best beacon block is not known")?;
+    if authority.last_index() != beacon_block.body.header.index {
+        return Err(format!(
+            "Authorities are processed up to block #{} instead of the best block #{}",
+            authority.last_index(),
+            beacon_block.body.header.index
+        ));
+    }
+    let root = shard_block.body.header.merkle_root_state;
+    let (index, hash) = (shard_block.body.header.index, shard_block.block_hash());
+    let chain_start = ChainStart { beacon_block, shard_block, authority: authority.checkpoint()? };
+    let metadata = chain_start.encode().ok_or("Failed to encode the chain start")?;
+    let file = File::create(path).map_err(|e| format!("Failed to create {:?}: {}", path, e))?;
+    let num_chunks =
+        export_snapshot(state_db, root, metadata, DEFAULT_CHUNK_SIZE, &mut BufWriter::new(file))
+            .map_err(|e| format!("Failed to export snapshot: {}", e))?;
+    info!(
+        target: "service",
+        "Exported state of shard block #{} {} in {} chunks to {:?}",
+        index, hash, num_chunks, path
+    );
+    Ok(())
+}
+
+/// Imports the state into a new storage and returns the blocks the chains start from.
+/// The chain start is written last, after the state and the blocks are verified.
+pub fn import_state_snapshot(
+    storage: &Storage,
+    state_db: Arc<StateDb>,
+    path: &Path,
+    epoch_length: u64,
+) -> Result<ChainStart, String> {
+    if storage.iter(COL_BLOCKS).next().is_some() {
+        return Err("State snapshot can only be imported into a new storage".to_string());
+    }
+    let mut db_transaction = storage.transaction();
+    db_transaction.put(COL_EXTRA, IMPORT_MARKER_KEY, &[]);
+    storage.write(db_transaction).map_err(|e| format!("Database write failed: {}", e))?;
+    let file = File::open(path).map_err(|e| format!("Failed to open {:?}: {}", path, e))?;
+    let snapshot_header = import_snapshot(state_db, &mut BufReader::new(file))
+        .map_err(|e| format!("Failed to import snapshot: {}", e))?;
+    let chain_start: ChainStart = Decode::decode(&snapshot_header.metadata)
+        .ok_or("Failed to decode the chain start from the snapshot")?;
+    let (index, hash) = (chain_start.shard_block.body.header.index, chain_start.shard_block.hash);
+    if chain_start.shard_block.body.header.merkle_root_state != snapshot_header.root {
+        return Err("Snapshot root doesn't match its shard block".to_string());
+    }
+    if hash_struct(&chain_start.shard_block.body.header) != hash {
+        return Err("Snapshot shard block doesn't match its hash".to_string());
+    }
+    if chain_start.beacon_block.body.header.shard_block_hash != hash {
+        return Err("Snapshot beacon block doesn't match its shard block".to_string());
+    }
+    chain_start.authority.verify_header(&chain_start.beacon_block.header(), epoch_length)?;
+    let mut db_transaction = storage.transaction();
+    Authority::import_checkpoint(
+        &mut db_transaction,
+        &chain_start.beacon_block.block_hash(),
+        &chain_start.authority,
+    )?;
+    let data = chain_start.encode().ok_or("Failed to encode the chain start")?;
+    db_transaction.put(COL_EXTRA, CHAIN_START_KEY, &data);
+    db_transaction.delete(COL_EXTRA, IMPORT_MARKER_KEY);
+    storage.write(db_transaction).map_err(|e| format!("Database write failed: {}", e))?;
+    info!(
+        target: "service",
+        "Imported state of shard block #{} {} from {:?}",
+        index, hash, path
+    );
+    Ok(chain_start)
+}
+
+/// Removes the state and the authorities left by an import that didn't finish, so that the
+/// storage is new again.
+pub fn clean_partial_import(storage: &Storage) -> Result<(), String> {
+    if let Ok(None) = storage.get(COL_EXTRA, IMPORT_MARKER_KEY) {
+        return Ok(());
+    }
+    warn!(target: "service", "Removing the state of an unfinished snapshot import");
+    let mut db_transaction = storage.transaction();
+    for col in &[COL_STATE, COL_STATE_RC, COL_STATE_JOURNAL, COL_AUTHORITIES] {
+        for (key, _) in storage.iter(*col) {
+            db_transaction.delete(*col, &key);
+        }
+    }
+    db_transaction.delete(COL_EXTRA, IMPORT_MARKER_KEY);
+    storage.write(db_transaction).map_err(|e| format!("Database write failed: {}", e))
+}
+
+/// Returns the blocks the chains start from if the state was imported from a snapshot.
+pub fn read_chain_start(storage: &Storage) -> Option<ChainStart> {
+    match storage.get(COL_EXTRA, CHAIN_START_KEY) {
+        Ok(Some(data)) => {
+            Some(Decode::decode(data.as_ref()).expect("Failed to decode the chain start"))
+        }
+        _ => None,
+    }
+}
@@ -27,6 +27,7 @@ pub fn spawn_consensus(
                     owner_uid: 0,
                     parents: HashSet::new(),
                     epoch: 0,
+                    seq: 0,
                     payload: p,
                     endorsements: vec![],
                 },
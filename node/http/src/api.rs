@@ -1,23 +1,28 @@
 use std::sync::Arc;
 
 use futures::sync::mpsc::Sender;
+use parking_lot::RwLock;
 
+use beacon::authority::Authority;
+use beacon::light_client::get_authority_proof;
 use beacon::types::BeaconBlockChain;
 use node_runtime::state_viewer::StateDbViewer;
 use primitives::traits::Encode;
 use primitives::types::{
     BlockId, CreateAccountTransaction, DeployContractTransaction,
-    FunctionCallTransaction, SendMoneyTransaction, SignedTransaction,
-    StakeTransaction, SwapKeyTransaction, TransactionBody,
+    FunctionCallTransaction, SendMoneyTransaction, SignedTransaction, SlashTransaction,
+    StakeTransaction, SwapKeyTransaction, TransactionBody, UnstakeTransaction,
 };
 use primitives::utils::bs58_vec2str;
 use shard::ShardBlockChain;
+use storage::InstrumentedDB;
 use types::{
-    CallViewFunctionRequest, CallViewFunctionResponse,
-    CreateAccountRequest, DeployContractRequest, GetBlockByHashRequest,
+    AuthorityProofResponse, CallViewFunctionRequest, CallViewFunctionResponse,
+    CreateAccountRequest, DeployContractRequest, GetAuthorityProofRequest, GetBlockByHashRequest,
     PreparedTransactionBodyResponse, ScheduleFunctionCallRequest, SendMoneyRequest,
-    SignedBeaconBlockResponse, SignedShardBlockResponse, StakeRequest, SwapKeyRequest,
-    ViewAccountRequest, ViewAccountResponse, ViewStateRequest, ViewStateResponse,
+    SignedBeaconBlockResponse, SignedShardBlockResponse, SlashRequest, StakeRequest,
+    StorageStatsResponse, SwapKeyRequest, UnstakeRequest, ViewAccountRequest, ViewAccountResponse,
+    ViewStateRequest, ViewStateResponse,
 };
 
 pub struct HttpApi {
@@ -25,6 +30,8 @@ pub struct HttpApi {
     submit_txn_sender: Sender<SignedTransaction>,
     beacon_chain: Arc<BeaconBlockChain>,
     shard_chain: Arc<ShardBlockChain>,
+    authority: Arc<RwLock<Authority>>,
+    storage: Arc<InstrumentedDB>,
 }
 
 impl HttpApi {
@@ -33,12 +40,16 @@ impl HttpApi {
         submit_txn_sender: Sender<SignedTransaction>,
         beacon_chain: Arc<BeaconBlockChain>,
         shard_chain: Arc<ShardBlockChain>,
+        authority: Arc<RwLock<Authority>>,
+        storage: Arc<InstrumentedDB>,
     ) -> HttpApi {
         HttpApi {
             state_db_viewer,
             submit_txn_sender,
             beacon_chain,
             shard_chain,
+            authority,
+            storage,
         }
     }
 }
@@ -117,6 +128,35 @@ impl HttpApi {
         Ok(PreparedTransactionBodyResponse { body })
     }
 
+    pub fn unstake(
+        &self,
+        r: &UnstakeRequest,
+    ) -> Result<PreparedTransactionBodyResponse, ()> {
+        let body = TransactionBody::Unstake(UnstakeTransaction {
+            nonce: r.nonce,
+            originator: r.originator.clone(),
+            amount: r.amount,
+        });
+        debug!(target: "near-rpc", "Unstake money transaction {:?}, amount: {:?}",
+               r.originator, r.amount);
+        Ok(PreparedTransactionBodyResponse { body })
+    }
+
+    pub fn slash(
+        &self,
+        r: &SlashRequest,
+    ) -> Result<PreparedTransactionBodyResponse, ()> {
+        let body = TransactionBody::Slash(SlashTransaction {
+            nonce: r.nonce,
+            originator: r.originator.clone(),
+            offender: r.offender.clone(),
+            evidence: r.evidence.clone(),
+        });
+        debug!(target: "near-rpc", "Slash transaction {:?}, offender: {:?}",
+               r.originator, r.offender);
+        Ok(PreparedTransactionBodyResponse { body })
+    }
+
     pub fn schedule_function_call(
         &self,
         r: ScheduleFunctionCallRequest,
@@ -207,6 +247,15 @@ impl HttpApi {
         }
     }
 
+    pub fn get_authority_proof(
+        &self,
+        r: &GetAuthorityProofRequest,
+    ) -> Result<AuthorityProofResponse, String> {
+        debug!(target: "near-rpc", "Get authority proof for epoch {}", r.epoch);
+        let proof = get_authority_proof(&self.beacon_chain, &self.authority.read(), r.epoch)?;
+        Ok(AuthorityProofResponse { proof })
+    }
+
     pub fn view_latest_shard_block(&self) -> Result<SignedShardBlockResponse, ()> {
         Ok(self.shard_chain.best_block().into())
     }
@@ -220,4 +269,13 @@ impl HttpApi {
             None => Err("block not found"),
         }
     }
+
+    pub fn view_storage_stats(&self) -> Result<StorageStatsResponse, ()> {
+        debug!(target: "near-rpc", "View storage stats");
+        Ok(StorageStatsResponse {
+            columns: self.storage.column_stats(),
+            beacon_chain_caches: self.beacon_chain.cache_stats(),
+            shard_chain_caches: self.shard_chain.cache_stats(),
+        })
+    }
 }
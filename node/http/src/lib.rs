@@ -4,12 +4,14 @@ extern crate futures;
 #[macro_use]
 extern crate log;
 extern crate node_runtime;
+extern crate parking_lot;
 extern crate primitives;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 extern crate shard;
+extern crate storage;
 
 pub mod api;
 pub mod server;
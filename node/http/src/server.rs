@@ -59,6 +59,50 @@ fn serve(http_api: Arc<HttpApi>, req: Request<Body>) -> BoxFut {
                 }
             }))
         }
+        (&Method::POST, "/unstake") => {
+            Box::new(req.into_body().concat2().map(move |chunk| {
+                match serde_json::from_slice(&chunk) {
+                    Ok(data) => {
+                        match http_api.unstake(&data) {
+                            Ok(response) => {
+                                Response::builder()
+                                    .body(Body::from(serde_json::to_string(&response).unwrap()))
+                                    .unwrap()
+                            }
+                            Err(_) => unreachable!()
+                        }
+                    }
+                    Err(e) => {
+                        Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::from(e.to_string()))
+                            .unwrap()
+                    }
+                }
+            }))
+        }
+        (&Method::POST, "/slash") => {
+            Box::new(req.into_body().concat2().map(move |chunk| {
+                match serde_json::from_slice(&chunk) {
+                    Ok(data) => {
+                        match http_api.slash(&data) {
+                            Ok(response) => {
+                                Response::builder()
+                                    .body(Body::from(serde_json::to_string(&response).unwrap()))
+                                    .unwrap()
+                            }
+                            Err(_) => unreachable!()
+                        }
+                    }
+                    Err(e) => {
+                        Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::from(e.to_string()))
+                            .unwrap()
+                    }
+                }
+            }))
+        }
         (&Method::POST, "/swap_key") => {
             Box::new(req.into_body().concat2().map(move |chunk| {
                 match serde_json::from_slice(&chunk) {
@@ -291,6 +335,33 @@ fn serve(http_api: Arc<HttpApi>, req: Request<Body>) -> BoxFut {
                 }
             }))
         }
+        (&Method::POST, "/get_authority_proof") => {
+            Box::new(req.into_body().concat2().map(move |chunk| {
+                match serde_json::from_slice(&chunk) {
+                    Ok(data) => {
+                        match http_api.get_authority_proof(&data) {
+                            Ok(response) => {
+                                Response::builder()
+                                    .body(Body::from(serde_json::to_string(&response).unwrap()))
+                                    .unwrap()
+                            }
+                            Err(e) => {
+                                Response::builder()
+                                    .status(StatusCode::BAD_REQUEST)
+                                    .body(Body::from(e.to_string()))
+                                    .unwrap()
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::from(e.to_string()))
+                            .unwrap()
+                    }
+                }
+            }))
+        }
         (&Method::POST, "/view_latest_shard_block") => {
             Box::new(future::ok(
                 match http_api.view_latest_shard_block() {
@@ -303,6 +374,18 @@ fn serve(http_api: Arc<HttpApi>, req: Request<Body>) -> BoxFut {
                 }
             ))
         }
+        (&Method::POST, "/view_storage_stats") => {
+            Box::new(future::ok(
+                match http_api.view_storage_stats() {
+                    Ok(response) => {
+                        Response::builder()
+                            .body(Body::from(serde_json::to_string(&response).unwrap()))
+                            .unwrap()
+                    }
+                    Err(_) => unreachable!()
+                }
+            ))
+        }
         (&Method::POST, "/get_shard_block_by_hash") => {
             Box::new(req.into_body().concat2().map(move |chunk| {
                 match serde_json::from_slice(&chunk) {
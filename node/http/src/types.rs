@@ -1,14 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use beacon::authority::AuthorityProposal;
+use beacon::light_client::AuthorityTransition;
 use beacon::types::{BeaconBlock, BeaconBlockHeader, SignedBeaconBlock};
 use primitives::hash::{bs58_format, CryptoHash};
 use primitives::signature::{bs58_pub_key_format, PublicKey};
 use primitives::types::{
-    AccountId, AuthorityMask, Balance, MerkleHash, ShardId, Transaction,
+    AccountId, AuthorityMask, Balance, MerkleHash, Misbehavior, ShardId, Transaction,
     TransactionBody,
 };
 use shard::{ShardBlock, ShardBlockHeader, SignedShardBlock};
+use storage::metrics::{CacheStats, ColumnStats};
 
 #[derive(Serialize, Deserialize)]
 pub struct SendMoneyRequest {
@@ -25,6 +27,21 @@ pub struct StakeRequest {
     pub amount: Balance,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SlashRequest {
+    pub nonce: u64,
+    pub originator: AccountId,
+    pub offender: AccountId,
+    pub evidence: Misbehavior,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnstakeRequest {
+    pub nonce: u64,
+    pub originator: AccountId,
+    pub amount: Balance,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct DeployContractRequest {
     pub nonce: u64,
@@ -127,6 +144,13 @@ impl From<AuthorityProposal> for AuthorityProposalResponse {
     }
 }
 
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RandomRevealResponse {
+    pub account_id: AccountId,
+    #[serde(with = "bs58_format")]
+    pub value: CryptoHash,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct BeaconBlockHeaderResponse {
     #[serde(with = "bs58_format")]
@@ -135,6 +159,10 @@ pub struct BeaconBlockHeaderResponse {
     pub authority_proposal: Vec<AuthorityProposalResponse>,
     #[serde(with = "bs58_format")]
     pub shard_block_hash: CryptoHash,
+    pub random_reveals: Vec<RandomRevealResponse>,
+    pub slashed_authorities: Vec<AccountId>,
+    #[serde(with = "bs58_format")]
+    pub authority_set_hash: CryptoHash,
 }
 
 impl From<BeaconBlockHeader> for BeaconBlockHeaderResponse {
@@ -142,11 +170,17 @@ impl From<BeaconBlockHeader> for BeaconBlockHeaderResponse {
         let authority_proposal = header.authority_proposal.into_iter()
             .map(|x| x.into())
             .collect();
+        let random_reveals = header.random_reveals.into_iter()
+            .map(|(account_id, value)| RandomRevealResponse { account_id, value })
+            .collect();
         BeaconBlockHeaderResponse {
             parent_hash: header.shard_block_hash,
             index: header.index,
             authority_proposal,
             shard_block_hash: header.shard_block_hash,
+            random_reveals,
+            slashed_authorities: header.slashed_authorities,
+            authority_set_hash: header.authority_set_hash,
         }
     }
 }
@@ -257,3 +291,21 @@ pub struct GetBlockByHashRequest {
     #[serde(with = "bs58_format")]
     pub hash: CryptoHash,
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct StorageStatsResponse {
+    pub columns: BTreeMap<String, ColumnStats>,
+    pub beacon_chain_caches: BTreeMap<String, CacheStats>,
+    pub shard_chain_caches: BTreeMap<String, CacheStats>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetAuthorityProofRequest {
+    pub epoch: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AuthorityProofResponse {
+    /// Transitions from the genesis authorities to the authorities of the requested epoch.
+    pub proof: Vec<AuthorityTransition>,
+}
@@ -1,3 +1,4 @@
+use beacon::finality::BlockVote;
 use primitives::hash::CryptoHash;
 use primitives::types::{AccountId, BlockId, SignedTransaction, ReceiptTransaction, Gossip};
 
@@ -14,6 +15,7 @@ pub enum Message<B, H, P> {
     BlockResponse(BlockResponse<B>),
     BlockAnnounce(BlockAnnounce<B, H>),
     Gossip(Gossip<P>),
+    BlockVote(BlockVote),
 }
 
 /// status sent on connection
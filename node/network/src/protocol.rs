@@ -8,6 +8,7 @@ use parking_lot::RwLock;
 use substrate_network_libp2p::{NodeIndex, ProtocolId, Severity};
 
 use beacon::authority::SelectedAuthority;
+use beacon::finality::BlockVote;
 use chain::{BlockChain, SignedBlock, SignedHeader as BlockHeader};
 use message::{self, Message};
 use primitives::hash::CryptoHash;
@@ -78,7 +79,7 @@ pub struct Protocol<B: SignedBlock, Header: BlockHeader> {
     /// Info for authority peers.
     peer_account_info: RwLock<HashMap<AccountId, NodeIndex>>,
     /// Chain info, for read-only access.
-    chain: Arc<BlockChain<B>>,
+    pub(crate) chain: Arc<BlockChain<B>>,
     /// Channel into which the protocol sends the new blocks.
     block_sender: Sender<B>,
     /// Channel into which the protocol sends the received transactions.
@@ -89,6 +90,8 @@ pub struct Protocol<B: SignedBlock, Header: BlockHeader> {
     message_sender: Sender<(NodeIndex, Message<B, Header, ChainPayload>)>,
     /// Channel into which the protocol sends the gossips that should be processed by TxFlow.
     gossip_sender: Sender<Gossip<ChainPayload>>,
+    /// Channel into which the protocol sends the received votes for blocks.
+    vote_sender: Sender<BlockVote>,
     /// map between authority uid and account id + public key.
     authority_map: RwLock<HashMap<UID, SelectedAuthority>>,
 }
@@ -102,6 +105,7 @@ impl<B: SignedBlock, Header: BlockHeader> Protocol<B, Header> {
         receipt_sender: Sender<ReceiptTransaction>,
         message_sender: Sender<(NodeIndex, Message<B, Header, ChainPayload>)>,
         gossip_sender: Sender<Gossip<ChainPayload>>,
+        vote_sender: Sender<BlockVote>,
     ) -> Self {
         Self {
             config,
@@ -114,6 +118,7 @@ impl<B: SignedBlock, Header: BlockHeader> Protocol<B, Header> {
             receipt_sender,
             message_sender,
             gossip_sender,
+            vote_sender,
             authority_map: RwLock::new(HashMap::new()),
         }
     }
@@ -255,6 +260,31 @@ impl<B: SignedBlock, Header: BlockHeader> Protocol<B, Header> {
         self.send_message(peer, message);
     }
 
+    /// Asks a peer without a pending request for the block with the given hash.
+    pub fn request_block(&self, hash: CryptoHash) {
+        let mut peers = self.peer_info.write();
+        let peer = peers
+            .iter_mut()
+            .filter(|(_, info)| info.block_request.is_none())
+            .max_by_key(|(_, info)| info.best_index);
+        match peer {
+            Some((peer, info)) => {
+                let request = message::BlockRequest {
+                    id: info.next_request_id,
+                    from: BlockId::Hash(hash),
+                    to: Some(BlockId::Hash(hash)),
+                    max: Some(1),
+                };
+                info.next_request_id += 1;
+                info.block_request = Some(request.clone());
+                info.request_timestamp = Some(time::Instant::now());
+                debug!(target: "network", "Requesting block {} from {:?}", hash, peer);
+                self.send_message(*peer, Message::BlockRequest(request));
+            }
+            None => debug!(target: "network", "No peer to request block {} from", hash),
+        }
+    }
+
     fn on_incoming_block(&self, block: B) {
         let copied_tx = self.block_sender.clone();
         tokio::spawn(
@@ -273,6 +303,23 @@ impl<B: SignedBlock, Header: BlockHeader> Protocol<B, Header> {
         }
     }
 
+    fn on_incoming_vote(&self, vote: BlockVote) {
+        let copied_tx = self.vote_sender.clone();
+        tokio::spawn(
+            copied_tx
+                .send(vote)
+                .map(|_| ())
+                .map_err(|e| error!("Failure to send the vote {:?}", e)),
+        );
+    }
+
+    pub fn on_outgoing_vote(&self, vote: &BlockVote) {
+        let peers = self.peer_info.read();
+        for peer in peers.keys() {
+            self.send_message(*peer, Message::BlockVote(vote.clone()));
+        }
+    }
+
     fn on_block_response(&self, peer_id: NodeIndex, response: message::BlockResponse<B>) {
         let copied_tx = self.block_sender.clone();
         self.peer_info.write().entry(peer_id).and_modify(|e| e.request_timestamp = None);
@@ -333,7 +380,8 @@ impl<B: SignedBlock, Header: BlockHeader> Protocol<B, Header> {
                     _ => unimplemented!(),
                 }
             }
-            Message::Gossip(gossip) => self.on_gossip_message(gossip)
+            Message::Gossip(gossip) => self.on_gossip_message(gossip),
+            Message::BlockVote(vote) => self.on_incoming_vote(vote),
 
         }
         Ok(())
@@ -425,7 +473,7 @@ mod tests {
         // chain1
         let beacon_chain = Arc::new(BeaconBlockChain::new(genesis_block.clone(), storage.clone()));
         let authority_config = get_test_authority_config(1, 1, 1);
-        let authority = Authority::new(authority_config, &beacon_chain);
+        let authority = Authority::new(authority_config, &beacon_chain, storage.clone());
         let (authority_tx, authority_rx) = channel(1024);
         let protocol = Arc::new(get_test_protocol());
 
@@ -6,6 +6,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use beacon::authority::SelectedAuthority;
+use beacon::finality::BlockVote;
 use futures::{Future, stream, Stream};
 use futures::sync::mpsc::Receiver;
 use parking_lot::Mutex;
@@ -39,6 +40,7 @@ pub fn spawn_network_tasks<B, Header>(
     block_receiver: Receiver<B>,
     authority_receiver: Receiver<HashMap<UID, SelectedAuthority>>,
     gossip_rx: Receiver<Gossip<ChainPayload>>,
+    vote_receiver: Receiver<BlockVote>,
 ) where
     B: SignedBlock,
     Header: BlockHeader,
@@ -139,6 +141,11 @@ pub fn spawn_network_tasks<B, Header>(
     let protocol2 = protocol.clone();
     tokio::spawn(messages_handler);
     tokio::spawn(block_announce_handler);
+    let protocol5 = protocol.clone();
+    tokio::spawn(vote_receiver.for_each(move |vote| {
+        protocol5.on_outgoing_vote(&vote);
+        Ok(())
+    }));
     tokio::spawn(
         authority_receiver.for_each(move |map| {
             protocol2.set_authority_map(map);
@@ -146,6 +153,13 @@ pub fn spawn_network_tasks<B, Header>(
         })
     );
 
+    // Fetches ancestors of the blocks that can't be connected to the chain yet.
+    let protocol4 = protocol.clone();
+    tokio::spawn(protocol.chain.subscribe_missing_blocks().for_each(move |hash| {
+        protocol4.request_block(hash);
+        Ok(())
+    }));
+
     let protocol3 = protocol.clone();
     let gossip_sender = gossip_rx
         .for_each(move |g| {
@@ -113,6 +113,7 @@ pub fn get_test_protocol() -> Protocol<SignedBeaconBlock, SignedBeaconBlockHeade
     let (receipt_tx, _) = channel(1024);
     let (message_tx, _) = channel(1024);
     let (gossip_tx, _) = channel(1024);
+    let (vote_tx, _) = channel(1024);
     Protocol::new(
         ProtocolConfig::default(),
         chain,
@@ -121,6 +122,7 @@ pub fn get_test_protocol() -> Protocol<SignedBeaconBlock, SignedBeaconBlockHeade
         receipt_tx,
         message_tx,
         gossip_tx,
+        vote_tx,
     )
 }
 
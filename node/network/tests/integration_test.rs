@@ -69,6 +69,7 @@ fn get_test_protocol(
     let (transaction_tx, _) = channel(1024);
     let (receipt_tx, _) = channel(1024);
     let (gossip_tx, _) = channel(1024);
+    let (vote_tx, _) = channel(1024);
     Protocol::new(
         ProtocolConfig::default(),
         chain,
@@ -77,6 +78,7 @@ fn get_test_protocol(
         receipt_tx,
         message_tx,
         gossip_tx,
+        vote_tx,
     )
 }
 
@@ -123,6 +125,8 @@ fn test_block_catch_up_from_start() {
     // chain2 should catch up with chain1
     let (_, gossip_rx1) = channel(1024);
     let (_, gossip_rx2) = channel(1024);
+    let (_, vote_rx1) = channel(1024);
+    let (_, vote_rx2) = channel(1024);
     let task = futures::lazy({
         let chain = beacon_chain2.clone();
         move || {
@@ -133,6 +137,7 @@ fn test_block_catch_up_from_start() {
                 block_outgoing_rx1,
                 authority_rx1,
                 gossip_rx1,
+                vote_rx1,
             );
             spawn_network_tasks(
                 network_service2,
@@ -141,6 +146,7 @@ fn test_block_catch_up_from_start() {
                 block_outgoing_rx2,
                 authority_rx2,
                 gossip_rx2,
+                vote_rx2,
             );
             spawn_simple_block_import_task(chain, block_rx2);
             Ok(())
@@ -239,6 +245,8 @@ fn test_block_catchup_from_network_interruption() {
 
     let (_, gossip_rx1) = channel(1024);
     let (_, gossip_rx2) = channel(1024);
+    let (_, vote_rx1) = channel(1024);
+    let (_, vote_rx2) = channel(1024);
     let task = futures::lazy({
         let chain = beacon_chain2.clone();
         move || {
@@ -249,6 +257,7 @@ fn test_block_catchup_from_network_interruption() {
                 block_outgoing_rx1,
                 authority_rx1,
                 gossip_rx1,
+                vote_rx1,
             );
             spawn_network_tasks(
                 network_service2.clone(),
@@ -257,6 +266,7 @@ fn test_block_catchup_from_network_interruption() {
                 block_outgoing_rx2,
                 authority_rx2,
                 gossip_rx2,
+                vote_rx2,
             );
             spawn_simple_block_import_task(chain, block_rx2);
             block_task
@@ -311,6 +321,8 @@ fn test_block_announce() {
 
     let (_, gossip_rx1) = channel(1024);
     let (_, gossip_rx2) = channel(1024);
+    let (_, vote_rx1) = channel(1024);
+    let (_, vote_rx2) = channel(1024);
     let task = futures::lazy({
         let chain1 = beacon_chain1.clone();
         let chain2 = beacon_chain2.clone();
@@ -324,6 +336,7 @@ fn test_block_announce() {
                 block_outgoing_rx1,
                 authority_rx1,
                 gossip_rx1,
+                vote_rx1,
             );
             spawn_network_tasks(
                 service2.clone(),
@@ -332,6 +345,7 @@ fn test_block_announce() {
                 block_outgoing_rx2,
                 authority_rx2,
                 gossip_rx2,
+                vote_rx2,
             );
             spawn_simple_block_import_task(chain2, block_rx2);
             thread::sleep(Duration::from_secs(3));
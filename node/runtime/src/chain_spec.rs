@@ -13,6 +13,29 @@ pub struct ChainSpec {
 
     pub beacon_chain_epoch_length: u64,
     pub beacon_chain_num_seats_per_slot: u64,
+    /// Number of epochs the unstaked money stays locked. Must cover the two epochs
+    /// for which the authority is already selected when it unstakes.
+    pub beacon_chain_lockup_epochs: u64,
+
+    /// Money minted every epoch for the authority rewards, in basis points (1/10000)
+    /// of the total supply.
+    pub inflation_rate_bps: u64,
 
     pub boot_nodes: Vec<String>,
 }
+
+impl ChainSpec {
+    /// Checks the parameters that the chain can't work with.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.beacon_chain_epoch_length == 0 {
+            return Err("Epoch length must be positive".to_string());
+        }
+        if self.beacon_chain_lockup_epochs < 2 {
+            return Err(format!(
+                "Lockup of {} epochs doesn't cover the two epochs an authority is selected for",
+                self.beacon_chain_lockup_epochs
+            ));
+        }
+        Ok(())
+    }
+}
@@ -13,12 +13,14 @@ extern crate shard;
 extern crate storage;
 extern crate wasm;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
 
 use serde::{de::DeserializeOwned, Serialize};
 
-use beacon::authority::AuthorityProposal;
+use beacon::authority::{epoch_of_block, AuthorityProposal};
+use beacon::randomness::verify_reveal;
+use beacon::slashing::{evidence_hash, misbehavior_epoch, verify_misbehavior};
 use ext::RuntimeExt;
 use primitives::hash::{CryptoHash, hash};
 use primitives::signature::{PublicKey, Signature, verify};
@@ -28,7 +30,7 @@ use primitives::types::{
     ReceiptTransaction, ReceiptBody, AsyncCall, CallbackResult, CallbackInfo, Callback,
     PromiseId, CallbackId, StakeTransaction, SendMoneyTransaction, CreateAccountTransaction,
     SwapKeyTransaction, DeployContractTransaction, Balance, Transaction, ShardId,
-    FunctionCallTransaction,
+    FunctionCallTransaction, RevealRandomnessTransaction, UnstakeTransaction, SlashTransaction,
 };
 use primitives::utils::{
     account_to_shard_id, index_to_bytes, is_valid_account_id
@@ -43,6 +45,10 @@ pub mod state_viewer;
 mod ext;
 
 const RUNTIME_DATA: &[u8] = b"runtime";
+/// Prefix of the last committed value of the randomness hash chain of an account.
+const RANDOM_COMMITMENT_PREFIX: &[u8] = b"random_commitment:";
+/// Prefix of the hashes of the evidence that was already used to slash.
+const USED_EVIDENCE_PREFIX: &[u8] = b"used_evidence:";
 const DEFAULT_MANA_LIMIT: u32 = 20;
 
 // const does not allow function call, so have to resort to this
@@ -52,12 +58,46 @@ fn system_account() -> AccountId {
 
 /// Runtime data that is stored in the state.
 /// TODO: Look into how to store this not in a single element of the StateDb.
+/// Collections are ordered, so that the encoding and the state root don't depend on the
+/// order of a hash map.
 #[derive(Default, Serialize, Deserialize)]
 pub struct RuntimeData {
     /// Currently staked money.
-    pub stake: HashMap<AccountId, u64>,
+    pub stake: BTreeMap<AccountId, u64>,
+    /// Unstaked money that is still locked.
+    pub unstaking: BTreeMap<AccountId, Unstaking>,
     /// scheduled callbacks
-    pub callbacks: HashMap<CallbackId, Callback>,
+    pub callbacks: BTreeMap<CallbackId, Callback>,
+    /// Length of the beacon chain epoch in blocks.
+    pub epoch_length: u64,
+    /// Number of epochs the unstaked money stays locked.
+    pub lockup_epochs: u64,
+    /// Money in all the accounts. Changes only by rewards and slashing.
+    pub total_supply: Balance,
+    /// Money minted every epoch for the rewards, in basis points of the total supply.
+    pub inflation_rate_bps: u64,
+    /// Participation of the authorities in the current epoch.
+    pub participation: BTreeMap<AccountId, Participation>,
+    /// Accounts that held seats in the recent epochs, kept for as long as the unstaked money
+    /// stays locked, so that misbehavior in these epochs can be slashed.
+    pub seated: BTreeMap<u64, BTreeSet<AccountId>>,
+    /// Authorities that revealed a value to the randomness beacon in the current epoch.
+    pub revealed: BTreeSet<AccountId>,
+}
+
+/// Seats of an authority in the current epoch and how many of them signed their blocks.
+#[derive(Default, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Participation {
+    pub seats: u64,
+    pub signed: u64,
+}
+
+/// Money withdrawn from the stake. It can still be slashed until it's released.
+#[derive(Default, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Unstaking {
+    pub amount: Balance,
+    /// The money is released in the blocks after this one.
+    pub release_index: u64,
 }
 
 impl RuntimeData {
@@ -66,7 +106,48 @@ impl RuntimeData {
     }
 
     pub fn put_stake_for_account(&mut self, account_id: &AccountId, amount: u64) {
-        self.stake.insert(account_id.clone(), amount);
+        if amount == 0 {
+            self.stake.remove(account_id);
+        } else {
+            self.stake.insert(account_id.clone(), amount);
+        }
+    }
+
+    pub fn get_unstaking_for_account(&self, account_id: &AccountId) -> u64 {
+        self.unstaking.get(account_id).map(|unstaking| unstaking.amount).unwrap_or(0)
+    }
+
+    /// Money of the account that can't be spent: the stake and the unstaked money in lockup.
+    pub fn get_locked_for_account(&self, account_id: &AccountId) -> u64 {
+        self.get_stake_for_account(account_id) + self.get_unstaking_for_account(account_id)
+    }
+
+    /// Index of the last block of the lockup for money unstaked in the given block. The lockup
+    /// starts with the epoch of the block and ends with an epoch, so that the authority keeps
+    /// its stake for all the seats it was selected for before unstaking.
+    fn release_index(&self, block_index: u64) -> u64 {
+        let epoch = epoch_of_block(block_index, self.epoch_length);
+        (epoch + self.lockup_epochs) * self.epoch_length
+    }
+
+    /// Weight of the authority in the rewards: its stake times the share of seats it signed.
+    fn reward_weight(&self, account_id: &AccountId, participation: &Participation) -> u128 {
+        if participation.seats == 0 {
+            return 0;
+        }
+        u128::from(self.get_stake_for_account(account_id)) * u128::from(participation.signed)
+            / u128::from(participation.seats)
+    }
+
+    fn held_seat(&self, account_id: &AccountId, epoch: u64) -> bool {
+        self.seated.get(&epoch).map_or(false, |accounts| accounts.contains(account_id))
+    }
+
+    /// Releases the unstaked money whose lockup is over. Returns true if any was released.
+    fn release_unstaked(&mut self, block_index: u64) -> bool {
+        let num_unstaking = self.unstaking.len();
+        self.unstaking.retain(|_, unstaking| unstaking.release_index >= block_index);
+        self.unstaking.len() != num_unstaking
     }
 }
 
@@ -89,6 +170,18 @@ fn account_id_to_bytes(account_key: &AccountId) -> Vec<u8> {
     account_key.clone().into_bytes()
 }
 
+fn random_commitment_key(account_id: &AccountId) -> Vec<u8> {
+    let mut key = RANDOM_COMMITMENT_PREFIX.to_vec();
+    key.extend_from_slice(account_id.as_bytes());
+    key
+}
+
+fn used_evidence_key(evidence_hash: &CryptoHash) -> Vec<u8> {
+    let mut key = USED_EVIDENCE_PREFIX.to_vec();
+    key.extend_from_slice(evidence_hash.as_ref());
+    key
+}
+
 fn create_nonce_with_nonce(base: &[u8], salt: u64) -> Vec<u8> {
     let mut nonce: Vec<u8> = base.to_owned();
     nonce.append(&mut index_to_bytes(salt));
@@ -100,6 +193,10 @@ pub struct ApplyState {
     pub shard_id: ShardId,
     pub block_index: u64,
     pub parent_block_hash: CryptoHash,
+    /// Authorities of the seats of the parent block and whether their signatures on it
+    /// verify. A block hash doesn't cover its signatures, so they are only known to the
+    /// blocks after it.
+    pub participation: Vec<(AccountId, bool)>,
 }
 
 pub struct ApplyResult {
@@ -107,6 +204,11 @@ pub struct ApplyResult {
     pub shard_id: ShardId,
     pub transaction: storage::TrieBackendTransaction,
     pub authority_proposals: Vec<AuthorityProposal>,
+    /// Values revealed for the randomness beacon with their authorities, in the order of the
+    /// transactions.
+    pub random_reveals: Vec<(AccountId, CryptoHash)>,
+    /// Authorities slashed by the transactions, they lose their seats.
+    pub slashed_authorities: Vec<AccountId>,
     pub filtered_transactions: Vec<Transaction>,
     pub new_receipts: Vec<Transaction>,
 }
@@ -122,6 +224,22 @@ fn set<T: Serialize>(state_update: &mut StateDbUpdate, key: &[u8], value: &T) {
         .unwrap_or_else(|| { debug!("set value failed"); })
 }
 
+/// Runs a step of a transaction inside of a nested checkpoint, so that a failure of the step only
+/// reverts its own changes and the caller decides what happens to the rest.
+fn with_checkpoint<T, F>(state_update: &mut StateDbUpdate, step: F) -> Result<T, String>
+where
+    F: FnOnce(&mut StateDbUpdate) -> Result<T, String>,
+{
+    state_update.checkpoint();
+    let result = step(state_update);
+    if result.is_ok() {
+        state_update.discard_checkpoint();
+    } else {
+        state_update.rollback_to_checkpoint();
+    }
+    result
+}
+
 pub struct Runtime {
     state_db: Arc<StateDb>,
 }
@@ -139,7 +257,7 @@ impl Runtime {
         sender: &mut Account,
         runtime_data: &mut RuntimeData,
     ) -> Result<Vec<Transaction>, String> {
-        let staked = runtime_data.get_stake_for_account(&transaction.originator);
+        let staked = runtime_data.get_locked_for_account(&transaction.originator);
         if sender.amount - staked >= transaction.amount {
             sender.amount -= transaction.amount;
             set(state_update, &account_id_to_bytes(&transaction.originator), sender);
@@ -168,6 +286,8 @@ impl Runtime {
         }
     }
 
+    /// Adds the amount to the stake. Money that is still locked after unstaking is
+    /// restaked first.
     fn staking(
         &self,
         state_update: &mut StateDbUpdate,
@@ -177,26 +297,133 @@ impl Runtime {
         runtime_data: &mut RuntimeData,
         authority_proposals: &mut Vec<AuthorityProposal>,
     ) -> Result<Vec<Transaction>, String>{
-        if sender.amount >= body.amount && sender.public_keys.is_empty() {
-            runtime_data.put_stake_for_account(&body.originator, body.amount);
-            authority_proposals.push(AuthorityProposal {
-                account_id: sender_account_id.clone(),
-                public_key: sender.public_keys[0],
-                amount: body.amount,
-            });
-            set(state_update, RUNTIME_DATA, &runtime_data);
-            Ok(vec![])
-        } else if sender.amount < body.amount {
-            let err_msg = format!(
-                "Account {} tries to stake {}, but only has {}",
+        if sender.public_keys.is_empty() {
+            return Err(format!("Account {} has no key to stake with", body.originator));
+        }
+        let unstaking = runtime_data.get_unstaking_for_account(&body.originator);
+        let restaked = unstaking.min(body.amount);
+        let locked = runtime_data.get_locked_for_account(&body.originator);
+        if sender.amount < locked + body.amount - restaked {
+            return Err(format!(
+                "Account {} tries to stake {}, but has locked {} and only has {}",
                 body.originator,
                 body.amount,
+                locked,
                 sender.amount
-            );
-            Err(err_msg)
-        } else {
-            Err(format!("Account {} already staked", body.originator))
+            ));
+        }
+        if restaked == unstaking {
+            runtime_data.unstaking.remove(&body.originator);
+        } else if let Some(unstaking) = runtime_data.unstaking.get_mut(&body.originator) {
+            unstaking.amount -= restaked;
         }
+        let stake = runtime_data.get_stake_for_account(&body.originator) + body.amount;
+        runtime_data.put_stake_for_account(&body.originator, stake);
+        authority_proposals.push(AuthorityProposal {
+            account_id: sender_account_id.clone(),
+            public_key: sender.public_keys[0],
+            amount: stake,
+        });
+        set(state_update, RUNTIME_DATA, &runtime_data);
+        Ok(vec![])
+    }
+
+    /// Withdraws the amount from the stake and locks it until the end of the lockup.
+    /// Proposes the remaining stake, so that an authority that unstaked everything
+    /// is not selected again.
+    fn unstaking(
+        &self,
+        state_update: &mut StateDbUpdate,
+        body: &UnstakeTransaction,
+        block_index: u64,
+        sender: &Account,
+        runtime_data: &mut RuntimeData,
+        authority_proposals: &mut Vec<AuthorityProposal>,
+    ) -> Result<Vec<Transaction>, String> {
+        let staked = runtime_data.get_stake_for_account(&body.originator);
+        if body.amount > staked {
+            return Err(format!(
+                "Account {} tries to unstake {}, but has only staked {}",
+                body.originator,
+                body.amount,
+                staked
+            ));
+        }
+        if sender.public_keys.is_empty() {
+            return Err(format!("Account {} has no key to stake with", body.originator));
+        }
+        runtime_data.put_stake_for_account(&body.originator, staked - body.amount);
+        let release_index = runtime_data.release_index(block_index);
+        let unstaking = runtime_data.unstaking.entry(body.originator.clone()).or_default();
+        unstaking.amount += body.amount;
+        unstaking.release_index = release_index;
+        authority_proposals.push(AuthorityProposal {
+            account_id: body.originator.clone(),
+            public_key: sender.public_keys[0],
+            amount: staked - body.amount,
+        });
+        set(state_update, RUNTIME_DATA, &runtime_data);
+        Ok(vec![])
+    }
+
+    /// Burns the stake of the offender, including the unstaked money that is still locked,
+    /// and proposes to take away its future seats. The offender must have held a seat in the
+    /// epoch of the misbehavior, or in the epoch of the block if the evidence doesn't show it,
+    /// and every evidence is used only once.
+    fn slash(
+        &self,
+        state_update: &mut StateDbUpdate,
+        body: &SlashTransaction,
+        block_index: u64,
+        runtime_data: &mut RuntimeData,
+        authority_proposals: &mut Vec<AuthorityProposal>,
+        slashed_authorities: &mut Vec<AccountId>,
+    ) -> Result<Vec<Transaction>, String> {
+        let burned = runtime_data.get_locked_for_account(&body.offender);
+        if burned == 0 {
+            return Err(format!("Account {} has no stake to slash", body.offender));
+        }
+        let used_key = used_evidence_key(&evidence_hash(&body.evidence)?);
+        if state_update.get(&used_key).is_some() {
+            return Err(format!("Evidence against {} was already used", body.offender));
+        }
+        let epoch = match misbehavior_epoch(&body.evidence, runtime_data.epoch_length)? {
+            Some(epoch) => epoch,
+            // Seats are recorded up to the parent block.
+            None => epoch_of_block(block_index.saturating_sub(1), runtime_data.epoch_length),
+        };
+        if !runtime_data.held_seat(&body.offender, epoch) {
+            return Err(format!(
+                "Account {} held no seat in epoch {} of the misbehavior",
+                body.offender,
+                epoch
+            ));
+        }
+        let offender_key = account_id_to_bytes(&body.offender);
+        let mut offender: Account = get(state_update, &offender_key)
+            .ok_or_else(|| format!("Account {} does not exist", body.offender))?;
+        let proven = offender
+            .public_keys
+            .iter()
+            .any(|public_key| verify_misbehavior(&body.evidence, public_key).is_ok());
+        if !proven {
+            return Err(format!("Evidence doesn't prove misbehavior of {}", body.offender));
+        }
+        let burned = burned.min(offender.amount);
+        offender.amount -= burned;
+        runtime_data.total_supply -= burned;
+        set(state_update, &offender_key, &offender);
+        set(state_update, &used_key, &true);
+        runtime_data.put_stake_for_account(&body.offender, 0);
+        runtime_data.unstaking.remove(&body.offender);
+        authority_proposals.push(AuthorityProposal {
+            account_id: body.offender.clone(),
+            public_key: offender.public_keys[0],
+            amount: 0,
+        });
+        slashed_authorities.push(body.offender.clone());
+        set(state_update, RUNTIME_DATA, &runtime_data);
+        Ok(vec![])
     }
 
     fn create_account(
@@ -210,7 +437,7 @@ impl Runtime {
         if !is_valid_account_id(&body.new_account_id) {
             return Err(format!("Account {} does not match requirements", body.new_account_id));
         }
-        let staked = runtime_data.get_stake_for_account(&body.originator);
+        let staked = runtime_data.get_locked_for_account(&body.originator);
         if sender.amount >= staked + body.amount {
             sender.amount -= body.amount;
             set(
@@ -272,6 +499,41 @@ impl Runtime {
         Ok(vec![])
     }
 
+    /// The first value of an authority is the commitment to its hash chain, every next one
+    /// must be the preimage of the previous one and is revealed to the randomness beacon.
+    /// An authority reveals at most once per epoch, so it can't choose among its values.
+    fn reveal_randomness(
+        &self,
+        state_update: &mut StateDbUpdate,
+        body: &RevealRandomnessTransaction,
+        runtime_data: &mut RuntimeData,
+        random_reveals: &mut Vec<(AccountId, CryptoHash)>,
+    ) -> Result<Vec<Transaction>, String> {
+        if runtime_data.get_stake_for_account(&body.originator) == 0 {
+            return Err(format!("Account {} is not staking", body.originator));
+        }
+        let key = random_commitment_key(&body.originator);
+        let commitment: Option<CryptoHash> = get(state_update, &key);
+        if let Some(commitment) = commitment {
+            if !verify_reveal(&commitment, &body.value) {
+                return Err(format!(
+                    "Account {} revealed a value that doesn't match its commitment",
+                    body.originator
+                ));
+            }
+            if !runtime_data.revealed.insert(body.originator.clone()) {
+                return Err(format!(
+                    "Account {} already revealed a value in this epoch",
+                    body.originator
+                ));
+            }
+            random_reveals.push((body.originator.clone(), body.value));
+            set(state_update, RUNTIME_DATA, &runtime_data);
+        }
+        set(state_update, &key, &body.value);
+        Ok(vec![])
+    }
+
     fn deploy(
         &self,
         body: &DeployContractTransaction,
@@ -303,7 +565,7 @@ impl Runtime {
         sender: &mut Account,
         runtime_data: &mut RuntimeData,
     ) -> Result<Vec<Transaction>, String> {
-        let staked = runtime_data.get_stake_for_account(&transaction.originator);
+        let staked = runtime_data.get_locked_for_account(&transaction.originator);
         if sender.amount - staked >= transaction.amount {
             sender.amount -= transaction.amount;
             set(state_update, &account_id_to_bytes(&transaction.originator), sender);
@@ -337,8 +599,11 @@ impl Runtime {
     fn apply_signed_transaction(
         &mut self,
         state_update: &mut StateDbUpdate,
+        block_index: u64,
         transaction: &SignedTransaction,
         authority_proposals: &mut Vec<AuthorityProposal>,
+        random_reveals: &mut Vec<(AccountId, CryptoHash)>,
+        slashed_authorities: &mut Vec<AccountId>,
     ) -> Result<Vec<Transaction>, String> {
         let runtime_data: Option<RuntimeData> = get(state_update, RUNTIME_DATA);
         let sender_account_id = transaction.body.get_originator();
@@ -379,6 +644,26 @@ impl Runtime {
                             authority_proposals,
                         )
                     },
+                    TransactionBody::Unstake(ref t) => {
+                        self.unstaking(
+                            state_update,
+                            &t,
+                            block_index,
+                            &sender,
+                            &mut runtime_data,
+                            authority_proposals,
+                        )
+                    },
+                    TransactionBody::Slash(ref t) => {
+                        self.slash(
+                            state_update,
+                            &t,
+                            block_index,
+                            &mut runtime_data,
+                            authority_proposals,
+                            slashed_authorities,
+                        )
+                    },
                     TransactionBody::FunctionCall(ref t) => {
                         self.call_function(
                             state_update,
@@ -411,6 +696,14 @@ impl Runtime {
                             &mut sender,
                         )
                     }
+                    TransactionBody::RevealRandomness(ref t) => {
+                        self.reveal_randomness(
+                            state_update,
+                            t,
+                            &mut runtime_data,
+                            random_reveals,
+                        )
+                    }
                 }
             }
             (None, _) => Err("runtime data does not exist".to_string()),
@@ -558,9 +851,10 @@ impl Runtime {
         nonce: &[u8],
         receiver: &mut Account,
     ) -> Result<Vec<Transaction>, String> {
-        let staked = runtime_data.get_stake_for_account(receiver_id);
+        let staked = runtime_data.get_locked_for_account(receiver_id);
         assert!(receiver.amount >= staked);
-        let result = {
+        // The changes of the contract are reverted if its return data can't be sent.
+        let result = with_checkpoint(state_update, |state_update| {
             let mut runtime_ext = RuntimeExt::new(
                 state_update,
                 receiver_id,
@@ -592,7 +886,7 @@ impl Runtime {
                 receiver.amount = wasm_res.balance + staked;
             }
             result
-        };
+        });
         set(
             state_update,
             &account_id_to_bytes(&receiver_id),
@@ -601,6 +895,9 @@ impl Runtime {
         result
     }
 
+    /// Records the result of the callback and executes it once all of its results are gathered.
+    /// The callback is consumed even if the execution fails, only the changes of the execution
+    /// are reverted and the next callback gets no result.
     fn apply_callback(
         &mut self,
         state_update: &mut StateDbUpdate,
@@ -611,74 +908,82 @@ impl Runtime {
         nonce: &[u8],
         receiver: &mut Account,
     ) -> Result<Vec<Transaction>, String> {
-        let staked = runtime_data.get_stake_for_account(receiver_id);
+        let staked = runtime_data.get_locked_for_account(receiver_id);
         assert!(receiver.amount >= staked);
-        let mut needs_removal = false;
-        let receipts = {
+        let is_complete = match runtime_data.callbacks.get_mut(&callback_res.info.id) {
+            Some(callback) => {
+                callback.results[callback_res.info.result_index] = callback_res.result.clone();
+                callback.result_counter += 1;
+                callback.result_counter == callback.results.len()
+            }
+            _ => {
+                return Err(format!("callback id: {:?} not found", callback_res.info.id));
+            }
+        };
+        if !is_complete {
+            // otherwise no receipt is generated
+            set(state_update, RUNTIME_DATA, &runtime_data);
+            return Ok(vec![]);
+        }
+        // if we have gathered all results, execute the callback
+        let callback = runtime_data
+            .callbacks
+            .remove(&callback_res.info.id)
+            .expect("callback must exist");
+        set(state_update, RUNTIME_DATA, &runtime_data);
+        let result = with_checkpoint(state_update, |state_update| {
             let mut runtime_ext = RuntimeExt::new(
                 state_update,
                 receiver_id,
                 nonce,
             );
-        
-            match runtime_data.callbacks.get_mut(&callback_res.info.id) {
-                Some(callback) => {
-                    callback.results[callback_res.info.result_index] = callback_res.result.clone();
-                    callback.result_counter += 1;
-                    // if we have gathered all results, execute the callback
-                    if callback.result_counter == callback.results.len() {
-                        let wasm_res = executor::execute(
-                            &receiver.code,
-                            &callback.method_name,
-                            &callback.args,
-                            &callback.results,
-                            &mut runtime_ext,
-                            &wasm::types::Config::default(),
-                            &RuntimeContext::new(
-                                receiver.amount - staked,
-                                0,
-                                sender_id,
-                                receiver_id,
-                                callback.mana,
-                            ),
-                        ).map_err(|e| format!("wasm exeuction failed with error: {:?}", e))?;
-                        needs_removal = true;
-                        let balance = wasm_res.balance;
-                        Self::return_data_to_receipts(
-                            &mut runtime_ext,
-                            wasm_res.return_data,
-                            &callback.callback,
-                            sender_id,
-                            receiver_id,
-                        ).and_then(|receipts| {
-                            receiver.amount = balance + staked;
-                            Ok(receipts)
-                        })?
-                    } else {
-                        // otherwise no receipt is generated
-                        vec![]
-                    }
-                },
-                _ => {
-                    return Err(format!("callback id: {:?} not found", callback_res.info.id));
-                }
+            let wasm_res = executor::execute(
+                &receiver.code,
+                &callback.method_name,
+                &callback.args,
+                &callback.results,
+                &mut runtime_ext,
+                &wasm::types::Config::default(),
+                &RuntimeContext::new(
+                    receiver.amount - staked,
+                    0,
+                    sender_id,
+                    receiver_id,
+                    callback.mana,
+                ),
+            ).map_err(|e| format!("wasm exeuction failed with error: {:?}", e))?;
+            let receipts = Self::return_data_to_receipts(
+                &mut runtime_ext,
+                wasm_res.return_data,
+                &callback.callback,
+                sender_id,
+                receiver_id,
+            )?;
+            receiver.amount = wasm_res.balance + staked;
+            Ok(receipts)
+        });
+        match result {
+            Ok(receipts) => {
+                set(
+                    state_update,
+                    &account_id_to_bytes(&receiver_id),
+                    receiver
+                );
+                Ok(receipts)
+            }
+            Err(s) => {
+                debug!(target: "runtime", "callback {:?} failed: {}", callback_res.info.id, s);
+                let receipts = callback.callback.map(|callback_info| {
+                    Transaction::Receipt(ReceiptTransaction::new(
+                        receiver_id.clone(),
+                        callback_info.receiver.clone(),
+                        create_nonce_with_nonce(nonce, 0),
+                        ReceiptBody::Callback(CallbackResult::new(callback_info, None)),
+                    ))
+                });
+                Ok(receipts.into_iter().collect())
             }
-        };
-        
-        if needs_removal {
-            runtime_data.callbacks.remove(&callback_res.info.id);
-            set(
-                state_update,
-                RUNTIME_DATA,
-                &runtime_data,
-            );
-            set(
-                state_update,
-                &account_id_to_bytes(&receiver_id),
-                receiver
-            );
         }
-        Ok(receipts)
     }
 
     fn apply_receipt(
@@ -700,12 +1005,14 @@ impl Runtime {
                     ReceiptBody::NewCall(async_call) => {
                         amount = async_call.amount;
                         if async_call.method_name == b"deposit".to_vec() {
-                            self.deposit(
-                                state_update,
-                                async_call.amount,
-                                &receipt.receiver,
-                                &mut receiver
-                            )
+                            with_checkpoint(state_update, |state_update| {
+                                self.deposit(
+                                    state_update,
+                                    async_call.amount,
+                                    &receipt.receiver,
+                                    &mut receiver
+                                )
+                            })
                         } else if async_call.method_name == b"create_account".to_vec() {
                             debug!(
                                 target: "runtime",
@@ -831,85 +1138,176 @@ impl Runtime {
         }
     }
 
+    /// Applies the transaction inside of a checkpoint, so that a failure only reverts the
+    /// changes of this transaction. The steps of a receipt are nested in checkpoints of their
+    /// own, see `with_checkpoint`. Returns whether the transaction was applied.
     fn filter_transaction(
         runtime: &mut Self,
         state_update: &mut StateDbUpdate,
-        shard_id: ShardId,
+        apply_state: &ApplyState,
         transaction: &Transaction,
         new_receipts: &mut Vec<Transaction>,
         authority_proposals: &mut Vec<AuthorityProposal>,
+        random_reveals: &mut Vec<(AccountId, CryptoHash)>,
+        slashed_authorities: &mut Vec<AccountId>,
     ) -> bool {
-        match transaction {
+        let result = match transaction {
             Transaction::SignedTransaction(ref tx) => {
-                match runtime.apply_signed_transaction(
+                state_update.checkpoint();
+                // Reveals of a failed transaction must not reach the beacon.
+                let mut reveals = vec![];
+                runtime.apply_signed_transaction(
                     state_update,
+                    apply_state.block_index,
                     tx,
-                    authority_proposals
-                ) {
-                    Ok(mut receipts) => {
-                        new_receipts.append(&mut receipts);
-                        state_update.commit();
-                        true
-                    }
-                    Err(s) => {
-                        debug!(target: "runtime", "{}", s);
-                        state_update.rollback();
-                        false
-                    }
-                }
+                    authority_proposals,
+                    &mut reveals,
+                    slashed_authorities,
+                ).map(|mut receipts| {
+                    new_receipts.append(&mut receipts);
+                    random_reveals.append(&mut reveals);
+                })
             }
             Transaction::Receipt(ref r) => {
-                if account_to_shard_id(&r.receiver) == shard_id {
-                    let mut tmp_new_receipts = vec![];
-                    match runtime.apply_receipt(state_update, r, &mut tmp_new_receipts) {
-                        Ok(()) => {
-                            state_update.commit();
-                            new_receipts.append(&mut tmp_new_receipts);
-                            true
-                        }
-                        Err(s) => {
-                            debug!(target: "runtime", "{}", s);
-                            state_update.rollback();
-                            new_receipts.append(&mut tmp_new_receipts);
-                            false
-                        }
-                    }
+                if account_to_shard_id(&r.receiver) == apply_state.shard_id {
+                    state_update.checkpoint();
+                    // Refunds and failed callbacks are kept even if the receipt fails.
+                    runtime.apply_receipt(state_update, r, new_receipts)
                 } else {
                     // wrong receipt
                     debug!(target: "runtime", "receipt sent to the wrong shard");
-                    false
+                    return false;
+                }
+            }
+        };
+        match result {
+            Ok(()) => {
+                state_update.discard_checkpoint();
+                state_update.commit();
+                true
+            }
+            Err(s) => {
+                debug!(target: "runtime", "{}", s);
+                state_update.rollback_to_checkpoint();
+                false
+            }
+        }
+    }
+
+    /// Releases the unstaked money whose lockup ended before the given block.
+    fn release_unstaked(state_update: &mut StateDbUpdate, block_index: u64) {
+        let runtime_data: Option<RuntimeData> = get(state_update, RUNTIME_DATA);
+        if let Some(mut runtime_data) = runtime_data {
+            if runtime_data.release_unstaked(block_index) {
+                set(state_update, RUNTIME_DATA, &runtime_data);
+                state_update.commit();
+            }
+        }
+    }
+
+    /// Records the participation of the authorities in the parent block and pays the rewards
+    /// once the last block of an epoch is recorded. Only the seats whose signatures verify
+    /// count as signed.
+    fn reward_authorities(state_update: &mut StateDbUpdate, apply_state: &ApplyState) {
+        let mut runtime_data: RuntimeData = match get(state_update, RUNTIME_DATA) {
+            Some(runtime_data) => runtime_data,
+            None => return,
+        };
+        let parent_index = apply_state.block_index.saturating_sub(1);
+        let epoch = epoch_of_block(parent_index, runtime_data.epoch_length);
+        for (account_id, signed) in apply_state.participation.iter() {
+            runtime_data.seated.entry(epoch).or_default().insert(account_id.clone());
+            let participation = runtime_data.participation.entry(account_id.clone()).or_default();
+            participation.seats += 1;
+            if *signed {
+                participation.signed += 1;
+            }
+        }
+        let is_epoch_end = parent_index > 0 && parent_index % runtime_data.epoch_length == 0;
+        if is_epoch_end {
+            if !runtime_data.participation.is_empty() {
+                let minted = u128::from(runtime_data.total_supply)
+                    * u128::from(runtime_data.inflation_rate_bps)
+                    / 10_000;
+                // An authority that committed to the randomness beacon and didn't reveal in the
+                // epoch could be withholding its value, it forfeits the reward.
+                let weights: Vec<(AccountId, u128)> = runtime_data
+                    .participation
+                    .iter()
+                    .map(|(account_id, participation)| {
+                        let committed = get::<CryptoHash>(
+                            state_update,
+                            &random_commitment_key(account_id),
+                        ).is_some();
+                        let weight = if committed && !runtime_data.revealed.contains(account_id) {
+                            0
+                        } else {
+                            runtime_data.reward_weight(account_id, participation)
+                        };
+                        (account_id.clone(), weight)
+                    })
+                    .collect();
+                let total_weight: u128 = weights.iter().map(|(_, weight)| weight).sum();
+                for (account_id, weight) in weights.into_iter().filter(|(_, weight)| *weight > 0) {
+                    let reward = (minted * weight / total_weight) as Balance;
+                    let key = account_id_to_bytes(&account_id);
+                    if let Some(mut account) = get::<Account>(state_update, &key) {
+                        account.amount += reward;
+                        runtime_data.total_supply += reward;
+                        set(state_update, &key, &account);
+                    }
                 }
             }
+            runtime_data.participation.clear();
+            runtime_data.revealed.clear();
+            let first_epoch = (epoch + 1).saturating_sub(runtime_data.lockup_epochs);
+            runtime_data.seated = runtime_data.seated.split_off(&first_epoch);
+        } else if apply_state.participation.is_empty() {
+            return;
         }
+        set(state_update, RUNTIME_DATA, &runtime_data);
+        state_update.commit();
     }
 
     /// check whether transactions in a block are valid and return the new root
-    /// if they are
+    /// together with the values revealed to the randomness beacon and the slashed
+    /// authorities if they are
+    #[allow(clippy::type_complexity)]
     pub fn check(
         &mut self,
         apply_state: &ApplyState,
         prev_receipts: &[Transaction],
         transactions: &[Transaction],
-    ) -> Option<(storage::TrieBackendTransaction, MerkleHash)> {
+    ) -> Option<(
+        storage::TrieBackendTransaction,
+        MerkleHash,
+        Vec<(AccountId, CryptoHash)>,
+        Vec<AccountId>,
+    )> {
         let mut new_receipts = vec![];
         let mut state_update = StateDbUpdate::new(self.state_db.clone(), apply_state.root);
         let mut authority_proposals = vec![];
-        let shard_id = apply_state.shard_id;
+        let mut random_reveals = vec![];
+        let mut slashed_authorities = vec![];
+        Self::release_unstaked(&mut state_update, apply_state.block_index);
+        Self::reward_authorities(&mut state_update, apply_state);
         for tx in prev_receipts.iter().chain(transactions) {
             let filter_res = Self::filter_transaction(
                 self,
                 &mut state_update,
-                shard_id,
+                apply_state,
                 tx,
                 &mut new_receipts,
-                &mut authority_proposals
+                &mut authority_proposals,
+                &mut random_reveals,
+                &mut slashed_authorities,
             );
             if !filter_res {
                 return None;
             }
         }
         let (db_transaction, new_root) = state_update.finalize();
-        Some((db_transaction, new_root))
+        Some((db_transaction, new_root, random_reveals, slashed_authorities))
     }
 
     /// apply receipts from previous block and transactions and receipts from this block
@@ -922,25 +1320,33 @@ impl Runtime {
         let mut new_receipts = vec![];
         let mut state_update = StateDbUpdate::new(self.state_db.clone(), apply_state.root);
         let mut authority_proposals = vec![];
+        let mut random_reveals = vec![];
+        let mut slashed_authorities = vec![];
         let shard_id = apply_state.shard_id;
+        Self::release_unstaked(&mut state_update, apply_state.block_index);
+        Self::reward_authorities(&mut state_update, apply_state);
         for receipt in prev_receipts.iter() {
             Self::filter_transaction(
                 self,
                 &mut state_update,
-                shard_id,
+                apply_state,
                 receipt,
                 &mut new_receipts,
-                &mut authority_proposals
+                &mut authority_proposals,
+                &mut random_reveals,
+                &mut slashed_authorities,
             );
         }
         transactions.retain(|t| {
             Self::filter_transaction(
                 self,
                 &mut state_update,
-                shard_id,
+                apply_state,
                 t,
                 &mut new_receipts,
-                &mut authority_proposals
+                &mut authority_proposals,
+                &mut random_reveals,
+                &mut slashed_authorities,
             )
         });
         let (transaction, new_root) = state_update.finalize();
@@ -948,6 +1354,8 @@ impl Runtime {
             root: new_root, 
             transaction,
             authority_proposals,
+            random_reveals,
+            slashed_authorities,
             shard_id,
             filtered_transactions: transactions,
             new_receipts,
@@ -958,8 +1366,33 @@ impl Runtime {
         &self,
         balances: &[(AccountId, ReadablePublicKey, u64)],
         wasm_binary: &[u8],
-        initial_authorities: &[(AccountId, ReadablePublicKey, u64)]
+        initial_authorities: &[(AccountId, ReadablePublicKey, u64)],
+        epoch_length: u64,
+        lockup_epochs: u64,
+        inflation_rate_bps: u64,
     ) -> MerkleHash {
+        let (mut transaction, genesis_root) = self.genesis_state(
+            balances,
+            wasm_binary,
+            initial_authorities,
+            epoch_length,
+            lockup_epochs,
+            inflation_rate_bps,
+        );
+        self.state_db.commit(&mut transaction).expect("Failed to commit genesis state");
+        genesis_root
+    }
+
+    /// Computes the genesis state without committing it.
+    pub fn genesis_state(
+        &self,
+        balances: &[(AccountId, ReadablePublicKey, u64)],
+        wasm_binary: &[u8],
+        initial_authorities: &[(AccountId, ReadablePublicKey, u64)],
+        epoch_length: u64,
+        lockup_epochs: u64,
+        inflation_rate_bps: u64,
+    ) -> (storage::TrieBackendTransaction, MerkleHash) {
         let mut state_db_update =
             StateDbUpdate::new(self.state_db.clone(), MerkleHash::default());
         balances.iter().for_each(|(account_id, public_key, balance)| {
@@ -985,13 +1418,18 @@ impl Runtime {
             .collect();
         let runtime_data = RuntimeData {
             stake,
-            callbacks: HashMap::new(),
+            unstaking: BTreeMap::new(),
+            callbacks: BTreeMap::new(),
+            epoch_length,
+            lockup_epochs,
+            total_supply: balances.iter().map(|(_, _, balance)| balance).sum(),
+            inflation_rate_bps,
+            participation: BTreeMap::new(),
+            seated: BTreeMap::new(),
+            revealed: BTreeSet::new(),
         };
         set(&mut state_db_update, RUNTIME_DATA, &runtime_data);
-        let (mut transaction, genesis_root) = state_db_update.finalize();
-        // TODO: check that genesis_root is not yet in the state_db? Also may be can check before doing this?
-        self.state_db.commit(&mut transaction).expect("Failed to commit genesis state");
-        genesis_root
+        state_db_update.finalize()
     }
 }
 
@@ -999,6 +1437,12 @@ impl Runtime {
 mod tests {
     use std::sync::Arc;
 
+    use beacon::randomness::HashChain;
+    use beacon::slashing::equivocation_evidence;
+    use beacon::types::SignedBeaconBlock;
+    use chain::SignedBlock;
+    use primitives::signer::InMemorySigner;
+    use primitives::traits::Signer;
     use primitives::hash::hash;
     use primitives::types::{
         DeployContractTransaction, FunctionCallTransaction,
@@ -1102,7 +1546,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let apply_result = runtime.apply_all(
             apply_state, vec![Transaction::SignedTransaction(transaction)]
@@ -1129,7 +1574,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let apply_result = runtime.apply_all(
             apply_state, vec![Transaction::SignedTransaction(transaction)],
@@ -1157,7 +1603,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply_all(
             apply_state, vec![Transaction::SignedTransaction(transaction)]
@@ -1196,7 +1643,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply_all(
             apply_state, vec![Transaction::SignedTransaction(transaction)],
@@ -1228,7 +1676,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply_all(
             apply_state, vec![Transaction::SignedTransaction(transaction)]
@@ -1276,7 +1725,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply(
             &apply_state, &[], vec![Transaction::SignedTransaction(transaction)]
@@ -1325,7 +1775,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply_all(
             apply_state, vec![Transaction::SignedTransaction(transaction)]
@@ -1364,7 +1815,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply_all(
             apply_state, vec![Transaction::SignedTransaction(transaction)]
@@ -1419,7 +1871,8 @@ mod tests {
                 root,
                 shard_id: 0,
                 parent_block_hash: CryptoHash::default(),
-                block_index: 0
+                block_index: 0,
+                participation: vec![],
             };
             let apply_result = runtime.apply_all(
                 apply_state, vec![Transaction::SignedTransaction(transaction)]
@@ -1457,7 +1910,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply_all(
             apply_state, vec![Transaction::SignedTransaction(transaction)]
@@ -1506,7 +1960,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply_all(
             apply_state, vec![Transaction::SignedTransaction(transaction)]
@@ -1529,6 +1984,7 @@ mod tests {
             root: apply_result.root,
             parent_block_hash: CryptoHash::default(),
             block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply(
             &apply_state, &[], vec![Transaction::SignedTransaction(transaction1)],
@@ -1561,7 +2017,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let apply_result = runtime.apply_all(
             apply_state, vec![Transaction::Receipt(receipt)]
@@ -1636,7 +2093,8 @@ mod tests {
             root: new_root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply(
             &apply_state, &[], vec![Transaction::Receipt(receipt)]
@@ -1648,6 +2106,63 @@ mod tests {
         assert_eq!(root, apply_result.root);
     }
 
+    #[test]
+    fn test_callback_failure() {
+        let (mut runtime, viewer) = get_runtime_and_state_db_viewer();
+        let root = viewer.get_root();
+        // The contract asserts that it gets two numbers.
+        let mut callback = Callback::new(b"sum_with_input".to_vec(), encode_int(7).to_vec(), 0);
+        callback.results.resize(1, None);
+        let next_callback_info = CallbackInfo::new([1; 32].to_vec(), 0, alice_account());
+        callback.callback = Some(next_callback_info.clone());
+        let callback_id = [0; 32].to_vec();
+        let mut state_update = StateDbUpdate::new(runtime.state_db.clone(), root);
+        let mut runtime_data: RuntimeData = get(&mut state_update, RUNTIME_DATA).unwrap();
+        runtime_data.callbacks.insert(callback_id.clone(), callback);
+        set(
+            &mut state_update,
+            RUNTIME_DATA,
+            &runtime_data
+        );
+        let (mut transaction, new_root) = state_update.finalize();
+        runtime.state_db.commit(&mut transaction).unwrap();
+        let receipt = ReceiptTransaction::new(
+            alice_account(),
+            bob_account(),
+            hash(&[1, 2, 3]).into(),
+            ReceiptBody::Callback(CallbackResult::new(
+                CallbackInfo::new(callback_id.clone(), 0, alice_account()),
+                None,
+            ))
+        );
+        let apply_state = ApplyState {
+            root: new_root,
+            shard_id: 0,
+            parent_block_hash: CryptoHash::default(),
+            block_index: 0,
+            participation: vec![],
+        };
+        let mut apply_result = runtime.apply(
+            &apply_state, &[], vec![Transaction::Receipt(receipt)]
+        );
+        // The failed execution is reverted, but the callback is still consumed.
+        assert_eq!(apply_result.filtered_transactions.len(), 1);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+        let mut state_update = StateDbUpdate::new(runtime.state_db.clone(), apply_result.root);
+        let runtime_data: RuntimeData = get(&mut state_update, RUNTIME_DATA).unwrap();
+        assert_eq!(runtime_data.callbacks.len(), 0);
+        assert_eq!(root, apply_result.root);
+        assert_eq!(apply_result.new_receipts.len(), 1);
+        if let Transaction::Receipt(new_receipt) = &apply_result.new_receipts[0] {
+            assert_eq!(new_receipt.originator, bob_account());
+            assert_eq!(new_receipt.receiver, alice_account());
+            let callback_res = CallbackResult::new(next_callback_info, None);
+            assert_eq!(new_receipt.body, ReceiptBody::Callback(callback_res));
+        } else {
+            assert!(false);
+        }
+    }
+
     #[test]
     fn test_nonce_update_when_deploying_contract() {
         let (mut runtime, viewer) = get_runtime_and_state_db_viewer();
@@ -1666,7 +2181,8 @@ mod tests {
             root,
             shard_id: 0,
             parent_block_hash: CryptoHash::default(),
-            block_index: 0
+            block_index: 0,
+            participation: vec![],
         };
         let mut apply_result = runtime.apply(
             &apply_state, &[], vec![Transaction::SignedTransaction(transaction)]
@@ -1676,4 +2192,264 @@ mod tests {
         let account: Account = get(&mut state_update, &account_id_to_bytes(&alice_account())).unwrap();
         assert_eq!(account.nonce, 1);
     }
+
+    #[test]
+    fn test_reveal_randomness() {
+        let (mut runtime, viewer) = get_runtime_and_state_db_viewer();
+        let root = viewer.get_root();
+        let hash_chain = HashChain::new(hash(b"seed"), 10);
+        let reveal = |nonce, originator, value| {
+            let tx_body = TransactionBody::RevealRandomness(RevealRandomnessTransaction {
+                nonce,
+                originator,
+                value,
+            });
+            Transaction::SignedTransaction(SignedTransaction::new(DEFAULT_SIGNATURE, tx_body))
+        };
+        let transactions = vec![
+            reveal(1, alice_account(), hash_chain.commitment()),
+            reveal(2, alice_account(), hash_chain.reveal(0).unwrap()),
+            reveal(3, alice_account(), hash_chain.reveal(0).unwrap()),
+            reveal(4, alice_account(), hash_chain.reveal(1).unwrap()),
+            reveal(1, bob_account(), hash(b"bob")),
+        ];
+        let apply_state = ApplyState {
+            root,
+            shard_id: 0,
+            parent_block_hash: CryptoHash::default(),
+            block_index: 0,
+            participation: vec![],
+        };
+        let mut apply_result = runtime.apply(&apply_state, &[], transactions);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+        // The second reveal of the epoch is rejected.
+        assert_eq!(apply_result.filtered_transactions.len(), 2);
+        assert_eq!(
+            apply_result.random_reveals,
+            vec![(alice_account(), hash_chain.reveal(0).unwrap())]
+        );
+
+        // The epoch ends with the second block, the next value is revealed in the next epoch.
+        let apply_state = ApplyState {
+            root: apply_result.root,
+            shard_id: 0,
+            parent_block_hash: CryptoHash::default(),
+            block_index: 3,
+            participation: vec![],
+        };
+        let transactions = vec![reveal(5, alice_account(), hash_chain.reveal(1).unwrap())];
+        let apply_result = runtime.apply(&apply_state, &[], transactions);
+        assert_eq!(
+            apply_result.random_reveals,
+            vec![(alice_account(), hash_chain.reveal(1).unwrap())]
+        );
+    }
+
+    #[test]
+    fn test_withheld_reveal_forfeits_reward() {
+        let (mut runtime, viewer) = get_runtime_and_state_db_viewer();
+        let hash_chain = HashChain::new(hash(b"seed"), 10);
+        let commit = Transaction::SignedTransaction(SignedTransaction::new(
+            DEFAULT_SIGNATURE,
+            TransactionBody::RevealRandomness(RevealRandomnessTransaction {
+                nonce: 1,
+                originator: alice_account(),
+                value: hash_chain.commitment(),
+            }),
+        ));
+        let apply_state = |root, block_index| ApplyState {
+            root,
+            shard_id: 0,
+            parent_block_hash: CryptoHash::default(),
+            block_index,
+            participation: vec![(alice_account(), true)],
+        };
+        let mut apply_result =
+            runtime.apply(&apply_state(viewer.get_root(), 2), &[], vec![commit]);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+
+        // Alice signed every seat, but revealed nothing after the commitment.
+        let mut apply_result = runtime.apply(&apply_state(apply_result.root, 3), &[], vec![]);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+        let result = viewer.view_account_at(&alice_account(), apply_result.root).unwrap();
+        assert_eq!(result.amount, 100);
+    }
+
+    #[test]
+    fn test_unstake_and_restake() {
+        let (mut runtime, viewer) = get_runtime_and_state_db_viewer();
+        let transaction = |body| {
+            Transaction::SignedTransaction(SignedTransaction::new(DEFAULT_SIGNATURE, body))
+        };
+        let send_money = |nonce, amount| {
+            transaction(TransactionBody::SendMoney(SendMoneyTransaction {
+                nonce,
+                originator: alice_account(),
+                receiver: bob_account(),
+                amount,
+            }))
+        };
+        let apply_state = |root, block_index| ApplyState {
+            root,
+            shard_id: 0,
+            parent_block_hash: CryptoHash::default(),
+            block_index,
+            participation: vec![],
+        };
+
+        let unstake = transaction(TransactionBody::Unstake(UnstakeTransaction {
+            nonce: 1,
+            originator: alice_account(),
+            amount: 30,
+        }));
+        let mut apply_result = runtime.apply(
+            &apply_state(viewer.get_root(), 1), &[], vec![unstake, send_money(2, 60)]
+        );
+        // Unstaked money is still locked.
+        assert_eq!(apply_result.filtered_transactions.len(), 1);
+        assert_eq!(apply_result.authority_proposals[0].amount, 20);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+
+        let restake = transaction(TransactionBody::Stake(StakeTransaction {
+            nonce: 3,
+            originator: alice_account(),
+            amount: 10,
+        }));
+        let mut apply_result = runtime.apply(
+            &apply_state(apply_result.root, 2), &[], vec![restake]
+        );
+        assert_eq!(apply_result.authority_proposals[0].amount, 30);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+        let mut state_update = StateDbUpdate::new(runtime.state_db.clone(), apply_result.root);
+        let runtime_data: RuntimeData = get(&mut state_update, RUNTIME_DATA).unwrap();
+        assert_eq!(runtime_data.get_stake_for_account(&alice_account()), 30);
+        assert_eq!(runtime_data.get_unstaking_for_account(&alice_account()), 20);
+
+        // The lockup covers the epoch of unstaking and the next one.
+        let root = apply_result.root;
+        let apply_result = runtime.apply(&apply_state(root, 4), &[], vec![send_money(4, 70)]);
+        assert_eq!(apply_result.filtered_transactions.len(), 0);
+        let mut apply_result =
+            runtime.apply(&apply_state(root, 5), &[], vec![send_money(4, 70)]);
+        assert_eq!(apply_result.filtered_transactions.len(), 1);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+        let mut state_update = StateDbUpdate::new(runtime.state_db.clone(), apply_result.root);
+        let runtime_data: RuntimeData = get(&mut state_update, RUNTIME_DATA).unwrap();
+        assert_eq!(runtime_data.get_unstaking_for_account(&alice_account()), 0);
+    }
+
+    #[test]
+    fn test_release_index() {
+        let runtime_data = RuntimeData { epoch_length: 2, lockup_epochs: 2, ..Default::default() };
+        // Blocks 1 and 2 are in the first epoch, the lockup ends with the second one.
+        assert_eq!(runtime_data.release_index(1), 4);
+        assert_eq!(runtime_data.release_index(2), 4);
+        assert_eq!(runtime_data.release_index(3), 6);
+    }
+
+    #[test]
+    fn test_slash() {
+        let (mut runtime, viewer) = get_runtime_and_state_db_viewer();
+        let signer = InMemorySigner::default();
+        let mut state_update = StateDbUpdate::new(runtime.state_db.clone(), viewer.get_root());
+        let mut alice: Account =
+            get(&mut state_update, &account_id_to_bytes(&alice_account())).unwrap();
+        alice.public_keys = vec![signer.public_key()];
+        set(&mut state_update, &account_id_to_bytes(&alice_account()), &alice);
+        let (mut transaction, root) = state_update.finalize();
+        runtime.state_db.commit(&mut transaction).unwrap();
+
+        let block = |shard_block_hash| {
+            let mut block =
+                SignedBeaconBlock::new(1, CryptoHash::default(), vec![], shard_block_hash);
+            let signature = block.sign(&signer);
+            block.add_signature(signature);
+            block.header()
+        };
+        let evidence =
+            equivocation_evidence(&block(hash(&[1])), &block(hash(&[2])), &signer.public_key())
+                .unwrap();
+        let slash = |nonce| {
+            let tx_body = TransactionBody::Slash(SlashTransaction {
+                nonce,
+                originator: bob_account(),
+                offender: alice_account(),
+                evidence: evidence.clone(),
+            });
+            Transaction::SignedTransaction(SignedTransaction::new(DEFAULT_SIGNATURE, tx_body))
+        };
+        let apply_state = |root, block_index, participation| ApplyState {
+            root,
+            shard_id: 0,
+            parent_block_hash: CryptoHash::default(),
+            block_index,
+            participation,
+        };
+        // Alice holds no seat in the epoch of the evidence.
+        let apply_result = runtime.apply(&apply_state(root, 2, vec![]), &[], vec![slash(1)]);
+        assert_eq!(apply_result.filtered_transactions.len(), 0);
+        // Seats of the first block are recorded by the second one.
+        let mut apply_result = runtime.apply(
+            &apply_state(root, 2, vec![(alice_account(), true)]),
+            &[],
+            vec![slash(1), slash(2)],
+        );
+        // Stake can only be burned once.
+        assert_eq!(apply_result.filtered_transactions.len(), 1);
+        assert_eq!(apply_result.authority_proposals.len(), 1);
+        assert_eq!(apply_result.authority_proposals[0].amount, 0);
+        assert_eq!(apply_result.slashed_authorities, vec![alice_account()]);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+        let result = viewer.view_account_at(&alice_account(), apply_result.root).unwrap();
+        assert_eq!(result.amount, 50);
+        assert_eq!(result.stake, 0);
+        let mut state_update = StateDbUpdate::new(runtime.state_db.clone(), apply_result.root);
+        let runtime_data: RuntimeData = get(&mut state_update, RUNTIME_DATA).unwrap();
+        assert_eq!(runtime_data.total_supply, 50);
+
+        // Evidence that was used once doesn't burn the new stake.
+        let stake = TransactionBody::Stake(StakeTransaction {
+            nonce: 1,
+            originator: alice_account(),
+            amount: 10,
+        });
+        let stake =
+            Transaction::SignedTransaction(SignedTransaction::new(DEFAULT_SIGNATURE, stake));
+        let apply_result = runtime.apply(
+            &apply_state(apply_result.root, 3, vec![(alice_account(), true)]),
+            &[],
+            vec![stake, slash(3)],
+        );
+        assert_eq!(apply_result.filtered_transactions.len(), 1);
+    }
+
+    #[test]
+    fn test_authority_rewards() {
+        let (mut runtime, viewer) = get_runtime_and_state_db_viewer();
+        let apply_state = |root, block_index, signed: &[bool]| ApplyState {
+            root,
+            shard_id: 0,
+            parent_block_hash: CryptoHash::default(),
+            block_index,
+            participation: signed.iter().map(|signed| (alice_account(), *signed)).collect(),
+        };
+        // Every block records the signatures of its parent.
+        let mut apply_result =
+            runtime.apply(&apply_state(viewer.get_root(), 2, &[true, false]), &[], vec![]);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+        let result = viewer.view_account_at(&alice_account(), apply_result.root).unwrap();
+        assert_eq!(result.amount, 100);
+
+        // The epoch ends with the second block and 1% of the total supply is minted once its
+        // signatures are recorded.
+        let mut apply_result =
+            runtime.apply(&apply_state(apply_result.root, 3, &[true, true]), &[], vec![]);
+        runtime.state_db.commit(&mut apply_result.transaction).unwrap();
+        let result = viewer.view_account_at(&alice_account(), apply_result.root).unwrap();
+        assert_eq!(result.amount, 101);
+        let mut state_update = StateDbUpdate::new(runtime.state_db.clone(), apply_result.root);
+        let runtime_data: RuntimeData = get(&mut state_update, RUNTIME_DATA).unwrap();
+        assert_eq!(runtime_data.total_supply, 101);
+        assert!(runtime_data.participation.is_empty());
+    }
 }
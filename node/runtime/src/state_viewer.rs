@@ -10,7 +10,8 @@ use wasm::executor;
 use wasm::types::{ReturnData, RuntimeContext};
 
 use super::{
-    Account, account_id_to_bytes, get, RUNTIME_DATA, RuntimeData, RuntimeExt,
+    Account, account_id_to_bytes, get, random_commitment_key, RUNTIME_DATA, RuntimeData,
+    RuntimeExt,
 };
 
 #[derive(Serialize, Deserialize)]
@@ -74,17 +75,25 @@ impl StateDbViewer {
         self.view_account_at(account_id, root)
     }
 
+    /// Returns the last value the account committed to or revealed for the randomness beacon.
+    pub fn get_random_commitment_at(
+        &self,
+        account_id: &AccountId,
+        root: MerkleHash,
+    ) -> Option<CryptoHash> {
+        let mut state_update = StateDbUpdate::new(self.state_db.clone(), root);
+        get(&mut state_update, &random_commitment_key(account_id))
+    }
+
     pub fn view_state(&self, account_id: &AccountId) -> ViewStateResult {
         let root = self.get_root();
-        let mut values = HashMap::default();
         let state_update = StateDbUpdate::new(self.state_db.clone(), root);
         let mut prefix = account_id_to_bytes(account_id);
         prefix.append(&mut b",".to_vec());
-        state_update.for_keys_with_prefix(&prefix, |key| {
-            if let Some(value) = state_update.get(key) {
-                values.insert(key.to_vec(), value.to_vec());
-            }
-        });
+        let values = state_update
+            .iter_prefix(&prefix)
+            .map(|(key, value)| (key, value.to_vec()))
+            .collect();
         ViewStateResult {
             values
         }
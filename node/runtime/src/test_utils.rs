@@ -25,6 +25,8 @@ pub fn generate_test_chain_spec() -> ChainSpec {
         genesis_wasm,
         beacon_chain_epoch_length: 2,
         beacon_chain_num_seats_per_slot: 10,
+        beacon_chain_lockup_epochs: 2,
+        inflation_rate_bps: 100,
         boot_nodes: vec![],
     }
 }
@@ -37,7 +39,10 @@ pub fn get_runtime_and_state_db_viewer() -> (Runtime, StateDbViewer) {
     let genesis_root = runtime.apply_genesis_state(
         &chain_spec.accounts,
         &chain_spec.genesis_wasm,
-        &chain_spec.initial_authorities
+        &chain_spec.initial_authorities,
+        chain_spec.beacon_chain_epoch_length,
+        chain_spec.beacon_chain_lockup_epochs,
+        chain_spec.inflation_rate_bps,
     );
 
     let shard_genesis = SignedShardBlock::genesis(genesis_root);
@@ -80,6 +85,8 @@ impl Runtime {
                 shard_id: cur_apply_state.shard_id,
                 block_index: cur_apply_state.block_index,
                 parent_block_hash: cur_apply_state.parent_block_hash,
+                // Participation in the block is already recorded.
+                participation: vec![],
             };
             cur_transactions = apply_result.new_receipts;
         }
@@ -267,6 +267,10 @@ class NearRPC(object):
         params = {'hash': _hash}
         return self._call_rpc('get_shard_block_by_hash', params)
 
+    def get_authority_proof(self, epoch):
+        params = {'epoch': epoch}
+        return self._call_rpc('get_authority_proof', params)
+
     def create_account(
         self,
         sender,
@@ -351,6 +355,7 @@ view_latest_beacon_block  {}
 get_beacon_block_by_hash  {}
 view_latest_shard_block   {}
 get_beacon_block_by_hash  {}
+get_authority_proof       {}
             """.format(
                 self.call_view_function.__doc__,
                 self.deploy.__doc__,
@@ -365,6 +370,7 @@ get_beacon_block_by_hash  {}
                 self.get_beacon_block_by_hash.__doc__,
                 self.view_latest_shard_block.__doc__,
                 self.get_shard_block_by_hash.__doc__,
+                self.get_authority_proof.__doc__,
             )
         )
         parser.add_argument('command', help='Command to run')
@@ -621,6 +627,14 @@ get_beacon_block_by_hash  {}
         client = self._get_rpc_client(args)
         return client.get_shard_block_by_hash(args.hash)
 
+    def get_authority_proof(self):
+        """Get proof of the authorities of an epoch for light clients."""
+        parser = self._get_command_parser(self.get_authority_proof.__doc__)
+        parser.add_argument('epoch', type=int)
+        args = self._get_command_args(parser)
+        client = self._get_rpc_client(args)
+        return client.get_authority_proof(args.epoch)
+
 
 if __name__ == "__main__":
     MultiCommandParser()